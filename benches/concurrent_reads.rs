@@ -0,0 +1,63 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use kv_store::KVStore;
+use rand::Rng;
+use std::{
+    env, fs,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+const KEYS: usize = 1000;
+const GETS_PER_READER: usize = 1000;
+
+// Throughput of gets made by many concurrent readers. Since reads don't serialize on a
+// single lock or file handle, the throughput should go up with the number of readers,
+// up to the number of cores.
+fn concurrent_gets(c: &mut Criterion) {
+    let mut group = c.benchmark_group("concurrent_gets");
+    group.sample_size(10);
+    for readers in [1, 2, 4, 8].iter() {
+        group.throughput(Throughput::Elements((readers * GETS_PER_READER) as u64));
+        group.bench_with_input(
+            BenchmarkId::from_parameter(readers),
+            readers,
+            |b, &readers| {
+                let n: u32 = rand::thread_rng().gen();
+                let dir = env::temp_dir().join(format!("kvs-bench-{}", n));
+                let store = Arc::new(KVStore::open(&dir).unwrap());
+                for i in 0..KEYS {
+                    store.set(format!("key {}", i), vec![b'x'; 256]).unwrap();
+                }
+                b.iter_custom(|iters| {
+                    let mut elapsed = Duration::default();
+                    for _ in 0..iters {
+                        let start = Instant::now();
+                        let handles: Vec<_> = (0..readers)
+                            .map(|reader| {
+                                let store = Arc::clone(&store);
+                                thread::spawn(move || {
+                                    for i in 0..GETS_PER_READER {
+                                        let key = format!("key {}", (reader + i * 7) % KEYS);
+                                        store.get(key).unwrap();
+                                    }
+                                })
+                            })
+                            .collect();
+                        for handle in handles {
+                            handle.join().unwrap();
+                        }
+                        elapsed += start.elapsed();
+                    }
+                    elapsed
+                });
+                drop(store);
+                fs::remove_dir_all(dir).unwrap();
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, concurrent_gets);
+criterion_main!(benches);
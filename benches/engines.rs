@@ -0,0 +1,70 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use kv_store::{
+    engines::{self, EngineKind},
+    KVStoreConfig,
+};
+use rand::Rng;
+use std::{env, fs};
+
+const KEYS: usize = 1000;
+
+fn engine_kinds() -> Vec<(&'static str, EngineKind)> {
+    vec![
+        ("kvs", EngineKind::Kvs),
+        ("sled", EngineKind::Sled),
+        ("memory", EngineKind::Memory),
+    ]
+}
+
+// Sets made sequentially against each of the engines.
+fn set(c: &mut Criterion) {
+    let mut group = c.benchmark_group("set");
+    for (name, kind) in engine_kinds() {
+        group.bench_with_input(BenchmarkId::from_parameter(name), &kind, |b, &kind| {
+            let n: u32 = rand::thread_rng().gen();
+            let dir = env::temp_dir().join(format!("kvs-bench-{}", n));
+            let engine = engines::open_engine(kind, &dir, KVStoreConfig::default()).unwrap();
+            let mut i = 0;
+            b.iter(|| {
+                engine
+                    .set(format!("key {}", i % KEYS).into_bytes(), b"val".to_vec())
+                    .unwrap();
+                i += 1;
+            });
+            drop(engine);
+            let _ = fs::remove_dir_all(dir);
+        });
+    }
+    group.finish();
+}
+
+// Gets of random keys made against each of the engines.
+fn get(c: &mut Criterion) {
+    let mut group = c.benchmark_group("get");
+    for (name, kind) in engine_kinds() {
+        group.bench_with_input(BenchmarkId::from_parameter(name), &kind, |b, &kind| {
+            let n: u32 = rand::thread_rng().gen();
+            let dir = env::temp_dir().join(format!("kvs-bench-{}", n));
+            let engine = engines::open_engine(kind, &dir, KVStoreConfig::default()).unwrap();
+            for i in 0..KEYS {
+                engine
+                    .set(
+                        format!("key {}", i).into_bytes(),
+                        format!("val {}", i).into_bytes(),
+                    )
+                    .unwrap();
+            }
+            let mut rng = rand::thread_rng();
+            b.iter(|| {
+                let i = rng.gen_range(0..KEYS);
+                engine.get(format!("key {}", i).into_bytes()).unwrap();
+            });
+            drop(engine);
+            let _ = fs::remove_dir_all(dir);
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, set, get);
+criterion_main!(benches);
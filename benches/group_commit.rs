@@ -0,0 +1,63 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use kv_store::{Durability, KVStore, KVStoreConfig};
+use rand::Rng;
+use std::{
+    env, fs,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+const SETS_PER_CLIENT: usize = 100;
+
+// Throughput of sets made by many concurrent clients, with every write fsynced.
+// Thanks to group commit, concurrent sets share a single fsync, so the throughput
+// should go up with the number of clients.
+fn concurrent_sets(c: &mut Criterion) {
+    let mut group = c.benchmark_group("concurrent_sets");
+    group.sample_size(10);
+    for clients in [1, 4, 16, 64].iter() {
+        group.throughput(Throughput::Elements((clients * SETS_PER_CLIENT) as u64));
+        group.bench_with_input(
+            BenchmarkId::from_parameter(clients),
+            clients,
+            |b, &clients| {
+                let n: u32 = rand::thread_rng().gen();
+                let dir = env::temp_dir().join(format!("kvs-bench-{}", n));
+                let config = KVStoreConfig {
+                    durability: Durability::Always,
+                    ..KVStoreConfig::default()
+                };
+                let store = Arc::new(KVStore::open_with_config(&dir, config).unwrap());
+                b.iter_custom(|iters| {
+                    let mut elapsed = Duration::default();
+                    for _ in 0..iters {
+                        let start = Instant::now();
+                        let handles: Vec<_> = (0..clients)
+                            .map(|client| {
+                                let store = Arc::clone(&store);
+                                thread::spawn(move || {
+                                    for i in 0..SETS_PER_CLIENT {
+                                        let key = format!("key {} {}", client, i);
+                                        store.set(key, String::from("val")).unwrap();
+                                    }
+                                })
+                            })
+                            .collect();
+                        for handle in handles {
+                            handle.join().unwrap();
+                        }
+                        elapsed += start.elapsed();
+                    }
+                    elapsed
+                });
+                drop(store);
+                fs::remove_dir_all(dir).unwrap();
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, concurrent_sets);
+criterion_main!(benches);
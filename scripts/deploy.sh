@@ -1,19 +1,36 @@
  #!/usr/bin/env bash
 cd /opt/kv-store
+# The data directory is locked by the container using it, so the new container can only
+# start once the old one is gone. Build the image first to keep the downtime short.
+echo "building web"
+docker-compose build web || exit 1
 container_id=""
 for id in `docker ps --format "table {{.ID}}  {{.Names}}  {{.CreatedAt}}" | grep web | awk -F  "  " '{print $1}'`
 do
     container_id="$id"
 done
-echo "found running web container: $container_id"
-echo "scaling web to 2 containers"
-docker-compose up -d --build --no-deps --scale web=2 --no-recreate web
-sleep 10
-echo "killing container $container_id"
-docker kill -s SIGTERM $container_id
-sleep 1
-echo "removing container $container_id"
-docker rm -f $container_id
-sleep 1
-echo "scaling back down to one container"
-docker-compose up -d --no-deps --scale web=1 --no-recreate web
+if [ -n "$container_id" ]; then
+    echo "stopping container $container_id"
+    docker stop -t 30 $container_id
+    echo "removing container $container_id"
+    docker rm -f $container_id
+fi
+echo "starting web"
+docker-compose up -d --no-deps --scale web=1 web
+new_container_id=""
+for id in `docker ps --format "table {{.ID}}  {{.Names}}  {{.CreatedAt}}" | grep web | awk -F  "  " '{print $1}'`
+do
+    new_container_id="$id"
+done
+echo "waiting for container $new_container_id to be ready"
+for i in `seq 1 60`
+do
+    if curl -fs "http://`docker port $new_container_id 80 | head -n 1`/health/ready" > /dev/null; then
+        echo "container $new_container_id is ready"
+        exit 0
+    fi
+    sleep 2
+done
+echo "container $new_container_id did not become ready"
+docker logs --tail 50 $new_container_id
+exit 1
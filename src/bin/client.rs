@@ -1,9 +1,31 @@
 use anyhow::Result;
-use clap::{App, Arg, SubCommand};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
 use kv_store::{
-    models::{GetBody, RmBody, RmItem, SetBody, SetItem},
+    bulk::{Format, PairReader, PairWriter, EXPORT_FAILED},
+    models::{
+        BackupBody, BackupItem, BatchBody, BatchItem, BatchOp, BucketsBody, ChangeBody,
+        CompactBody, CreateBucketBody, DropBucketBody, Encoding, ErrorBody, GetBody, ImportBody,
+        IncrBody, IncrItem, KeysBody, RestoreBody, RestoreItem, RmBody, RmItem, ScanBody, SetBody,
+        SetItem, TtlBody, TxnBody, TxnItem, TxnOp,
+    },
     pubsub, ConnStrings,
 };
+use reqwest::{header::CONTENT_TYPE, StatusCode};
+use serde::de::DeserializeOwned;
+use std::{
+    fmt::Display,
+    fs,
+    io::{self, BufRead, BufReader, Read, Write},
+};
+
+// Number of operations sent at a time by a batch, the most the server accepts in one.
+const BATCH_SIZE: usize = 1000;
+
+// Number of key-value pairs sent at a time by an import.
+const IMPORT_BATCH_SIZE: usize = 1000;
+
+// Number of bytes at the end of an export that are kept to check whether it failed.
+const EXPORT_TAIL_SIZE: usize = 4096;
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -13,29 +35,186 @@ async fn main() -> Result<()> {
         .about(env!("CARGO_PKG_DESCRIPTION"))
         .after_help("To speficy a custom server host, set the $KVSTORE_SERVER_HOST variable.")
         .after_help("To speficy a custom server host, set the $KVSTORE_NATS_HOST variable.")
+        .arg(
+            Arg::with_name("bucket")
+                .long("bucket")
+                .takes_value(true)
+                .global(true)
+                .help("Use this bucket instead of the default one."),
+        )
         .subcommand(
             SubCommand::with_name("set")
                 .about("Set the key value pair.")
                 .arg(Arg::with_name("key").required(true))
-                .arg(Arg::with_name("val").required(true)),
+                .arg(Arg::with_name("val").required_unless("file"))
+                .arg(
+                    Arg::with_name("file")
+                        .long("file")
+                        .takes_value(true)
+                        .conflicts_with("val")
+                        .help("Read the value from this file, as is, or from stdin if it's `-`."),
+                )
+                .arg(
+                    Arg::with_name("ttl")
+                        .long("ttl")
+                        .takes_value(true)
+                        .help("Number of seconds after which the key expires."),
+                )
+                .arg(
+                    Arg::with_name("if-absent")
+                        .long("if-absent")
+                        .conflicts_with_all(&["ttl", "if-version"])
+                        .help("Only set the key if it doesn't exist."),
+                )
+                .arg(
+                    Arg::with_name("if-version")
+                        .long("if-version")
+                        .takes_value(true)
+                        .conflicts_with("ttl")
+                        .help("Only set the key if it's at this version."),
+                ),
         )
         .subcommand(
             SubCommand::with_name("get")
                 .about("Get the value of this key.")
+                .arg(Arg::with_name("key").required(true))
+                .arg(
+                    Arg::with_name("raw")
+                        .long("raw")
+                        .help("Write only the value to stdout, as is."),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("ttl")
+                .about("Get the number of seconds left before this key expires.")
                 .arg(Arg::with_name("key").required(true)),
         )
+        .subcommand(
+            SubCommand::with_name("scan")
+                .about("List the key value pairs in a range, ordered by key.")
+                .args(&scan_args()),
+        )
+        .subcommand(
+            SubCommand::with_name("keys")
+                .about("List the keys in a range, ordered by key.")
+                .args(&scan_args()),
+        )
         .subcommand(
             SubCommand::with_name("rm")
                 .about("Remove the key from the store.")
-                .arg(Arg::with_name("key").required(true)),
+                .arg(Arg::with_name("key").required(true))
+                .arg(
+                    Arg::with_name("if-version")
+                        .long("if-version")
+                        .takes_value(true)
+                        .help("Only remove the key if it's at this version."),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("txn")
+                .about("Apply several writes atomically, e.g. `txn set a 1 rm b`.")
+                .arg(Arg::with_name("ops").required(true).multiple(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("batch")
+                .about("Apply the operations in a JSON Lines file, or stdin if it's `-`.")
+                .after_help(
+                    "Each line holds one operation, e.g. {\"op\":\"set\",\"key\":\"a\",\"val\":\"1\"}, \
+                     {\"op\":\"get\",\"key\":\"a\"} or {\"op\":\"rm\",\"key\":\"a\"}.",
+                )
+                .arg(Arg::with_name("file").required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("incr")
+                .about("Add to the integer stored as the value of this key.")
+                .arg(Arg::with_name("key").required(true))
+                .setting(AppSettings::AllowNegativeNumbers)
+                .arg(Arg::with_name("delta").help("Amount to add, 1 by default.")),
+        )
+        .subcommand(
+            SubCommand::with_name("decr")
+                .about("Subtract from the integer stored as the value of this key.")
+                .arg(Arg::with_name("key").required(true))
+                .setting(AppSettings::AllowNegativeNumbers)
+                .arg(Arg::with_name("delta").help("Amount to subtract, 1 by default.")),
+        )
+        .subcommand(SubCommand::with_name("compact").about("Compact the log of the store."))
+        .subcommand(
+            SubCommand::with_name("backup")
+                .about("Write a snapshot of the store to a directory within the backup directory.")
+                .arg(Arg::with_name("path").required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("restore")
+                .about("Create the bucket given by --bucket out of a snapshot in the backup directory.")
+                .arg(Arg::with_name("path").required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("import")
+                .about("Import the key-value pairs in a JSON Lines or CSV file.")
+                .arg(Arg::with_name("file").required(true).help(
+                    "File to import, or `-` for stdin. Files ending in `.csv` are read as CSV.",
+                ))
+                .arg(format_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("export")
+                .about("Export all the key-value pairs, ordered by key, to stdout.")
+                .arg(format_arg())
+                .arg(
+                    Arg::with_name("output")
+                        .long("output")
+                        .takes_value(true)
+                        .help("Write to this file instead of stdout."),
+                ),
         )
         .subcommand(SubCommand::with_name("sub").about("Subscribe to changes to any of the keys."))
+        .subcommand(
+            SubCommand::with_name("watch")
+                .about("Watch the changes to the keys, without NATS.")
+                .arg(
+                    Arg::with_name("key")
+                        .long("key")
+                        .takes_value(true)
+                        .conflicts_with("prefix")
+                        .help("Only watch this key."),
+                )
+                .arg(
+                    Arg::with_name("prefix")
+                        .long("prefix")
+                        .takes_value(true)
+                        .help("Only watch the keys starting with this prefix."),
+                )
+                .arg(
+                    Arg::with_name("after")
+                        .long("after")
+                        .takes_value(true)
+                        .help("Start with the changes after the one with this id."),
+                ),
+        )
+        .subcommand(SubCommand::with_name("buckets").about("List the buckets."))
+        .subcommand(
+            SubCommand::with_name("create-bucket")
+                .about("Create a bucket.")
+                .arg(Arg::with_name("name").required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("drop-bucket")
+                .about("Drop a bucket, along with all the keys in it.")
+                .arg(Arg::with_name("name").required(true)),
+        )
         .get_matches();
 
     // prepare connection strings.
-    let conn_strings = ConnStrings::load();
+    let conn_strings = ConnStrings::load()?;
     let server_host = conn_strings.server_host();
     let client = reqwest::Client::new();
+    // Routes for the keys in a bucket are under `/buckets/{name}`.
+    let bucket = matches.value_of("bucket");
+    let base = match bucket {
+        Some(bucket) => format!("{}/buckets/{}", server_host, bucket),
+        None => server_host.clone(),
+    };
 
     match matches.subcommand() {
         ("set", Some(matches)) => {
@@ -43,53 +222,318 @@ async fn main() -> Result<()> {
                 .value_of("key")
                 .expect("Key not provided")
                 .to_string();
-            let val = matches
-                .value_of("val")
-                .expect("Value not provided")
+            let ttl: Option<u64> = match matches.value_of("ttl") {
+                Some(ttl) => Some(ttl.parse()?),
+                None => None,
+            };
+            let if_version: Option<u64> = match matches.value_of("if-version") {
+                Some(version) => Some(version.parse()?),
+                None => None,
+            };
+            let if_absent = matches.is_present("if-absent");
+
+            let request = client.post(format!("{}/set", base));
+            let request = match matches.value_of("file") {
+                // Values read from a file are sent as is, since they might not be UTF-8.
+                Some(path) => {
+                    let val = read_file(path)?;
+                    let mut query = vec![("key", key)];
+                    if let Some(ttl) = ttl {
+                        query.push(("ttl", ttl.to_string()));
+                    }
+                    if if_absent {
+                        query.push(("if_absent", true.to_string()));
+                    }
+                    if let Some(version) = if_version {
+                        query.push(("if_version", version.to_string()));
+                    }
+                    request
+                        .query(&query)
+                        .header(CONTENT_TYPE, "application/octet-stream")
+                        .body(val)
+                }
+                None => {
+                    let val = matches
+                        .value_of("val")
+                        .expect("Value not provided")
+                        .to_string();
+                    request.json(&SetItem {
+                        key,
+                        val,
+                        ttl,
+                        if_absent,
+                        if_version,
+                        encoding: Encoding::Utf8,
+                    })
+                }
+            };
+            let resp = request.send().await?;
+            print_response::<SetBody>(resp).await?;
+        }
+        ("get", Some(matches)) => {
+            let key = matches.value_of("key").expect("Key not provided");
+
+            if matches.is_present("raw") {
+                let resp = client
+                    .get(format!("{}/get", base))
+                    .query(&[("key", key), ("raw", "true")])
+                    .send()
+                    .await?;
+                if resp.status() == StatusCode::NOT_FOUND {
+                    anyhow::bail!("Key `{}` does not exist.", key);
+                }
+                io::stdout().write_all(&resp.error_for_status()?.bytes().await?)?;
+            } else {
+                let resp = client
+                    .get(format!("{}/get", base))
+                    .query(&[("key", key)])
+                    .send()
+                    .await?;
+                print_response::<GetBody>(resp).await?;
+            }
+        }
+        ("ttl", Some(matches)) => {
+            let key = matches.value_of("key").expect("Key not provided");
+
+            let resp = client
+                .get(format!("{}/ttl", base))
+                .query(&[("key", key)])
+                .send()
+                .await?;
+            print_response::<TtlBody>(resp).await?;
+        }
+        ("scan", Some(matches)) => {
+            let resp = client
+                .get(format!("{}/scan", base))
+                .query(&scan_query(matches))
+                .send()
+                .await?;
+            print_response::<ScanBody>(resp).await?;
+        }
+        ("keys", Some(matches)) => {
+            let resp = client
+                .get(format!("{}/keys", base))
+                .query(&scan_query(matches))
+                .send()
+                .await?;
+            print_response::<KeysBody>(resp).await?;
+        }
+        ("rm", Some(matches)) => {
+            let key = matches
+                .value_of("key")
+                .expect("Key not provided")
                 .to_string();
-            let body = SetItem { key, val };
+            let if_version = match matches.value_of("if-version") {
+                Some(version) => Some(version.parse()?),
+                None => None,
+            };
+            let body = RmItem {
+                key,
+                if_version,
+                encoding: Encoding::Utf8,
+            };
 
-            let resp: SetBody = client
-                .post(format!("{}/set", server_host))
+            let resp = client
+                .delete(format!("{}/rm", base))
                 .json(&body)
                 .send()
-                .await?
-                .json()
                 .await?;
-
-            println!("{}", resp);
+            print_response::<RmBody>(resp).await?;
         }
-        ("get", Some(matches)) => {
-            let key = matches.value_of("key").expect("Key not provided");
+        ("txn", Some(matches)) => {
+            let mut args = matches.values_of("ops").expect("Writes not provided");
+            let mut ops = vec![];
+            while let Some(op) = args.next() {
+                let key = args
+                    .next()
+                    .ok_or_else(|| anyhow::anyhow!("`{}` is missing a key", op))?
+                    .to_string();
+                let op = match op {
+                    "set" => {
+                        let val = args
+                            .next()
+                            .ok_or_else(|| anyhow::anyhow!("`set {}` is missing a value", key))?
+                            .to_string();
+                        TxnOp::Set { key, val }
+                    }
+                    "rm" => TxnOp::Rm { key },
+                    op => anyhow::bail!("`{}` is not a valid write, expected set or rm", op),
+                };
+                ops.push(op);
+            }
+            let body = TxnItem {
+                ops,
+                encoding: Encoding::Utf8,
+            };
 
-            let resp: GetBody = client
-                .get(format!("{}/get?key={}", server_host, key))
+            let resp = client
+                .post(format!("{}/txn", base))
+                .json(&body)
                 .send()
-                .await?
-                .json()
                 .await?;
-            println!("{}", resp);
+            print_response::<TxnBody>(resp).await?;
         }
-        ("rm", Some(matches)) => {
+        ("batch", Some(matches)) => {
+            let path = matches.value_of("file").expect("File not provided");
+            let reader: Box<dyn Read> = match path {
+                "-" => Box::new(io::stdin()),
+                path => Box::new(fs::File::open(path)?),
+            };
+            let mut ops = vec![];
+            for (i, line) in BufReader::new(reader).lines().enumerate() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let op: BatchOp = serde_json::from_str(&line).map_err(|err| {
+                    anyhow::anyhow!("Invalid operation on line {}: {}", i + 1, err)
+                })?;
+                ops.push(op);
+            }
+
+            // The results are printed one per line, in the order of the operations.
+            let mut ops = ops.into_iter().peekable();
+            while ops.peek().is_some() {
+                let body = BatchItem {
+                    ops: ops.by_ref().take(BATCH_SIZE).collect(),
+                    encoding: Encoding::Utf8,
+                };
+                let resp = client
+                    .post(format!("{}/batch", base))
+                    .json(&body)
+                    .send()
+                    .await?;
+                if !resp.status().is_success() {
+                    return print_response::<BatchBody>(resp).await;
+                }
+                for result in resp.json::<BatchBody>().await?.results() {
+                    println!("{}", result);
+                }
+            }
+        }
+        (op @ "incr", Some(matches)) | (op @ "decr", Some(matches)) => {
             let key = matches
                 .value_of("key")
                 .expect("Key not provided")
                 .to_string();
-            let body = RmItem { key };
+            let delta = match matches.value_of("delta") {
+                Some(delta) => delta.parse()?,
+                None => 1,
+            };
+            let body = IncrItem {
+                key,
+                delta,
+                encoding: Encoding::Utf8,
+            };
 
-            let resp: RmBody = client
-                .delete(format!("{}/rm", server_host))
+            let resp = client
+                .post(format!("{}/{}", base, op))
                 .json(&body)
                 .send()
-                .await?
-                .json()
                 .await?;
-            println!("{}", resp);
+            print_response::<IncrBody>(resp).await?;
+        }
+        ("compact", Some(_)) => {
+            let resp = client.post(format!("{}/compact", base)).send().await?;
+            print_response::<CompactBody>(resp).await?;
+        }
+        ("backup", Some(matches)) => {
+            let path = matches
+                .value_of("path")
+                .expect("Path not provided")
+                .to_string();
+
+            let resp = client
+                .post(format!("{}/backup", base))
+                .json(&BackupItem { path })
+                .send()
+                .await?;
+            print_response::<BackupBody>(resp).await?;
+        }
+        ("restore", Some(matches)) => {
+            let path = matches
+                .value_of("path")
+                .expect("Path not provided")
+                .to_string();
+
+            let resp = client
+                .post(format!("{}/restore", base))
+                .json(&RestoreItem { path })
+                .send()
+                .await?;
+            print_response::<RestoreBody>(resp).await?;
+        }
+        ("import", Some(matches)) => {
+            let path = matches.value_of("file").expect("File not provided");
+            let format = match matches.value_of("format") {
+                Some(format) => format.parse()?,
+                None if path.ends_with(".csv") => Format::Csv,
+                None => Format::Jsonl,
+            };
+            let reader: Box<dyn Read> = match path {
+                "-" => Box::new(io::stdin()),
+                path => Box::new(fs::File::open(path)?),
+            };
+
+            // The file is read and sent in batches, so that it's never held in memory as a whole.
+            let mut pairs = PairReader::new(reader, format).peekable();
+            let mut imported = 0;
+            while pairs.peek().is_some() {
+                let mut batch = PairWriter::new(vec![], Format::Jsonl)?;
+                for pair in pairs.by_ref().take(IMPORT_BATCH_SIZE) {
+                    let (key, val) = pair?;
+                    batch.write(&key, &val)?;
+                }
+                let resp = client
+                    .post(format!("{}/import", base))
+                    .query(&[("format", "jsonl")])
+                    .body(batch.into_inner()?)
+                    .send()
+                    .await?;
+                if !resp.status().is_success() {
+                    return print_response::<ImportBody>(resp).await;
+                }
+                imported += resp.json::<ImportBody>().await?.imported;
+            }
+            println!("{}", ImportBody::from(imported));
+        }
+        ("export", Some(matches)) => {
+            let format = matches.value_of("format").unwrap_or("jsonl");
+            let mut resp = client
+                .get(format!("{}/export", base))
+                .query(&[("format", format)])
+                .send()
+                .await?;
+            if !resp.status().is_success() {
+                return print_response::<ImportBody>(resp).await;
+            }
+            let mut writer: Box<dyn Write> = match matches.value_of("output") {
+                Some(path) => Box::new(fs::File::create(path)?),
+                None => Box::new(io::stdout()),
+            };
+            // An export that fails once it's started is ended with a line saying so, which is
+            // looked for in the last bytes received.
+            let mut tail = vec![];
+            while let Some(chunk) = resp.chunk().await? {
+                writer.write_all(&chunk)?;
+                tail.extend_from_slice(&chunk);
+                tail.drain(..tail.len().saturating_sub(EXPORT_TAIL_SIZE));
+            }
+            writer.flush()?;
+            let tail = String::from_utf8_lossy(&tail);
+            let last_line = tail
+                .trim_end_matches('\n')
+                .rsplit('\n')
+                .next()
+                .unwrap_or_default();
+            if let Some(err) = last_line.strip_prefix(EXPORT_FAILED) {
+                anyhow::bail!("The export is incomplete: {}", err);
+            }
         }
         ("sub", Some(_)) => {
             let conn = pubsub::connect(conn_strings.nats_host());
             if let Some(nc) = conn {
-                let rm_sub = pubsub::subscribe(&nc, "rm")?;
+                let rm_sub = pubsub::subscribe(&nc, &pubsub::subject(bucket, "rm"))?;
                 // move this subscription to another thread, so that we don't block the main thread
                 // while iterating.
                 tokio::spawn(async move {
@@ -100,14 +544,136 @@ async fn main() -> Result<()> {
                         }
                     }
                 });
-                let set_sub = pubsub::subscribe(&nc, "set")?;
+                let set_sub = pubsub::subscribe(&nc, &pubsub::subject(bucket, "set"))?;
                 for msg in set_sub.messages() {
                     let set_item: SetItem = serde_json::from_slice(&msg.data)?;
                     println!("Set: {}", set_item);
                 }
             }
         }
+        ("watch", Some(matches)) => {
+            let mut query = vec![];
+            for &name in &["key", "prefix", "after"] {
+                if let Some(val) = matches.value_of(name) {
+                    query.push((name, val));
+                }
+            }
+            let mut resp = client
+                .get(format!("{}/watch", base))
+                .query(&query)
+                .send()
+                .await?;
+            if !resp.status().is_success() {
+                return print_response::<ChangeBody>(resp).await;
+            }
+
+            // Every event is made up of lines, the change is on the one starting with `data:`.
+            let mut buf = vec![];
+            while let Some(chunk) = resp.chunk().await? {
+                buf.extend_from_slice(&chunk);
+                while let Some(end) = buf.iter().position(|&b| b == b'\n') {
+                    let line: Vec<u8> = buf.drain(..=end).collect();
+                    if let Some(data) = line.strip_prefix(b"data:") {
+                        let change: ChangeBody = serde_json::from_slice(data)?;
+                        println!("{}", change);
+                    }
+                }
+            }
+        }
+        ("buckets", Some(_)) => {
+            let resp = client
+                .get(format!("{}/buckets", server_host))
+                .send()
+                .await?;
+            print_response::<BucketsBody>(resp).await?;
+        }
+        ("create-bucket", Some(matches)) => {
+            let name = matches.value_of("name").expect("Name not provided");
+
+            let resp = client
+                .post(format!("{}/buckets/{}", server_host, name))
+                .send()
+                .await?;
+            print_response::<CreateBucketBody>(resp).await?;
+        }
+        ("drop-bucket", Some(matches)) => {
+            let name = matches.value_of("name").expect("Name not provided");
+
+            let resp = client
+                .delete(format!("{}/buckets/{}", server_host, name))
+                .send()
+                .await?;
+            print_response::<DropBucketBody>(resp).await?;
+        }
         _ => unreachable!(),
     }
     Ok(())
 }
+
+// Read the whole file, or stdin if the path is `-`.
+fn read_file(path: &str) -> Result<Vec<u8>> {
+    if path == "-" {
+        let mut buf = vec![];
+        io::stdin().read_to_end(&mut buf)?;
+        return Ok(buf);
+    }
+    Ok(fs::read(path)?)
+}
+
+// Argument to pick the format of an import or an export.
+fn format_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("format")
+        .long("format")
+        .takes_value(true)
+        .possible_values(&["jsonl", "csv"])
+        .help("Format of the file, `jsonl` or `csv`.")
+}
+
+// Arguments to select the range of keys for the scan and keys subcommands.
+fn scan_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("prefix")
+            .long("prefix")
+            .takes_value(true)
+            .conflicts_with_all(&["start", "end"])
+            .help("Only list the keys starting with this prefix."),
+        Arg::with_name("start")
+            .long("start")
+            .takes_value(true)
+            .help("Start listing from this key."),
+        Arg::with_name("end")
+            .long("end")
+            .takes_value(true)
+            .help("Stop listing before this key."),
+        Arg::with_name("after")
+            .long("after")
+            .takes_value(true)
+            .help("Continue listing after this cursor, returned as `next` by the previous page."),
+        Arg::with_name("limit")
+            .long("limit")
+            .takes_value(true)
+            .help("Maximum number of results to list."),
+    ]
+}
+
+// Query string for the scan and keys routes.
+fn scan_query<'a>(matches: &'a ArgMatches) -> Vec<(&'static str, &'a str)> {
+    ["prefix", "start", "end", "after", "limit"]
+        .iter()
+        .filter_map(|&arg| matches.value_of(arg).map(|val| (arg, val)))
+        .collect()
+}
+
+// Print the body of the response, or the error returned by the server if the request
+// was rejected, e.g. because its condition didn't hold, or failed.
+async fn print_response<T: DeserializeOwned + Display>(resp: reqwest::Response) -> Result<()> {
+    let status = resp.status();
+    if status.is_client_error() || status.is_server_error() {
+        let err: ErrorBody = resp.json().await?;
+        println!("{}: {}", status, err);
+    } else {
+        let body: T = resp.json().await?;
+        println!("{}", body);
+    }
+    Ok(())
+}
@@ -1,27 +1,340 @@
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
+    io::{self, BufWriter, Read, Write},
     net::{IpAddr, Ipv4Addr},
+    ops::Bound,
+    path::PathBuf,
     str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
 };
 
 use kv_store::{
-    models::{GetBody, RmBody, RmItem, SetBody, SetItem},
-    pubsub, ConnStrings, KVStore, KVStoreError,
+    backup_path,
+    bulk::{self, Format},
+    engines::{self, AsyncEngine, EngineStats, KeyRange},
+    metrics::Metrics,
+    models::{
+        BackupBody, BackupItem, BatchBody, BatchItem, BatchOp, BucketsBody, ChangeBody,
+        CompactBody, ComponentHealth, CreateBucketBody, DropBucketBody, Encoding, ErrorBody,
+        GetBody, HealthBody, HealthStatus, ImportBody, IncrBody, IncrItem, KeysBody, RestoreBody,
+        RestoreItem, RmBody, RmItem, ScanBody, SetBody, SetItem, TtlBody, TxnBody, TxnItem, TxnOp,
+    },
+    pubsub,
+    watch::{Changes, Event as ChangeEvent, EventId, Keys},
+    Buckets, ConnStrings, KVStoreError, WriteOp,
 };
 use nats::Connection;
 use rocket::serde::json::Json;
-use rocket::{http::hyper::Uri, response::status, Config, State};
+use rocket::{
+    data::{Data, Limits, ToByteUnit},
+    fairing::{AdHoc, Fairing, Info, Kind},
+    http::{hyper::Uri, uri::Origin, ContentType, Status},
+    outcome::Outcome,
+    request::{self, FromRequest},
+    response::{
+        status,
+        stream::{ByteStream, Event as SseEvent, EventStream},
+    },
+    tokio::{
+        io::AsyncReadExt,
+        sync::{broadcast::error::RecvError, mpsc},
+        task, time,
+    },
+    Config, Request, Response, Shutdown, State,
+};
 
 #[macro_use]
 extern crate rocket;
 
 type Result<T, E = rocket::response::Debug<KVStoreError>> = std::result::Result<T, E>;
 
+// Default and maximum number of results returned by a scan.
+const DEFAULT_SCAN_LIMIT: usize = 100;
+const MAX_SCAN_LIMIT: usize = 1000;
+
+// Maximum size of a request body, in mebibytes.
+const MAX_BODY_SIZE: u64 = 16;
+
+// Time to wait for NATS to answer a health check.
+const PUBSUB_CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+// Maximum number of operations in a batch.
+const MAX_BATCH_OPS: usize = 1000;
+
+// Maximum size of the body of an import, in gibibytes. Imports are streamed into the store,
+// so they aren't bound by the size of the other request bodies.
+const MAX_IMPORT_SIZE: u64 = 4;
+
+// Size of the chunks that imports and exports are streamed in, and the number of chunks
+// buffered between the request or the response and the store.
+const TRANSFER_CHUNK_SIZE: usize = 64 * 1024;
+const TRANSFER_CHANNEL_SIZE: usize = 4;
+
+// Errors returned by the routes, so that invalid requests and requests whose
+// conditions don't hold can be told apart from failures of the store itself.
+// All of them have a JSON body with the error.
+#[derive(Responder)]
+enum ApiError {
+    #[response(status = 400)]
+    BadRequest(Json<ErrorBody>),
+    #[response(status = 404)]
+    NotFound(Json<ErrorBody>),
+    #[response(status = 409)]
+    Conflict(Json<ErrorBody>),
+    #[response(status = 410)]
+    Gone(Json<ErrorBody>),
+    #[response(status = 412)]
+    PreconditionFailed(Json<ErrorBody>),
+    #[response(status = 413)]
+    PayloadTooLarge(Json<ErrorBody>),
+    #[response(status = 500)]
+    Internal(Json<ErrorBody>),
+    #[response(status = 501)]
+    NotImplemented(Json<ErrorBody>),
+}
+
+impl From<KVStoreError> for ApiError {
+    fn from(err: KVStoreError) -> Self {
+        match err {
+            KVStoreError::Base64(_)
+            | KVStoreError::InvalidBucket(_)
+            | KVStoreError::InvalidSnapshot(_)
+            | KVStoreError::InvalidPath(_)
+            | KVStoreError::InvalidFormat(_)
+            | KVStoreError::InvalidRecord(..)
+            | KVStoreError::InvalidEventId(_) => ApiError::BadRequest(Json(err.to_string().into())),
+            KVStoreError::KeyNotFound(_) | KVStoreError::BucketNotFound(_) => {
+                ApiError::NotFound(Json(err.to_string().into()))
+            }
+            KVStoreError::KeyExists(_)
+            | KVStoreError::BucketExists(_)
+            | KVStoreError::DirNotEmpty(_)
+            | KVStoreError::NotAnInteger(_)
+            | KVStoreError::Overflow(_) => ApiError::Conflict(Json(err.to_string().into())),
+            KVStoreError::EventsUnavailable(_) => ApiError::Gone(Json(err.to_string().into())),
+            KVStoreError::VersionMismatch(..) => {
+                ApiError::PreconditionFailed(Json(err.to_string().into()))
+            }
+            KVStoreError::TooLarge(_) => ApiError::PayloadTooLarge(Json(err.to_string().into())),
+            KVStoreError::Unsupported(_) => ApiError::NotImplemented(Json(err.to_string().into())),
+            // Failures of the store itself, e.g. IO errors or poisoned locks.
+            err => {
+                log::error!("Request failed: {}", err);
+                ApiError::Internal(Json(err.to_string().into()))
+            }
+        }
+    }
+}
+
+// Response of the get route: the value is either returned in a JSON body or as is.
+#[derive(Responder)]
+enum GetResponse {
+    Json(Json<GetBody>),
+    Raw(Vec<u8>),
+    #[response(status = 404)]
+    NotFound(()),
+}
+
+// Name of the bucket a request was routed to, if it's not for the default bucket.
+struct RoutedBucket(Option<String>);
+
+// Request guard for the bucket a request is for. Requests to `/buckets/{name}/...` are for the
+// bucket with that name, see `bucket_router`, all others are for the default bucket.
+struct Bucket<'r> {
+    name: Option<&'r str>,
+    buckets: &'r Arc<Buckets>,
+    changes: &'r Arc<Changes>,
+    metrics: &'r Metrics,
+}
+
+impl Bucket<'_> {
+    // The engine backing the bucket. Returns a BucketNotFound error if the bucket doesn't exist.
+    fn engine(&self) -> Result<AsyncEngine, KVStoreError> {
+        let buckets = self.buckets;
+        let engine = match self.name {
+            Some(name) => buckets.bucket(name)?,
+            None => buckets.default_bucket(),
+        };
+        Ok(AsyncEngine::new(engine))
+    }
+
+    // Subject to publish the actions of the given kind on the keys in the bucket to.
+    fn subject(&self, action: &str) -> String {
+        pubsub::subject(self.name, action)
+    }
+
+    // Publish the item of a set to NATS, if connected, counting whether it was published. The
+    // watchers of the key get the change from the engine itself.
+    async fn publish_set(
+        &self,
+        conn: &Option<Connection>,
+        item: SetItem,
+    ) -> Result<(), KVStoreError> {
+        if let Some(nc) = conn {
+            let published = pubsub::publish_action(nc, &self.subject("set"), Box::new(item)).await;
+            self.metrics.observe_publish(published.is_ok());
+            published?;
+        }
+        Ok(())
+    }
+
+    // Publish the item of an rm to NATS, if connected, counting whether it was published. The
+    // watchers of the key get the change from the engine itself.
+    async fn publish_rm(
+        &self,
+        conn: &Option<Connection>,
+        item: RmItem,
+    ) -> Result<(), KVStoreError> {
+        if let Some(nc) = conn {
+            let published = pubsub::publish_action(nc, &self.subject("rm"), Box::new(item)).await;
+            self.metrics.observe_publish(published.is_ok());
+            published?;
+        }
+        Ok(())
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Bucket<'r> {
+    type Error = std::convert::Infallible;
+
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let RoutedBucket(name) = req.local_cache(|| RoutedBucket(None));
+        let buckets = req
+            .rocket()
+            .state::<Arc<Buckets>>()
+            .expect("The buckets are not managed by the server");
+        let changes = req
+            .rocket()
+            .state::<Arc<Changes>>()
+            .expect("The changes are not managed by the server");
+        let metrics = req
+            .rocket()
+            .state::<Metrics>()
+            .expect("The metrics are not managed by the server");
+        Outcome::Success(Bucket {
+            name: name.as_deref(),
+            buckets,
+            changes,
+            metrics,
+        })
+    }
+}
+
+// Id of the last event a client of `/watch` got, sent back in the `Last-Event-ID` header when
+// it reconnects.
+struct LastEventId(Option<EventId>);
+
+// Directory that snapshots are written to and restored from, see `backup_path`.
+struct BackupDir(PathBuf);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for LastEventId {
+    type Error = std::convert::Infallible;
+
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let id = req.headers().get_one("Last-Event-ID");
+        Outcome::Success(LastEventId(id.and_then(|id| id.parse().ok())))
+    }
+}
+
+// Opens the buckets, loading their indexes, before the server starts taking requests. The
+// server can't do anything without them, so if they can't be opened, it fails to launch.
+// The changes made in the buckets are published to `changes` by the engines themselves.
+fn open_buckets(conn_strings: ConnStrings, changes: Arc<Changes>) -> AdHoc {
+    AdHoc::try_on_ignite("Open buckets", |rocket| async move {
+        let open = task::spawn_blocking(move || {
+            Buckets::open_with_changes(
+                conn_strings.engine(),
+                conn_strings.data_dir(),
+                conn_strings.store_config(),
+                Some(changes),
+            )
+        });
+        match open.await.map_err(KVStoreError::from).and_then(|open| open) {
+            Ok(buckets) => Ok(rocket.manage(Arc::new(buckets))),
+            Err(err) => {
+                log::error!("Could not open the storage engine: {}", err);
+                Err(rocket)
+            }
+        }
+    })
+}
+
+// Routes the requests to `/buckets/{name}/...` to the same routes as the requests to the
+// default bucket, e.g. `/buckets/users/get?key=abc` to `/get?key=abc`, keeping the name of
+// the bucket around for the Bucket guard.
+fn bucket_router() -> AdHoc {
+    AdHoc::on_request("Bucket router", |req, _| {
+        Box::pin(async move {
+            if let Some((name, uri)) = split_bucket(&req.uri().to_string()) {
+                if let Ok(uri) = Origin::parse_owned(uri) {
+                    req.local_cache(|| RoutedBucket(Some(name)));
+                    req.set_uri(uri);
+                }
+            }
+        })
+    })
+}
+
+// Time at which a request was received.
+struct RequestStart(Instant);
+
+// Records every request in the metrics, along with the route that handled it, the status of
+// the response and the time it took to respond. Requests that weren't routed anywhere are
+// recorded under `unmatched`.
+struct RequestMetrics;
+
+#[rocket::async_trait]
+impl Fairing for RequestMetrics {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request metrics",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, req: &mut Request<'_>, _: &mut Data<'_>) {
+        req.local_cache(|| RequestStart(Instant::now()));
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        let RequestStart(start) = req.local_cache(|| RequestStart(Instant::now()));
+        let route = req
+            .route()
+            .and_then(|route| route.name.as_deref())
+            .unwrap_or("unmatched");
+        let metrics = req
+            .rocket()
+            .state::<Metrics>()
+            .expect("The metrics are not managed by the server");
+        let method = req.method().as_str();
+        let status = res.status().code;
+        if let Err(err) = metrics.observe_request(method, route, status, start.elapsed()) {
+            log::error!("Could not record the request: {}", err);
+        }
+    }
+}
+
+// Split a URI of the form `/buckets/{name}/...` into the name and the rest of the URI.
+// `/buckets/{name}` itself isn't split, since it's used to create and drop the bucket.
+fn split_bucket(uri: &str) -> Option<(String, String)> {
+    let rest = uri.strip_prefix("/buckets/")?;
+    let end = rest.find(&['/', '?'][..])?;
+    let (name, uri) = rest.split_at(end);
+    if name.is_empty() || !uri.starts_with('/') {
+        return None;
+    }
+    Some((name.to_string(), uri.to_string()))
+}
+
 #[launch]
 fn rocket() -> _ {
-    let conn_strings = ConnStrings::load();
+    let conn_strings =
+        ConnStrings::load().unwrap_or_else(|err| panic!("Invalid settings: {}", err));
     let nc = pubsub::connect(conn_strings.nats_host());
-    let store = KVStore::open(conn_strings.log_file_path()).expect("Could not open KVStore");
+    let changes = Arc::new(Changes::new(conn_strings.watch_history()));
 
     let server_host = conn_strings
         .server_host()
@@ -33,19 +346,67 @@ fn rocket() -> _ {
     let config = Config {
         address: IpAddr::V4(Ipv4Addr::from_str(host).unwrap()),
         port,
+        limits: Limits::default()
+            .limit("bytes", MAX_BODY_SIZE.mebibytes())
+            .limit("json", MAX_BODY_SIZE.mebibytes()),
         ..Config::default()
     };
 
     rocket::build()
-        .mount("/", routes![index, set, get, rm])
+        .mount(
+            "/",
+            routes![
+                index,
+                health_live,
+                health_ready,
+                get_key,
+                put_key,
+                delete_key,
+                set,
+                set_raw,
+                get,
+                ttl,
+                scan,
+                keys,
+                rm,
+                txn,
+                batch,
+                incr,
+                decr,
+                compact,
+                backup,
+                restore,
+                import,
+                export,
+                watch,
+                stats,
+                metrics,
+                list_buckets,
+                create_bucket,
+                drop_bucket
+            ],
+        )
         .configure(&config)
-        .manage(store)
+        .register("/", catchers![default_catcher])
+        .attach(open_buckets(conn_strings.clone(), Arc::clone(&changes)))
+        .attach(bucket_router())
+        .attach(RequestMetrics)
         .manage(nc)
+        .manage(changes)
+        .manage(BackupDir(PathBuf::from(conn_strings.backup_dir())))
+        .manage(Metrics::default())
+}
+
+// Respond to requests that fail before reaching a route, e.g. because there's no route for
+// them or their body is malformed, with a JSON body, the same as the errors of the routes.
+#[catch(default)]
+fn default_catcher(status: Status, _req: &Request) -> Json<ErrorBody> {
+    Json(status.to_string().into())
 }
 
 #[get("/")]
 fn index(
-    _store_state: &State<KVStore>,
+    _buckets_state: &State<Arc<Buckets>>,
     _conn_state: &State<Option<Connection>>,
 ) -> Json<HashMap<String, bool>> {
     let mut response = HashMap::new();
@@ -53,56 +414,930 @@ fn index(
     Json(response)
 }
 
-#[post("/set", format = "json", data = "<item>")]
-fn set(
-    store_state: &State<KVStore>,
+// Liveness probe: the server is up as long as it's responding, whether or not the store or
+// NATS are, so that it isn't restarted because of either of them.
+#[get("/health/live")]
+fn health_live() -> Json<HealthBody> {
+    let mut components = BTreeMap::new();
+    components.insert(String::from("server"), ComponentHealth::from(Ok(())));
+    Json(HealthBody::from(components))
+}
+
+// Readiness probe: the server is ready to take requests as long as the store can be written
+// to and NATS, if it's connected, responds. The indexes of the buckets are always loaded by
+// then, see `open_buckets`, so they aren't checked. Returns a 503 if either is down, along
+// with the status of both of them.
+#[get("/health/ready")]
+async fn health_ready(
+    buckets_state: &State<Arc<Buckets>>,
     conn_state: &State<Option<Connection>>,
-    item: Json<SetItem>,
-) -> Result<status::Created<Json<SetBody>>> {
-    let store = store_state.inner();
-    let val = store.set(item.key.clone(), item.val.clone())?;
-    let conn = conn_state.inner();
-    if let Some(nc) = conn {
-        pubsub::publish_action(nc, "set", Box::new(item.into_inner()))?;
-    }
-    let response = status::Created::new("");
-    if let Some(val) = val {
-        Ok(response.body(Json(SetBody::from((true, Some(val))))))
+) -> status::Custom<Json<HealthBody>> {
+    let mut components = BTreeMap::new();
+    let store = check_store(Arc::clone(buckets_state.inner())).await;
+    components.insert(String::from("store"), ComponentHealth::from(store));
+    let pubsub = match conn_state.inner() {
+        Some(nc) => ComponentHealth::from(check_pubsub(nc.clone()).await),
+        None => ComponentHealth::disabled(),
+    };
+    components.insert(String::from("pubsub"), pubsub);
+
+    let body = HealthBody::from(components);
+    let status = if body.status == HealthStatus::Down {
+        Status::ServiceUnavailable
     } else {
-        Ok(response.body(Json(SetBody::from((true, None)))))
+        Status::Ok
+    };
+    status::Custom(status, Json(body))
+}
+
+// Check that every bucket can be written to.
+async fn check_store(buckets: Arc<Buckets>) -> Result<(), String> {
+    let check = task::spawn_blocking(move || {
+        let names = buckets.list_buckets().map_err(|err| err.to_string())?;
+        let default = buckets.default_bucket();
+        default.check_writable().map_err(|err| err.to_string())?;
+        for name in names {
+            match buckets.bucket(&name) {
+                Ok(engine) => engine
+                    .check_writable()
+                    .map_err(|err| format!("Bucket `{}`: {}", name, err))?,
+                // The bucket was dropped in the meantime.
+                Err(KVStoreError::BucketNotFound(_)) => continue,
+                Err(err) => return Err(err.to_string()),
+            }
+        }
+        Ok(())
+    });
+    check.await.map_err(|err| err.to_string())?
+}
+
+// Check that NATS answers a ping, on the blocking thread pool since the ping blocks.
+async fn check_pubsub(nc: Connection) -> Result<(), String> {
+    match time::timeout(PUBSUB_CHECK_TIMEOUT, task::spawn_blocking(move || nc.rtt())).await {
+        Ok(Ok(Ok(_))) => Ok(()),
+        Ok(Ok(Err(err))) => Err(err.to_string()),
+        Ok(Err(err)) => Err(err.to_string()),
+        Err(_) => Err(String::from("Timed out waiting for NATS to respond.")),
     }
 }
 
-#[get("/get?<key>")]
-fn get(
-    state: &State<KVStore>,
+#[post("/set", format = "json", data = "<item>")]
+async fn set(
+    bucket: Bucket<'_>,
+    conn_state: &State<Option<Connection>>,
+    item: Json<SetItem>,
+) -> Result<status::Created<Json<SetBody>>, ApiError> {
+    let item = item.into_inner();
+    let key = item.encoding.decode(&item.key)?;
+    let val = item.encoding.decode(&item.val)?;
+    let (body, _) = store_set(&bucket, conn_state.inner(), item, key, val).await?;
+    Ok(status::Created::new("").body(Json(body)))
+}
+
+// Same as set, but the value is the body of the request, as is.
+#[post(
+    "/set?<key>&<encoding>&<ttl>&<if_absent>&<if_version>",
+    format = "application/octet-stream",
+    data = "<val>"
+)]
+#[allow(clippy::too_many_arguments)]
+async fn set_raw(
+    bucket: Bucket<'_>,
+    conn_state: &State<Option<Connection>>,
+    key: String,
+    encoding: Option<Encoding>,
+    ttl: Option<u64>,
+    if_absent: bool,
+    if_version: Option<u64>,
+    val: Vec<u8>,
+) -> Result<status::Created<Json<SetBody>>, ApiError> {
+    let encoding = encoding.unwrap_or_default();
+    let key = encoding.decode(&key)?;
+    let item = raw_set_item(&key, &val, encoding, ttl, if_absent, if_version);
+    let (body, _) = store_set(&bucket, conn_state.inner(), item, key, val).await?;
+    Ok(status::Created::new("").body(Json(body)))
+}
+
+#[get("/get?<key>&<encoding>&<raw>")]
+async fn get(
+    bucket: Bucket<'_>,
     _conn_state: &State<Option<Connection>>,
     key: String,
-) -> Result<Json<GetBody>> {
-    let store = state.inner();
-    let val = store.get(key);
-    if let Ok(Some(val)) = val {
-        Ok(Json(GetBody::from((true, Some(val)))))
-    } else {
-        Ok(Json(GetBody::from((false, None))))
+    encoding: Option<Encoding>,
+    raw: bool,
+) -> Result<GetResponse, ApiError> {
+    let store = bucket.engine()?;
+    let encoding = encoding.unwrap_or_default();
+    let key = encoding.decode(&key)?;
+    // Missing keys are reported in the body, rather than with a 404, unless the value is
+    // fetched as is. Use `/v1/keys/{key}` for a 404 in either case.
+    match read_key(&store, key).await {
+        Ok((val, _)) if raw => Ok(GetResponse::Raw(val)),
+        Ok((val, version)) => Ok(GetResponse::Json(Json(GetBody::from((
+            true,
+            Some(val),
+            version,
+            encoding,
+        ))))),
+        Err(KVStoreError::KeyNotFound(_)) if raw => Ok(GetResponse::NotFound(())),
+        Err(KVStoreError::KeyNotFound(_)) => Ok(GetResponse::Json(Json(GetBody::from((
+            false, None, encoding,
+        ))))),
+        Err(err) => Err(err.into()),
+    }
+}
+
+#[get("/ttl?<key>&<encoding>")]
+async fn ttl(
+    bucket: Bucket<'_>,
+    key: String,
+    encoding: Option<Encoding>,
+) -> Result<Json<TtlBody>, ApiError> {
+    let store = bucket.engine()?;
+    let key = encoding.unwrap_or_default().decode(&key)?;
+    match store.ttl(key).await {
+        // Round up, so that a key that's about to expire doesn't report a TTL of 0.
+        Ok(ttl) => {
+            let ttl = ttl.map(|ttl| ttl.as_secs() + u64::from(ttl.subsec_nanos() > 0));
+            Ok(Json(TtlBody::from((true, ttl))))
+        }
+        Err(KVStoreError::KeyNotFound(_)) => Ok(Json(TtlBody::from((false, None)))),
+        Err(err) => Err(err.into()),
     }
 }
 
+#[get("/scan?<prefix>&<start>&<end>&<after>&<limit>&<encoding>")]
+async fn scan(
+    bucket: Bucket<'_>,
+    prefix: Option<String>,
+    start: Option<String>,
+    end: Option<String>,
+    after: Option<String>,
+    limit: Option<usize>,
+    encoding: Option<Encoding>,
+) -> Result<Json<ScanBody>, ApiError> {
+    let store = bucket.engine()?;
+    let encoding = encoding.unwrap_or_default();
+    let range = scan_range(prefix, start, end, after, encoding)?;
+    let page = store.scan_page(range, scan_limit(limit)).await?;
+    Ok(Json(ScanBody::from((page.items, page.next, encoding))))
+}
+
+#[get("/keys?<prefix>&<start>&<end>&<after>&<limit>&<encoding>")]
+async fn keys(
+    bucket: Bucket<'_>,
+    prefix: Option<String>,
+    start: Option<String>,
+    end: Option<String>,
+    after: Option<String>,
+    limit: Option<usize>,
+    encoding: Option<Encoding>,
+) -> Result<Json<KeysBody>, ApiError> {
+    let store = bucket.engine()?;
+    let encoding = encoding.unwrap_or_default();
+    let range = scan_range(prefix, start, end, after, encoding)?;
+    let page = store.keys_page(range, scan_limit(limit)).await?;
+    Ok(Json(KeysBody::from((page.items, page.next, encoding))))
+}
+
 #[delete("/rm", format = "json", data = "<item>")]
-fn rm(
-    store_state: &State<KVStore>,
+async fn rm(
+    bucket: Bucket<'_>,
     conn_state: &State<Option<Connection>>,
     item: Json<RmItem>,
-) -> Result<Json<RmBody>> {
-    let store = store_state.inner();
-    let val = store.rm(item.key.clone());
-    let conn = conn_state.inner();
-    if let Some(nc) = conn {
-        pubsub::publish_action(nc, "rm", Box::new(item.into_inner()))?;
-    }
-    if let Ok(Some(val)) = val {
-        Ok(Json(RmBody::from((true, Some(val)))))
+) -> Result<Json<RmBody>, ApiError> {
+    let key = item.encoding.decode(&item.key)?;
+    let encoding = item.encoding;
+    // Missing keys are reported in the body, rather than with a 404.
+    match store_rm(&bucket, conn_state.inner(), item.into_inner(), key).await {
+        Ok(Some(val)) => Ok(Json(RmBody::from((true, Some(val), encoding)))),
+        Ok(None) | Err(KVStoreError::KeyNotFound(_)) => {
+            Ok(Json(RmBody::from((false, None, encoding))))
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+// Get the value of the key, along with its version, as JSON, or as is if `raw` is set.
+// Returns a 404 if the key doesn't exist. HEAD requests are answered by this route as well,
+// without the body.
+#[get("/v1/keys/<key>?<encoding>&<raw>")]
+async fn get_key(
+    bucket: Bucket<'_>,
+    key: &str,
+    encoding: Option<Encoding>,
+    raw: bool,
+) -> Result<GetResponse, ApiError> {
+    let store = bucket.engine()?;
+    let encoding = encoding.unwrap_or_default();
+    let key = encoding.decode(key)?;
+    let (val, version) = read_key(&store, key).await?;
+    if raw {
+        return Ok(GetResponse::Raw(val));
+    }
+    let body = GetBody::from((true, Some(val), version, encoding));
+    Ok(GetResponse::Json(Json(body)))
+}
+
+// Set the value of the key to the body of the request, as is. Returns a 201 if the key
+// was created and a 200 if it already existed.
+#[put(
+    "/v1/keys/<key>?<encoding>&<ttl>&<if_absent>&<if_version>",
+    data = "<val>"
+)]
+#[allow(clippy::too_many_arguments)]
+async fn put_key(
+    bucket: Bucket<'_>,
+    conn_state: &State<Option<Connection>>,
+    key: &str,
+    encoding: Option<Encoding>,
+    ttl: Option<u64>,
+    if_absent: bool,
+    if_version: Option<u64>,
+    val: Vec<u8>,
+) -> Result<status::Custom<Json<SetBody>>, ApiError> {
+    let encoding = encoding.unwrap_or_default();
+    let key = encoding.decode(key)?;
+    let item = raw_set_item(&key, &val, encoding, ttl, if_absent, if_version);
+    let (body, created) = store_set(&bucket, conn_state.inner(), item, key, val).await?;
+    let status = if created { Status::Created } else { Status::Ok };
+    Ok(status::Custom(status, Json(body)))
+}
+
+// Remove the key. Returns a 404 if the key doesn't exist.
+#[delete("/v1/keys/<key>?<encoding>&<if_version>")]
+async fn delete_key(
+    bucket: Bucket<'_>,
+    conn_state: &State<Option<Connection>>,
+    key: &str,
+    encoding: Option<Encoding>,
+    if_version: Option<u64>,
+) -> Result<Json<RmBody>, ApiError> {
+    let encoding = encoding.unwrap_or_default();
+    let item = RmItem {
+        key: key.to_string(),
+        if_version,
+        encoding,
+    };
+    let key = encoding.decode(key)?;
+    let val = store_rm(&bucket, conn_state.inner(), item, key).await?;
+    Ok(Json(RmBody::from((true, val, encoding))))
+}
+
+#[post("/txn", format = "json", data = "<item>")]
+async fn txn(
+    bucket: Bucket<'_>,
+    conn_state: &State<Option<Connection>>,
+    item: Json<TxnItem>,
+) -> Result<Json<TxnBody>, ApiError> {
+    let store = bucket.engine()?;
+    let item = item.into_inner();
+    let vals = match store.commit_txn(item.write_ops()?).await {
+        // One of the keys to be removed doesn't exist, so none of the writes were applied.
+        Err(err @ KVStoreError::KeyNotFound(_)) => {
+            return Err(ApiError::Conflict(Json(err.to_string().into())))
+        }
+        vals => vals?,
+    };
+    // The transaction is committed by then, so every write is published even if some of them
+    // can't be, and the failures are reported together rather than failing the request.
+    let encoding = item.encoding;
+    let mut unpublished = vec![];
+    for op in item.ops {
+        let published = match op {
+            TxnOp::Set { key, val } => {
+                let item = SetItem {
+                    key,
+                    val,
+                    ttl: None,
+                    if_absent: false,
+                    if_version: None,
+                    encoding,
+                };
+                bucket.publish_set(conn_state, item).await
+            }
+            TxnOp::Rm { key } => {
+                let item = RmItem {
+                    key,
+                    if_version: None,
+                    encoding,
+                };
+                bucket.publish_rm(conn_state, item).await
+            }
+        };
+        if let Err(err) = published {
+            unpublished.push(err.to_string());
+        }
+    }
+    if !unpublished.is_empty() {
+        log::error!(
+            "Could not publish {} of the {} writes of a transaction: {}",
+            unpublished.len(),
+            vals.len(),
+            unpublished.join(", ")
+        );
+    }
+    Ok(Json(TxnBody::from((true, vals, item.encoding))))
+}
+
+// An operation in a batch with its key and value decoded.
+enum Decoded {
+    Get(Vec<u8>),
+    Write(WriteOp),
+}
+
+// Apply the operations in order and return the result of each of them. Unlike a transaction,
+// each operation succeeds or fails on its own. Consecutive gets are read, and consecutive
+// writes are committed, at once.
+#[post("/batch", format = "json", data = "<item>")]
+async fn batch(
+    bucket: Bucket<'_>,
+    conn_state: &State<Option<Connection>>,
+    item: Json<BatchItem>,
+) -> Result<Json<BatchBody>, ApiError> {
+    let item = item.into_inner();
+    if item.ops.len() > MAX_BATCH_OPS {
+        let err = format!("A batch can have at most {} operations.", MAX_BATCH_OPS);
+        return Err(ApiError::BadRequest(Json(err.into())));
+    }
+    let store = bucket.engine()?;
+    let encoding = item.encoding;
+    let mut ops = vec![];
+    for op in &item.ops {
+        ops.push(match op {
+            BatchOp::Get { key } => Decoded::Get(encoding.decode(key)?),
+            BatchOp::Set { key, val } => Decoded::Write(WriteOp::Set {
+                key: encoding.decode(key)?,
+                val: encoding.decode(val)?,
+            }),
+            BatchOp::Rm { key } => Decoded::Write(WriteOp::Rm {
+                key: encoding.decode(key)?,
+            }),
+        });
+    }
+
+    let mut results = Vec::with_capacity(ops.len());
+    let mut ops = ops.into_iter().peekable();
+    while let Some(op) = ops.next() {
+        match op {
+            Decoded::Get(key) => {
+                let mut keys = vec![key];
+                while let Some(Decoded::Get(_)) = ops.peek() {
+                    if let Some(Decoded::Get(key)) = ops.next() {
+                        keys.push(key);
+                    }
+                }
+                let vals = store.get_many(keys.clone()).await?;
+                for (key, val) in keys.into_iter().zip(vals) {
+                    let val = val.map(Some).ok_or_else(|| {
+                        KVStoreError::KeyNotFound(String::from_utf8_lossy(&key).into_owned())
+                    });
+                    results.push((true, val));
+                }
+            }
+            Decoded::Write(write) => {
+                let mut writes = vec![write];
+                while let Some(Decoded::Write(_)) = ops.peek() {
+                    if let Some(Decoded::Write(write)) = ops.next() {
+                        writes.push(write);
+                    }
+                }
+                for result in store.write_many(writes).await? {
+                    results.push((false, result));
+                }
+            }
+        }
+    }
+
+    for (op, (_, result)) in item.ops.into_iter().zip(&results) {
+        match op {
+            BatchOp::Set { key, val } if result.is_ok() => {
+                let item = SetItem {
+                    key,
+                    val,
+                    ttl: None,
+                    if_absent: false,
+                    if_version: None,
+                    encoding,
+                };
+                if let Err(err) = bucket.publish_set(conn_state, item).await {
+                    log_unpublished(err);
+                }
+            }
+            BatchOp::Rm { key } if result.is_ok() => {
+                let item = RmItem {
+                    key,
+                    if_version: None,
+                    encoding,
+                };
+                if let Err(err) = bucket.publish_rm(conn_state, item).await {
+                    log_unpublished(err);
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(Json(BatchBody::from((results, encoding))))
+}
+
+#[post("/incr", format = "json", data = "<item>")]
+async fn incr(
+    bucket: Bucket<'_>,
+    conn_state: &State<Option<Connection>>,
+    item: Json<IncrItem>,
+) -> Result<Json<IncrBody>, ApiError> {
+    let item = item.into_inner();
+    let delta = item.delta;
+    store_incr(&bucket, conn_state.inner(), item, delta).await
+}
+
+#[post("/decr", format = "json", data = "<item>")]
+async fn decr(
+    bucket: Bucket<'_>,
+    conn_state: &State<Option<Connection>>,
+    item: Json<IncrItem>,
+) -> Result<Json<IncrBody>, ApiError> {
+    let item = item.into_inner();
+    let delta = item
+        .delta
+        .checked_neg()
+        .ok_or_else(|| KVStoreError::Overflow(item.key.clone()))?;
+    store_incr(&bucket, conn_state.inner(), item, delta).await
+}
+
+#[post("/compact")]
+async fn compact(bucket: Bucket<'_>) -> Result<Json<CompactBody>, ApiError> {
+    let store = bucket.engine()?;
+    let reclaimed = store.compact().await?;
+    Ok(Json(CompactBody::from((true, reclaimed))))
+}
+
+// Write a snapshot of the bucket to a directory within the backup directory, while writes
+// continue.
+#[post("/backup", format = "json", data = "<item>")]
+async fn backup(
+    bucket: Bucket<'_>,
+    backup_dir: &State<BackupDir>,
+    item: Json<BackupItem>,
+) -> Result<Json<BackupBody>, ApiError> {
+    let store = bucket.engine()?;
+    let path = backup_path(&backup_dir.0, &item.path)?;
+    let info = store.snapshot(path).await?;
+    Ok(Json(BackupBody::from((item.into_inner().path, info))))
+}
+
+// Create a bucket out of a snapshot in a directory within the backup directory. The default
+// bucket can't be replaced while the server is running, so only named buckets can be restored.
+#[post("/restore", format = "json", data = "<item>")]
+async fn restore(
+    bucket: Bucket<'_>,
+    backup_dir: &State<BackupDir>,
+    item: Json<RestoreItem>,
+) -> Result<status::Created<Json<RestoreBody>>, ApiError> {
+    let name = match bucket.name {
+        Some(name) => name.to_string(),
+        None => {
+            let err = "Only named buckets can be restored, restore to `/buckets/{name}/restore`";
+            return Err(ApiError::BadRequest(Json(err.to_string().into())));
+        }
+    };
+    let path = backup_path(&backup_dir.0, &item.path)?;
+    let buckets = Arc::clone(bucket.buckets);
+    let bucket = name.clone();
+    task::spawn_blocking(move || buckets.restore_bucket(&bucket, &path))
+        .await
+        .map_err(KVStoreError::from)??;
+    let body = RestoreBody::from((name, true));
+    Ok(status::Created::new("").body(Json(body)))
+}
+
+// Store every key-value pair in the body, in JSON Lines or CSV, as it's received.
+#[post("/import?<format>", data = "<data>")]
+async fn import(
+    bucket: Bucket<'_>,
+    format: Option<&str>,
+    data: Data<'_>,
+) -> Result<Json<ImportBody>, ApiError> {
+    let format = transfer_format(format)?;
+    let store = bucket.engine()?;
+    let (sender, receiver) = mpsc::channel(TRANSFER_CHANNEL_SIZE);
+    let import = task::spawn(async move { store.import(ChunkReader::new(receiver), format).await });
+
+    // A byte over the limit is read, so that a body that's too large isn't taken for a whole
+    // one cut short at the limit.
+    let limit = MAX_IMPORT_SIZE.gibibytes().as_u64();
+    let mut body = data.open((limit + 1).bytes());
+    let mut received = 0;
+    let failed = loop {
+        let mut chunk = vec![0; TRANSFER_CHUNK_SIZE];
+        let len = match body.read(&mut chunk).await {
+            Ok(len) => len,
+            Err(err) => break Some(KVStoreError::from(err)),
+        };
+        received += len as u64;
+        if received > limit {
+            break Some(KVStoreError::TooLarge(limit));
+        }
+        chunk.truncate(len);
+        // The import stops reading once it fails, in which case its error is returned below.
+        if len == 0 || sender.send(Ok(chunk)).await.is_err() {
+            break None;
+        }
+    };
+    // Only a body read to its end is taken as the whole of it, otherwise the import is failed
+    // before it gets to the end of the body, so that the last pair read isn't stored cut short.
+    if let Some(err) = &failed {
+        let err = io::Error::new(io::ErrorKind::UnexpectedEof, err.to_string());
+        let _ = sender.send(Err(err)).await;
+    }
+    drop(sender);
+    let imported = import.await.map_err(KVStoreError::from)?;
+    if let Some(err) = failed {
+        return Err(err.into());
+    }
+    let imported = imported?;
+    Ok(Json(ImportBody::from(imported)))
+}
+
+// Stream every key-value pair in the bucket, ordered by key, in JSON Lines or CSV.
+#[get("/export?<format>")]
+async fn export(
+    bucket: Bucket<'_>,
+    format: Option<&str>,
+) -> Result<(ContentType, ByteStream![Vec<u8>]), ApiError> {
+    let format = transfer_format(format)?;
+    let store = bucket.engine()?;
+    let (sender, mut receiver) = mpsc::channel(TRANSFER_CHANNEL_SIZE);
+    let writer = BufWriter::with_capacity(TRANSFER_CHUNK_SIZE, ChunkWriter(sender.clone()));
+    task::spawn(async move {
+        // The status has been sent by the time the export fails, so the response is ended
+        // with a line saying that it failed instead.
+        if let Err(err) = store.export(writer, format).await {
+            log::error!("Export failed: {}", err);
+            let line = bulk::export_failed_line(&err);
+            let _ = sender.send(line.into_bytes()).await;
+        }
+    });
+    let content_type = match format {
+        Format::Jsonl => ContentType::new("application", "x-ndjson"),
+        Format::Csv => ContentType::CSV,
+    };
+    let stream = ByteStream! {
+        while let Some(chunk) = receiver.recv().await {
+            yield chunk;
+        }
+    };
+    Ok((content_type, stream))
+}
+
+// Stream the changes to the keys in the bucket as server-sent events: to all of them, to a
+// single key or to the keys with a prefix. The id of every event is the id of the change. The
+// changes after an id, passed as `after` or as the last event id of a client reconnecting, are
+// sent first, or a 410 is returned if they're no longer kept.
+#[get("/watch?<key>&<prefix>&<after>&<encoding>")]
+#[allow(clippy::too_many_arguments)]
+async fn watch(
+    bucket: Bucket<'_>,
+    last_event_id: LastEventId,
+    key: Option<String>,
+    prefix: Option<String>,
+    after: Option<String>,
+    encoding: Option<Encoding>,
+    mut shutdown: Shutdown,
+) -> Result<EventStream![], ApiError> {
+    // Only the changes to buckets that exist can be watched.
+    bucket.engine()?;
+    let encoding = encoding.unwrap_or_default();
+    let keys = match (key, prefix) {
+        (Some(_), Some(_)) => {
+            let err = String::from("Only one of key and prefix can be watched.");
+            return Err(ApiError::BadRequest(Json(err.into())));
+        }
+        (Some(key), None) => Keys::Key(encoding.decode(&key)?),
+        (None, Some(prefix)) => Keys::Prefix(encoding.decode(&prefix)?),
+        (None, None) => Keys::All,
+    };
+    let name = bucket.name.map(String::from);
+    let after = match after {
+        Some(after) => Some(after.parse()?),
+        None => last_event_id.0,
+    };
+    let (missed, mut receiver) = bucket.changes.subscribe(after)?;
+    let stream = EventStream! {
+        for event in missed {
+            if event.matches(name.as_deref(), &keys) {
+                yield change_event(&event, encoding);
+            }
+        }
+        loop {
+            let event = rocket::tokio::select! {
+                event = receiver.recv() => event,
+                _ = &mut shutdown => break,
+            };
+            match event {
+                Ok(event) => {
+                    if event.matches(name.as_deref(), &keys) {
+                        yield change_event(&event, encoding);
+                    }
+                }
+                // A watcher that falls behind is cut off, it can reconnect to pick up from
+                // the last event it got.
+                Err(RecvError::Lagged(_)) | Err(RecvError::Closed) => break,
+            }
+        }
+    };
+    Ok(stream)
+}
+
+// Server-sent event for a change, named after the kind of change.
+fn change_event(event: &ChangeEvent, encoding: Encoding) -> SseEvent {
+    let body = ChangeBody::from((event, encoding));
+    let op = body.op.clone();
+    SseEvent::json(&body).id(event.id.to_string()).event(op)
+}
+
+#[get("/stats")]
+async fn stats(bucket: Bucket<'_>) -> Result<Json<EngineStats>, ApiError> {
+    let store = bucket.engine()?;
+    Ok(Json(store.stats().await?))
+}
+
+// Metrics about the requests served, the buckets and the process, in the Prometheus text
+// format.
+#[get("/metrics")]
+async fn metrics(
+    buckets_state: &State<Arc<Buckets>>,
+    metrics_state: &State<Metrics>,
+) -> Result<(ContentType, String), ApiError> {
+    let buckets = buckets_state.inner();
+    let default_stats = AsyncEngine::new(buckets.default_bucket()).stats().await?;
+    let mut stats = vec![(None, default_stats)];
+    for name in buckets.list_buckets()? {
+        let engine = match buckets.bucket(&name) {
+            Ok(engine) => engine,
+            // The bucket was dropped in the meantime.
+            Err(KVStoreError::BucketNotFound(_)) => continue,
+            Err(err) => return Err(err.into()),
+        };
+        stats.push((Some(name), AsyncEngine::new(engine).stats().await?));
+    }
+    let content_type = ContentType::with_params("text", "plain", ("version", "0.0.4"));
+    Ok((content_type, metrics_state.render(&stats)?))
+}
+
+#[get("/buckets")]
+async fn list_buckets(buckets_state: &State<Arc<Buckets>>) -> Result<Json<BucketsBody>, ApiError> {
+    let buckets = buckets_state.inner();
+    Ok(Json(BucketsBody::from(buckets.list_buckets()?)))
+}
+
+#[post("/buckets/<name>")]
+async fn create_bucket(
+    buckets_state: &State<Arc<Buckets>>,
+    name: &str,
+) -> Result<status::Created<Json<CreateBucketBody>>, ApiError> {
+    let buckets = Arc::clone(buckets_state.inner());
+    let bucket = name.to_string();
+    task::spawn_blocking(move || buckets.create_bucket(&bucket))
+        .await
+        .map_err(KVStoreError::from)??;
+    let body = CreateBucketBody::from((name.to_string(), true));
+    Ok(status::Created::new("").body(Json(body)))
+}
+
+#[delete("/buckets/<name>")]
+async fn drop_bucket(
+    buckets_state: &State<Arc<Buckets>>,
+    name: &str,
+) -> Result<Json<DropBucketBody>, ApiError> {
+    let buckets = Arc::clone(buckets_state.inner());
+    let bucket = name.to_string();
+    task::spawn_blocking(move || buckets.drop_bucket(&bucket))
+        .await
+        .map_err(KVStoreError::from)??;
+    Ok(Json(DropBucketBody::from((name.to_string(), true))))
+}
+
+// Store the key and it's value, as per the options in the item, and publish the item.
+// Returns the body of the response, along with whether the key was created.
+async fn store_set(
+    bucket: &Bucket<'_>,
+    conn: &Option<Connection>,
+    item: SetItem,
+    key: Vec<u8>,
+    val: Vec<u8>,
+) -> Result<(SetBody, bool), ApiError> {
+    let store = bucket.engine()?;
+    let conditional = item.if_absent || item.if_version.is_some();
+    if conditional && item.ttl.is_some() {
+        let err = String::from("A ttl can't be set along with a condition.");
+        return Err(ApiError::BadRequest(Json(err.into())));
+    }
+    let (body, created) = if item.if_absent {
+        let version = store.set_if_absent(key, val).await?;
+        (
+            SetBody::from((true, None, Some(version), item.encoding)),
+            true,
+        )
+    } else if let Some(version) = item.if_version {
+        let version = store.set_if_version(key, val, version).await?;
+        (
+            SetBody::from((true, None, Some(version), item.encoding)),
+            false,
+        )
     } else {
-        Ok(Json(RmBody::from((false, None))))
+        let val = match item.ttl {
+            Some(ttl) => {
+                store
+                    .set_with_ttl(key, val, Duration::from_secs(ttl))
+                    .await?
+            }
+            None => store.set(key, val).await?,
+        };
+        let created = val.is_none();
+        (SetBody::from((true, val, item.encoding)), created)
+    };
+    if let Err(err) = bucket.publish_set(conn, item).await {
+        log_unpublished(err);
+    }
+    Ok((body, created))
+}
+
+// Remove the key, as per the options in the item, and publish the item once it's removed.
+async fn store_rm(
+    bucket: &Bucket<'_>,
+    conn: &Option<Connection>,
+    item: RmItem,
+    key: Vec<u8>,
+) -> Result<Option<Vec<u8>>, KVStoreError> {
+    let store = bucket.engine()?;
+    let val = match item.if_version {
+        Some(version) => store.rm_if_version(key, version).await?,
+        None => store.rm(key).await?,
+    };
+    if let Err(err) = bucket.publish_rm(conn, item).await {
+        log_unpublished(err);
+    }
+    Ok(val)
+}
+
+// Log a failure to publish a write to NATS. The write has been made by then, so it doesn't
+// fail the request, the failure is only logged and counted in the metrics.
+fn log_unpublished(err: KVStoreError) {
+    log::error!("Could not publish a write: {}", err);
+}
+
+// Get the value of the key, along with its version, if the engine keeps versions.
+async fn read_key(
+    store: &AsyncEngine,
+    key: Vec<u8>,
+) -> Result<(Vec<u8>, Option<u64>), KVStoreError> {
+    match store.get_with_version(key.clone()).await {
+        Ok((val, version)) => Ok((val, Some(version))),
+        // Engines without versions only have the value.
+        Err(KVStoreError::Unsupported(_)) => match store.get(key.clone()).await? {
+            Some(val) => Ok((val, None)),
+            None => Err(KVStoreError::KeyNotFound(
+                String::from_utf8_lossy(&key).into_owned(),
+            )),
+        },
+        Err(err) => Err(err),
+    }
+}
+
+// Build the item for a set whose value is the body of the request, as is. The key and the
+// value are published in the requested encoding, unless they aren't valid UTF-8.
+fn raw_set_item(
+    key: &[u8],
+    val: &[u8],
+    encoding: Encoding,
+    ttl: Option<u64>,
+    if_absent: bool,
+    if_version: Option<u64>,
+) -> SetItem {
+    let encoding = encoding.fit(vec![key, val]);
+    SetItem {
+        key: encoding.encode(key),
+        val: encoding.encode(val),
+        ttl,
+        if_absent,
+        if_version,
+        encoding,
+    }
+}
+
+// Add the delta to the counter stored as the value of the key and publish the new value,
+// the same way as if it was set.
+async fn store_incr(
+    bucket: &Bucket<'_>,
+    conn: &Option<Connection>,
+    item: IncrItem,
+    delta: i64,
+) -> Result<Json<IncrBody>, ApiError> {
+    let store = bucket.engine()?;
+    let key = item.encoding.decode(&item.key)?;
+    let counter = store.incr(key, delta).await?;
+    let item = SetItem {
+        key: item.key,
+        val: item.encoding.encode(counter.to_string().as_bytes()),
+        ttl: None,
+        if_absent: false,
+        if_version: None,
+        encoding: item.encoding,
+    };
+    if let Err(err) = bucket.publish_set(conn, item).await {
+        log_unpublished(err);
+    }
+    Ok(Json(IncrBody::from(counter)))
+}
+
+// Range of keys to scan: the keys with the prefix if there's one, otherwise the keys from
+// the start up to, but excluding, the end. Starts after the cursor of the previous page.
+fn scan_range(
+    prefix: Option<String>,
+    start: Option<String>,
+    end: Option<String>,
+    after: Option<String>,
+    encoding: Encoding,
+) -> Result<KeyRange, KVStoreError> {
+    let decode = |key: Option<String>| key.map(|key| encoding.decode(&key)).transpose();
+    let mut range = match decode(prefix)? {
+        Some(prefix) => engines::prefix_range(prefix),
+        None => (
+            decode(start)?.map_or(Bound::Unbounded, Bound::Included),
+            decode(end)?.map_or(Bound::Unbounded, Bound::Excluded),
+        ),
+    };
+    if let Some(after) = decode(after)? {
+        range.0 = Bound::Excluded(after);
+    }
+    Ok(range)
+}
+
+// Format of an import or an export, JSON Lines unless specified.
+fn transfer_format(format: Option<&str>) -> Result<Format, KVStoreError> {
+    match format {
+        Some(format) => format.parse(),
+        None => Ok(Format::default()),
+    }
+}
+
+// Reads the chunks of a request body received over a channel, so that the body can be
+// read by a blocking reader while it's still being received.
+struct ChunkReader {
+    receiver: mpsc::Receiver<io::Result<Vec<u8>>>,
+    chunk: Vec<u8>,
+    pos: usize,
+}
+
+impl ChunkReader {
+    fn new(receiver: mpsc::Receiver<io::Result<Vec<u8>>>) -> Self {
+        ChunkReader {
+            receiver,
+            chunk: vec![],
+            pos: 0,
+        }
+    }
+}
+
+impl Read for ChunkReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.chunk.len() {
+            match self.receiver.blocking_recv() {
+                Some(Ok(chunk)) => {
+                    self.chunk = chunk;
+                    self.pos = 0;
+                }
+                // The body couldn't be read to its end.
+                Some(Err(err)) => return Err(err),
+                // The whole body has been read.
+                None => return Ok(0),
+            }
+        }
+        let len = buf.len().min(self.chunk.len() - self.pos);
+        buf[..len].copy_from_slice(&self.chunk[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+// Sends everything written to it over a channel, so that a response body can be written by
+// a blocking writer while it's being sent.
+struct ChunkWriter(mpsc::Sender<Vec<u8>>);
+
+impl Write for ChunkWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0
+            .blocking_send(buf.to_vec())
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "The response was closed"))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
     }
 }
+
+fn scan_limit(limit: Option<usize>) -> usize {
+    limit.unwrap_or(DEFAULT_SCAN_LIMIT).min(MAX_SCAN_LIMIT)
+}
@@ -0,0 +1,372 @@
+use crate::{engines::WriteOp, models::Encoding, KVStoreError, KvsEngine, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    io::{BufRead, BufReader, Lines, Read, Write},
+    ops::Bound,
+    str::FromStr,
+};
+
+// Number of key-value pairs read from the engine at a time by an export.
+const EXPORT_PAGE_SIZE: usize = 256;
+// Number of key-value pairs written to the engine at a time by an import.
+const IMPORT_BATCH_SIZE: usize = 256;
+
+/// Start of the line that ends an export which failed midway, followed by the error, see
+/// `export_failed_line`.
+pub const EXPORT_FAILED: &str = "!export failed: ";
+
+/// Formats that key-value pairs can be imported from and exported to.
+///
+/// Both hold one pair per line (or per row), along with the encoding of the key and the value,
+/// which is `utf8` unless either of them isn't valid UTF-8, in which case it's `base64`:
+/// - `jsonl`: JSON Lines, e.g. `{"key":"abc","val":"xyz"}`, the encoding is only written out
+///   if it's `base64`.
+/// - `csv`: CSV with a `key,val,encoding` header. The encoding column can be left out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Jsonl,
+    Csv,
+}
+
+impl Default for Format {
+    fn default() -> Self {
+        Format::Jsonl
+    }
+}
+
+impl FromStr for Format {
+    type Err = KVStoreError;
+
+    /// Parses one of `jsonl` or `csv`.
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "jsonl" => Ok(Format::Jsonl),
+            "csv" => Ok(Format::Csv),
+            _ => Err(KVStoreError::InvalidFormat(s.to_string())),
+        }
+    }
+}
+
+// A key-value pair as it's written in a file.
+#[derive(Serialize, Deserialize)]
+struct Record {
+    key: String,
+    val: String,
+    #[serde(default, skip_serializing_if = "Encoding::is_utf8")]
+    encoding: Encoding,
+}
+
+impl Record {
+    fn encode(key: &[u8], val: &[u8]) -> Self {
+        let encoding = Encoding::Utf8.fit(vec![key, val]);
+        Record {
+            key: encoding.encode(key),
+            val: encoding.encode(val),
+            encoding,
+        }
+    }
+
+    fn decode(self) -> Result<(Vec<u8>, Vec<u8>)> {
+        Ok((
+            self.encoding.decode(&self.key)?,
+            self.encoding.decode(&self.val)?,
+        ))
+    }
+}
+
+/// Iterator over the key-value pairs in a file, read one at a time.
+pub struct PairReader<R: Read> {
+    records: Records<R>,
+}
+
+enum Records<R: Read> {
+    Jsonl {
+        lines: Lines<BufReader<R>>,
+        line: u64,
+    },
+    Csv(csv::DeserializeRecordsIntoIter<R, Record>),
+}
+
+impl<R: Read> PairReader<R> {
+    pub fn new(reader: R, format: Format) -> Self {
+        let records = match format {
+            Format::Jsonl => Records::Jsonl {
+                lines: BufReader::new(reader).lines(),
+                line: 0,
+            },
+            Format::Csv => Records::Csv(csv::Reader::from_reader(reader).into_deserialize()),
+        };
+        PairReader { records }
+    }
+}
+
+impl<R: Read> Iterator for PairReader<R> {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let record = match &mut self.records {
+            Records::Jsonl { lines, line } => loop {
+                *line += 1;
+                match lines.next()? {
+                    // Blank lines, e.g. at the end of the file, are skipped.
+                    Ok(text) if text.trim().is_empty() => continue,
+                    Ok(text) => {
+                        break serde_json::from_str::<Record>(&text)
+                            .map_err(|err| KVStoreError::InvalidRecord(*line, err.to_string()))
+                    }
+                    Err(err) => break Err(err.into()),
+                }
+            },
+            Records::Csv(records) => records.next()?.map_err(csv_error),
+        };
+        Some(record.and_then(Record::decode))
+    }
+}
+
+/// Writes key-value pairs to a file, one at a time.
+pub struct PairWriter<W: Write> {
+    records: RecordWriter<W>,
+}
+
+enum RecordWriter<W: Write> {
+    Jsonl(W),
+    Csv(Box<csv::Writer<W>>),
+}
+
+impl<W: Write> PairWriter<W> {
+    /// Starts writing to the writer, with the header first, if the format has one.
+    pub fn new(writer: W, format: Format) -> Result<Self> {
+        let records = match format {
+            Format::Jsonl => RecordWriter::Jsonl(writer),
+            Format::Csv => {
+                // The header is written out even if there are no pairs, so it's written
+                // separately from the records.
+                let mut writer = csv::WriterBuilder::new()
+                    .has_headers(false)
+                    .from_writer(writer);
+                writer
+                    .write_record(["key", "val", "encoding"])
+                    .map_err(csv_error)?;
+                RecordWriter::Csv(Box::new(writer))
+            }
+        };
+        Ok(PairWriter { records })
+    }
+
+    pub fn write(&mut self, key: &[u8], val: &[u8]) -> Result<()> {
+        let record = Record::encode(key, val);
+        match &mut self.records {
+            RecordWriter::Jsonl(writer) => {
+                serde_json::to_writer(&mut *writer, &record)?;
+                writer.write_all(b"\n")?;
+            }
+            RecordWriter::Csv(writer) => {
+                // Unlike JSON Lines, every row has all the columns.
+                let encoding = match record.encoding {
+                    Encoding::Utf8 => "utf8",
+                    Encoding::Base64 => "base64",
+                };
+                writer
+                    .write_record([record.key.as_str(), record.val.as_str(), encoding])
+                    .map_err(csv_error)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Flushes everything written so far.
+    pub fn flush(&mut self) -> Result<()> {
+        match &mut self.records {
+            RecordWriter::Jsonl(writer) => writer.flush()?,
+            RecordWriter::Csv(writer) => writer.flush()?,
+        }
+        Ok(())
+    }
+
+    /// Flushes everything written so far and returns the underlying writer.
+    pub fn into_inner(self) -> Result<W> {
+        match self.records {
+            RecordWriter::Jsonl(mut writer) => {
+                writer.flush()?;
+                Ok(writer)
+            }
+            RecordWriter::Csv(writer) => writer
+                .into_inner()
+                .map_err(|err| KVStoreError::Io(err.into_error())),
+        }
+    }
+}
+
+/// Stores every key-value pair read from the reader in the engine, overwriting the keys that
+/// already exist. Returns the number of pairs imported.
+///
+/// Pairs are stored in batches as they're read, so the file is never held in memory as a
+/// whole, but an import that fails midway, e.g. on a malformed record, leaves the pairs
+/// before it in place.
+pub fn import(engine: &dyn KvsEngine, reader: impl Read, format: Format) -> Result<u64> {
+    let mut imported = 0;
+    let mut batch = Vec::with_capacity(IMPORT_BATCH_SIZE);
+    for pair in PairReader::new(reader, format) {
+        let (key, val) = match pair {
+            Ok(pair) => pair,
+            Err(err) => {
+                write_batch(engine, &mut batch)?;
+                return Err(err);
+            }
+        };
+        batch.push(WriteOp::Set { key, val });
+        if batch.len() == IMPORT_BATCH_SIZE {
+            imported += write_batch(engine, &mut batch)?;
+        }
+    }
+    imported += write_batch(engine, &mut batch)?;
+    Ok(imported)
+}
+
+// Write the batch of pairs to the engine, leaving it empty, and return the number written.
+fn write_batch(engine: &dyn KvsEngine, batch: &mut Vec<WriteOp>) -> Result<u64> {
+    if batch.is_empty() {
+        return Ok(0);
+    }
+    let mut written = 0;
+    for result in engine.write_many(std::mem::take(batch))? {
+        result?;
+        written += 1;
+    }
+    Ok(written)
+}
+
+/// Writes every key-value pair in the engine to the writer, ordered by key.
+/// Returns the number of pairs exported.
+///
+/// The pairs are read a page at a time and the engine is not locked in between, so writes
+/// made during the export may or may not be seen.
+pub fn export(engine: &dyn KvsEngine, writer: impl Write, format: Format) -> Result<u64> {
+    let mut writer = PairWriter::new(writer, format)?;
+    let mut exported = 0;
+    let mut start = Bound::Unbounded;
+    loop {
+        let page = engine.scan_page((start, Bound::Unbounded), EXPORT_PAGE_SIZE)?;
+        for (key, val) in &page.items {
+            writer.write(key, val)?;
+            exported += 1;
+        }
+        match page.next {
+            Some(next) => start = Bound::Excluded(next),
+            None => break,
+        }
+    }
+    writer.flush()?;
+    Ok(exported)
+}
+
+/// Returns the line that ends an export which failed midway with the error, once the response
+/// can no longer be failed as a whole.
+///
+/// The line starts with `EXPORT_FAILED` and has no commas or quotes, so it can't be taken for
+/// a record, or the end of one, in either format, and the export can't be imported as it is.
+pub fn export_failed_line(err: &KVStoreError) -> String {
+    let message: String = err
+        .to_string()
+        .chars()
+        .map(|c| match c {
+            ',' | '"' | '\r' | '\n' => ' ',
+            c => c,
+        })
+        .collect();
+    // It starts on a line of its own, even if the export failed midway through a record.
+    format!("\n{}{}\n", EXPORT_FAILED, message)
+}
+
+// Errors while reading or writing a CSV file, other than IO errors, are due to malformed records.
+fn csv_error(err: csv::Error) -> KVStoreError {
+    let line = err.position().map(|pos| pos.line()).unwrap_or_default();
+    let message = err.to_string();
+    match err.into_kind() {
+        csv::ErrorKind::Io(err) => err.into(),
+        _ => KVStoreError::InvalidRecord(line, message),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::engines::MemoryEngine;
+
+    #[test]
+    fn test_import_export() {
+        for &format in &[Format::Jsonl, Format::Csv] {
+            let engine = MemoryEngine::new();
+            engine.set(b"a".to_vec(), b"1".to_vec()).unwrap();
+            engine
+                .set(b"b".to_vec(), b"with, \"quotes\"\nand lines".to_vec())
+                .unwrap();
+            engine.set(vec![0xff, 0x00], vec![0xde, 0xad]).unwrap();
+            for i in 0..EXPORT_PAGE_SIZE * 2 {
+                engine
+                    .set(format!("key {:04}", i).into_bytes(), b"val".to_vec())
+                    .unwrap();
+            }
+
+            let mut file = vec![];
+            let exported = export(&engine, &mut file, format).unwrap();
+            assert_eq!(exported, 3 + EXPORT_PAGE_SIZE as u64 * 2);
+
+            let copy = MemoryEngine::new();
+            assert_eq!(import(&copy, &file[..], format).unwrap(), exported);
+            let all = (Bound::Unbounded, Bound::Unbounded);
+            assert_eq!(copy.scan(all.clone()).unwrap(), engine.scan(all).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_export_failed() {
+        let err = KVStoreError::InvalidRecord(1, "a, \"b\"\nc".to_string());
+        let line = export_failed_line(&err);
+        assert_eq!(
+            line,
+            "\n!export failed: Invalid record on line 1: a   b  c\n"
+        );
+        for &format in &[Format::Jsonl, Format::Csv] {
+            let engine = MemoryEngine::new();
+            engine.set(b"a".to_vec(), b"1".to_vec()).unwrap();
+            let mut file = vec![];
+            export(&engine, &mut file, format).unwrap();
+            file.extend_from_slice(line.as_bytes());
+            assert!(matches!(
+                import(&MemoryEngine::new(), &file[..], format),
+                Err(KVStoreError::InvalidRecord(..))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_import_formats() {
+        let engine = MemoryEngine::new();
+        let jsonl = "{\"key\":\"a\",\"val\":\"1\"}\n\n{\"key\":\"/w==\",\"val\":\"3q0=\",\"encoding\":\"base64\"}\n";
+        assert_eq!(import(&engine, jsonl.as_bytes(), Format::Jsonl).unwrap(), 2);
+        assert_eq!(engine.get(vec![0xff]).unwrap(), Some(vec![0xde, 0xad]));
+
+        // The encoding column is optional.
+        let csv = "key,val\nb,2\nc,\"x,y\"\n";
+        assert_eq!(import(&engine, csv.as_bytes(), Format::Csv).unwrap(), 2);
+        assert_eq!(engine.get(b"c".to_vec()).unwrap(), Some(b"x,y".to_vec()));
+
+        // Malformed records fail the import, with the line they're on.
+        let jsonl = "{\"key\":\"d\",\"val\":\"4\"}\nnot json\n";
+        assert!(matches!(
+            import(&engine, jsonl.as_bytes(), Format::Jsonl),
+            Err(KVStoreError::InvalidRecord(2, _))
+        ));
+        assert_eq!(engine.get(b"d".to_vec()).unwrap(), Some(b"4".to_vec()));
+        let csv = "key,val\ne,5,extra\n";
+        assert!(matches!(
+            import(&engine, csv.as_bytes(), Format::Csv),
+            Err(KVStoreError::InvalidRecord(2, _))
+        ));
+        assert!(matches!(
+            "xml".parse::<Format>(),
+            Err(KVStoreError::InvalidFormat(_))
+        ));
+    }
+}
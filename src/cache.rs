@@ -0,0 +1,226 @@
+use crate::{KVStoreError, Result};
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeMap, HashMap},
+    hash::{Hash, Hasher},
+    sync::{Mutex, MutexGuard},
+};
+
+// Most number of shards a cache is split into.
+const MAX_SHARDS: u64 = 16;
+// Least capacity of a shard, so that small caches aren't split into shards too small to hold
+// any value.
+const MIN_SHARD_CAPACITY: u64 = 1024 * 1024;
+
+// A value in the cache, along with the tick at which it was last used.
+struct CacheEntry {
+    val: Vec<u8>,
+    used_at: u64,
+}
+
+/// A cache of the values of keys, bounded by the total size of the keys and values in it.
+/// Once the size crosses the capacity, the least recently used values are evicted.
+pub(crate) struct ValueCache {
+    capacity: u64,
+    size: u64,
+    // Incremented every time a value is used, to order the values by their last use.
+    tick: u64,
+    entries: HashMap<Vec<u8>, CacheEntry>,
+    // Keys ordered from the least to the most recently used one.
+    recency: BTreeMap<u64, Vec<u8>>,
+    hits: u64,
+    misses: u64,
+}
+
+impl ValueCache {
+    pub(crate) fn new(capacity: u64) -> Self {
+        ValueCache {
+            capacity,
+            size: 0,
+            tick: 0,
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    // Returns the cached value of the key, if there's one, marking it as the most recently used.
+    pub(crate) fn get(&mut self, key: &[u8]) -> Option<Vec<u8>> {
+        self.tick += 1;
+        let tick = self.tick;
+        match self.entries.get_mut(key) {
+            Some(entry) => {
+                self.hits += 1;
+                let key = self
+                    .recency
+                    .remove(&entry.used_at)
+                    .expect("Cached key is missing from the recency order");
+                self.recency.insert(tick, key);
+                entry.used_at = tick;
+                Some(entry.val.clone())
+            }
+            None => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    // Caches the value of the key, evicting the least recently used values to make room for it.
+    // Values which wouldn't fit even in an empty cache are not cached.
+    pub(crate) fn insert(&mut self, key: Vec<u8>, val: Vec<u8>) {
+        self.remove(&key);
+        let size = entry_size(&key, &val);
+        if size > self.capacity {
+            return;
+        }
+        while self.size + size > self.capacity {
+            let oldest = match self.recency.keys().next() {
+                Some(&oldest) => oldest,
+                None => break,
+            };
+            if let Some(key) = self.recency.remove(&oldest) {
+                self.remove(&key);
+            }
+        }
+        self.tick += 1;
+        self.size += size;
+        self.recency.insert(self.tick, key.clone());
+        self.entries.insert(
+            key,
+            CacheEntry {
+                val,
+                used_at: self.tick,
+            },
+        );
+    }
+
+    // Evicts the value of the key, if it's cached.
+    pub(crate) fn remove(&mut self, key: &[u8]) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.recency.remove(&entry.used_at);
+            self.size -= entry_size(key, &entry.val);
+        }
+    }
+
+    // Returns the number of hits and misses so far, along with the size of the cached values.
+    pub(crate) fn stats(&self) -> (u64, u64, u64) {
+        (self.hits, self.misses, self.size)
+    }
+}
+
+/// A value cache split into shards, each with its own lock and an even share of the capacity,
+/// so that reads of different keys don't all contend on the same lock. Keys are assigned to
+/// shards by their hash, and the least recently used values are evicted from each shard.
+pub(crate) struct ShardedCache {
+    shards: Vec<Mutex<ValueCache>>,
+}
+
+impl ShardedCache {
+    pub(crate) fn new(capacity: u64) -> Self {
+        let shards = (capacity / MIN_SHARD_CAPACITY).clamp(1, MAX_SHARDS);
+        ShardedCache {
+            shards: (0..shards)
+                .map(|_| Mutex::new(ValueCache::new(capacity / shards)))
+                .collect(),
+        }
+    }
+
+    pub(crate) fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.shard(key)?.get(key))
+    }
+
+    pub(crate) fn insert(&self, key: Vec<u8>, val: Vec<u8>) -> Result<()> {
+        self.shard(&key)?.insert(key, val);
+        Ok(())
+    }
+
+    pub(crate) fn remove(&self, key: &[u8]) -> Result<()> {
+        self.shard(key)?.remove(key);
+        Ok(())
+    }
+
+    // Returns the number of hits and misses so far, along with the size of the cached values,
+    // across all the shards.
+    pub(crate) fn stats(&self) -> Result<(u64, u64, u64)> {
+        let mut stats = (0, 0, 0);
+        for shard in &self.shards {
+            let (hits, misses, size) = shard.lock().map_err(|_| KVStoreError::Lock)?.stats();
+            stats = (stats.0 + hits, stats.1 + misses, stats.2 + size);
+        }
+        Ok(stats)
+    }
+
+    // Lock the shard the key belongs to.
+    fn shard(&self, key: &[u8]) -> Result<MutexGuard<'_, ValueCache>> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let shard = &self.shards[(hasher.finish() % self.shards.len() as u64) as usize];
+        shard.lock().map_err(|_| KVStoreError::Lock)
+    }
+}
+
+// Size taken up by a key and it's value in the cache.
+fn entry_size(key: &[u8], val: &[u8]) -> u64 {
+    (key.len() + val.len()) as u64
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_value_cache() {
+        let mut cache = ValueCache::new(30);
+        cache.insert(b"a".to_vec(), vec![0; 9]);
+        cache.insert(b"b".to_vec(), vec![1; 9]);
+        cache.insert(b"c".to_vec(), vec![2; 9]);
+        assert_eq!(cache.stats(), (0, 0, 30));
+
+        // Using a value makes it the most recently used one, so another one is evicted.
+        assert_eq!(cache.get(b"a"), Some(vec![0; 9]));
+        cache.insert(b"d".to_vec(), vec![3; 9]);
+        assert_eq!(cache.get(b"b"), None);
+        assert_eq!(cache.get(b"a"), Some(vec![0; 9]));
+        assert_eq!(cache.get(b"c"), Some(vec![2; 9]));
+        assert_eq!(cache.stats(), (3, 1, 30));
+
+        // Overwriting a value takes its size into account.
+        cache.insert(b"c".to_vec(), vec![2; 4]);
+        assert_eq!(cache.stats().2, 25);
+        cache.remove(b"a");
+        assert_eq!(cache.get(b"a"), None);
+        assert_eq!(cache.stats().2, 15);
+
+        // Values bigger than the whole cache are never cached.
+        cache.insert(b"e".to_vec(), vec![4; 30]);
+        assert_eq!(cache.get(b"e"), None);
+        assert_eq!(cache.get(b"d"), Some(vec![3; 9]));
+    }
+
+    #[test]
+    fn test_sharded_cache() {
+        // Small caches aren't split, so they can hold values as big as the whole cache.
+        let cache = ShardedCache::new(30);
+        assert_eq!(cache.shards.len(), 1);
+        cache.insert(b"a".to_vec(), vec![0; 29]).unwrap();
+        assert_eq!(cache.get(b"a").unwrap(), Some(vec![0; 29]));
+
+        let cache = ShardedCache::new(64 * MIN_SHARD_CAPACITY);
+        assert_eq!(cache.shards.len() as u64, MAX_SHARDS);
+        for i in 0..100u32 {
+            cache.insert(i.to_le_bytes().to_vec(), vec![1; 10]).unwrap();
+        }
+        cache.remove(&0u32.to_le_bytes()).unwrap();
+        assert_eq!(cache.get(&0u32.to_le_bytes()).unwrap(), None);
+        assert_eq!(cache.get(&1u32.to_le_bytes()).unwrap(), Some(vec![1; 10]));
+        // Keys end up in more than one shard, and the stats add up across them.
+        let used = cache
+            .shards
+            .iter()
+            .filter(|shard| shard.lock().unwrap().size > 0)
+            .count();
+        assert!(used > 1);
+        assert_eq!(cache.stats().unwrap(), (1, 1, 99 * 14));
+    }
+}
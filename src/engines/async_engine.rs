@@ -0,0 +1,179 @@
+use super::{EngineStats, KeyRange, KvsEngine, Page, SnapshotInfo, WriteOp};
+use crate::{
+    bulk::{self, Format},
+    Result,
+};
+use std::{
+    io::{Read, Write},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+use tokio::task;
+
+/// An async facade over an engine, for use from async code such as the server's handlers.
+///
+/// Engines block on file I/O, so every call is run on tokio's blocking thread pool, leaving
+/// the async worker threads free to make progress on other requests even when the disk is slow.
+/// The semantics of all the methods are the same as that of their counterparts on KvsEngine.
+#[derive(Clone)]
+pub struct AsyncEngine {
+    engine: Arc<dyn KvsEngine>,
+}
+
+impl AsyncEngine {
+    pub fn new(engine: Arc<dyn KvsEngine>) -> Self {
+        AsyncEngine { engine }
+    }
+
+    pub async fn get(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        self.run(move |engine| engine.get(key)).await
+    }
+
+    pub async fn set(&self, key: Vec<u8>, val: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        self.run(move |engine| engine.set(key, val)).await
+    }
+
+    pub async fn set_with_ttl(
+        &self,
+        key: Vec<u8>,
+        val: Vec<u8>,
+        ttl: Duration,
+    ) -> Result<Option<Vec<u8>>> {
+        self.run(move |engine| engine.set_with_ttl(key, val, ttl))
+            .await
+    }
+
+    pub async fn ttl(&self, key: Vec<u8>) -> Result<Option<Duration>> {
+        self.run(move |engine| engine.ttl(key)).await
+    }
+
+    pub async fn get_with_version(&self, key: Vec<u8>) -> Result<(Vec<u8>, u64)> {
+        self.run(move |engine| engine.get_with_version(key)).await
+    }
+
+    pub async fn set_if_absent(&self, key: Vec<u8>, val: Vec<u8>) -> Result<u64> {
+        self.run(move |engine| engine.set_if_absent(key, val)).await
+    }
+
+    pub async fn set_if_version(&self, key: Vec<u8>, val: Vec<u8>, version: u64) -> Result<u64> {
+        self.run(move |engine| engine.set_if_version(key, val, version))
+            .await
+    }
+
+    pub async fn rm_if_version(&self, key: Vec<u8>, version: u64) -> Result<Option<Vec<u8>>> {
+        self.run(move |engine| engine.rm_if_version(key, version))
+            .await
+    }
+
+    pub async fn rm(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        self.run(move |engine| engine.rm(key)).await
+    }
+
+    pub async fn incr(&self, key: Vec<u8>, delta: i64) -> Result<i64> {
+        self.run(move |engine| engine.incr(key, delta)).await
+    }
+
+    pub async fn commit_txn(&self, ops: Vec<WriteOp>) -> Result<Vec<Option<Vec<u8>>>> {
+        self.run(move |engine| engine.commit_txn(ops)).await
+    }
+
+    pub async fn get_many(&self, keys: Vec<Vec<u8>>) -> Result<Vec<Option<Vec<u8>>>> {
+        self.run(move |engine| engine.get_many(keys)).await
+    }
+
+    pub async fn write_many(&self, ops: Vec<WriteOp>) -> Result<Vec<Result<Option<Vec<u8>>>>> {
+        self.run(move |engine| engine.write_many(ops)).await
+    }
+
+    pub async fn scan(&self, range: KeyRange) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.run(move |engine| engine.scan(range)).await
+    }
+
+    pub async fn scan_page(
+        &self,
+        range: KeyRange,
+        limit: usize,
+    ) -> Result<Page<(Vec<u8>, Vec<u8>)>> {
+        self.run(move |engine| engine.scan_page(range, limit)).await
+    }
+
+    pub async fn keys_page(&self, range: KeyRange, limit: usize) -> Result<Page<Vec<u8>>> {
+        self.run(move |engine| engine.keys_page(range, limit)).await
+    }
+
+    pub async fn stats(&self) -> Result<EngineStats> {
+        self.run(|engine| engine.stats()).await
+    }
+
+    pub async fn compact(&self) -> Result<u64> {
+        self.run(|engine| engine.compact()).await
+    }
+
+    pub async fn reap_expired(&self) -> Result<usize> {
+        self.run(|engine| engine.reap_expired()).await
+    }
+
+    pub async fn snapshot(&self, dest: PathBuf) -> Result<SnapshotInfo> {
+        self.run(move |engine| engine.snapshot(dest)).await
+    }
+
+    pub async fn import(&self, reader: impl Read + Send + 'static, format: Format) -> Result<u64> {
+        self.run(move |engine| bulk::import(engine, reader, format))
+            .await
+    }
+
+    pub async fn export(&self, writer: impl Write + Send + 'static, format: Format) -> Result<u64> {
+        self.run(move |engine| bulk::export(engine, writer, format))
+            .await
+    }
+
+    // Run the call to the engine on the blocking thread pool and wait for its result.
+    async fn run<T, F>(&self, call: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&dyn KvsEngine) -> Result<T> + Send + 'static,
+    {
+        let engine = Arc::clone(&self.engine);
+        task::spawn_blocking(move || call(engine.as_ref())).await?
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{engines::MemoryEngine, KVStoreError};
+
+    #[tokio::test]
+    async fn test_async_engine() {
+        let engine = AsyncEngine::new(Arc::new(MemoryEngine::new()));
+        let key = b"key".to_vec();
+        assert_eq!(
+            engine.set(key.clone(), b"val".to_vec()).await.unwrap(),
+            None
+        );
+        assert_eq!(
+            engine.get(key.clone()).await.unwrap(),
+            Some(b"val".to_vec())
+        );
+        assert_eq!(engine.incr(b"counter".to_vec(), 2).await.unwrap(), 2);
+        assert_eq!(engine.stats().await.unwrap().live_keys, 2);
+        assert_eq!(engine.rm(key.clone()).await.unwrap(), Some(b"val".to_vec()));
+        assert!(matches!(
+            engine.get(key).await,
+            Err(KVStoreError::KeyNotFound(_))
+        ));
+
+        // Concurrent calls all make progress on the blocking pool.
+        let calls: Vec<_> = (0..16)
+            .map(|_| {
+                let engine = engine.clone();
+                tokio::spawn(async move { engine.incr(b"counter".to_vec(), 1).await })
+            })
+            .collect();
+        for call in calls {
+            call.await.unwrap().unwrap();
+        }
+        assert_eq!(engine.incr(b"counter".to_vec(), 0).await.unwrap(), 18);
+    }
+}
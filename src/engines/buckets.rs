@@ -0,0 +1,549 @@
+use super::{open_engine, EngineKind, KvsEngine};
+use crate::{
+    watch::{Change, ChangeSink, Changes},
+    KVStore, KVStoreConfig, KVStoreError, Result,
+};
+use log::error;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::{self, File},
+    ops::Bound,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
+    thread,
+    time::Duration,
+};
+
+// Maximum length of the name of a bucket.
+const MAX_BUCKET_NAME_LEN: usize = 64;
+
+// File marking the directory of a dropped bucket, which is deleted once the engine backing the
+// bucket is closed.
+const DROPPED_FILE: &str = "DROPPED";
+
+// Interval at which it's checked whether the engine of a dropped bucket is still in use.
+const DROPPED_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+// Number of pairs of a restored bucket read at a time to publish them as changes.
+const RESTORE_PAGE_SIZE: usize = 256;
+
+/// A set of named buckets, each with a keyspace of its own, along with the default bucket.
+///
+/// Every bucket is backed by an engine of its own, so it has its own index and is compacted
+/// separately. The default bucket is stored in the directory itself, while every named
+/// bucket is stored in a directory named after it, within the `buckets` directory. A bucket
+/// created again while the engine of the dropped one is still in use gets a directory of its
+/// own, named after it along with a number, e.g. `users.1`.
+pub struct Buckets {
+    path: PathBuf,
+    kind: EngineKind,
+    config: KVStoreConfig,
+    default: Arc<dyn KvsEngine>,
+    buckets: RwLock<BTreeMap<String, NamedBucket>>,
+    // Names of the buckets being restored, which are taken until the restore is done. It's
+    // only locked while holding the lock on the buckets.
+    restoring: Mutex<BTreeSet<String>>,
+    // Where the changes made in every bucket are published, if they're watched.
+    changes: Option<Arc<Changes>>,
+}
+
+// A named bucket: the engine backing it and the directory it's stored in.
+struct NamedBucket {
+    engine: Arc<dyn KvsEngine>,
+    path: PathBuf,
+}
+
+impl Buckets {
+    /// Opens the default bucket in the directory, along with all the named buckets in it,
+    /// using engines of the given kind.
+    pub fn open(kind: EngineKind, path: impl Into<PathBuf>, config: KVStoreConfig) -> Result<Self> {
+        Buckets::open_with_changes(kind, path, config, None)
+    }
+
+    /// Opens the buckets like `open`, publishing the changes made in every bucket from then
+    /// on, including the ones created later, to `changes`.
+    pub fn open_with_changes(
+        kind: EngineKind,
+        path: impl Into<PathBuf>,
+        config: KVStoreConfig,
+        changes: Option<Arc<Changes>>,
+    ) -> Result<Self> {
+        let path = path.into();
+        let default = open_engine(kind, path.clone(), config.clone())?;
+        watch(&changes, None, default.as_ref())?;
+        // A legacy log only ever held the keys of the default bucket.
+        let config = KVStoreConfig {
+            legacy_log: None,
+            ..config
+        };
+        let mut buckets = BTreeMap::new();
+        // Nothing is persisted by in-memory engines, so there are no buckets to open.
+        let dir = buckets_dir(&path);
+        if kind != EngineKind::Memory && dir.is_dir() {
+            for entry in fs::read_dir(&dir)? {
+                let path = entry?.path();
+                let name = match path.file_name().and_then(|name| name.to_str()) {
+                    Some(name) if path.is_dir() => match bucket_name(name) {
+                        Some(name) => name.to_string(),
+                        None => continue,
+                    },
+                    _ => continue,
+                };
+                // Left behind by a bucket dropped before the process went down.
+                if path.join(DROPPED_FILE).exists() {
+                    fs::remove_dir_all(&path)?;
+                    continue;
+                }
+                // Only one directory is in use for a bucket at a time.
+                if buckets.contains_key(&name) {
+                    return Err(KVStoreError::Corrupt);
+                }
+                let engine = open_engine(kind, path.clone(), config.clone())?;
+                watch(&changes, Some(&name), engine.as_ref())?;
+                buckets.insert(name, NamedBucket { engine, path });
+            }
+        }
+        Ok(Buckets {
+            path,
+            kind,
+            config,
+            default,
+            buckets: RwLock::new(buckets),
+            restoring: Mutex::new(BTreeSet::new()),
+            changes,
+        })
+    }
+
+    /// Returns the default bucket.
+    pub fn default_bucket(&self) -> Arc<dyn KvsEngine> {
+        self.default.clone()
+    }
+
+    /// Returns the bucket with the given name. If not found, returns a BucketNotFound error.
+    pub fn bucket(&self, name: &str) -> Result<Arc<dyn KvsEngine>> {
+        let buckets = self.buckets.read().map_err(|_| KVStoreError::Lock)?;
+        buckets
+            .get(name)
+            .map(|bucket| bucket.engine.clone())
+            .ok_or_else(|| KVStoreError::BucketNotFound(name.to_string()))
+    }
+
+    /// Creates an empty bucket with the given name and returns it. Names are made up of ASCII
+    /// letters, digits, `-` and `_`. If the bucket already exists, returns a BucketExists error.
+    pub fn create_bucket(&self, name: &str) -> Result<Arc<dyn KvsEngine>> {
+        if !is_valid_name(name) {
+            return Err(KVStoreError::InvalidBucket(name.to_string()));
+        }
+        let mut buckets = self.buckets.write().map_err(|_| KVStoreError::Lock)?;
+        if buckets.contains_key(name) || self.is_restoring(name)? {
+            return Err(KVStoreError::BucketExists(name.to_string()));
+        }
+        let path = self.new_bucket_dir(name);
+        let engine = open_engine(self.kind, path.clone(), self.config.clone())?;
+        watch(&self.changes, Some(name), engine.as_ref())?;
+        let bucket = NamedBucket {
+            engine: engine.clone(),
+            path,
+        };
+        buckets.insert(name.to_string(), bucket);
+        Ok(engine)
+    }
+
+    /// Creates a bucket with the given name out of the snapshot in the directory, written by
+    /// `KvsEngine::snapshot`, and returns it. The snapshot itself is left untouched.
+    /// Every pair restored is published as a change, before the bucket can be written to.
+    /// Only buckets backed by KVStore can be restored.
+    ///
+    /// The snapshot is copied and its pairs are published without holding up the other
+    /// buckets, the name is taken in the meantime, so the bucket can't be created elsewhere.
+    pub fn restore_bucket(&self, name: &str, snapshot: &Path) -> Result<Arc<dyn KvsEngine>> {
+        if self.kind != EngineKind::Kvs {
+            return Err(KVStoreError::Unsupported(String::from("snapshots")));
+        }
+        if !is_valid_name(name) {
+            return Err(KVStoreError::InvalidBucket(name.to_string()));
+        }
+        let path = {
+            let buckets = self.buckets.write().map_err(|_| KVStoreError::Lock)?;
+            let mut restoring = self.restoring.lock().map_err(|_| KVStoreError::Lock)?;
+            if buckets.contains_key(name) || !restoring.insert(name.to_string()) {
+                return Err(KVStoreError::BucketExists(name.to_string()));
+            }
+            self.new_bucket_dir(name)
+        };
+        let restored = self.restore_engine(name, snapshot, &path);
+        let mut buckets = self.buckets.write().map_err(|_| KVStoreError::Lock)?;
+        self.restoring
+            .lock()
+            .map_err(|_| KVStoreError::Lock)?
+            .remove(name);
+        let engine = restored?;
+        let bucket = NamedBucket {
+            engine: engine.clone(),
+            path,
+        };
+        buckets.insert(name.to_string(), bucket);
+        Ok(engine)
+    }
+
+    // Restore the snapshot into the directory of a bucket and publish every pair in it.
+    fn restore_engine(
+        &self,
+        name: &str,
+        snapshot: &Path,
+        path: &Path,
+    ) -> Result<Arc<dyn KvsEngine>> {
+        let engine: Arc<dyn KvsEngine> = Arc::new(KVStore::restore(
+            snapshot,
+            path.to_path_buf(),
+            self.config.clone(),
+        )?);
+        if let Some(changes) = &self.changes {
+            let sink = ChangeSink::new(Arc::clone(changes), Some(name.to_string()));
+            publish_pairs(engine.as_ref(), &sink)?;
+            engine.watch(sink)?;
+        }
+        Ok(engine)
+    }
+
+    // Whether a bucket with the name is being restored. Callers hold the lock on the buckets.
+    fn is_restoring(&self, name: &str) -> Result<bool> {
+        let restoring = self.restoring.lock().map_err(|_| KVStoreError::Lock)?;
+        Ok(restoring.contains(name))
+    }
+
+    /// Drops the bucket with the given name, along with all the key-value pairs in it.
+    /// If not found, returns a BucketNotFound error.
+    ///
+    /// Requests which got hold of the bucket before it was dropped may still be using its
+    /// engine, which keeps writing to its directory, so the directory is only marked as
+    /// dropped, freeing up the name right away, and deleted once the engine has been closed.
+    pub fn drop_bucket(&self, name: &str) -> Result<()> {
+        let mut buckets = self.buckets.write().map_err(|_| KVStoreError::Lock)?;
+        let bucket = buckets
+            .remove(name)
+            .ok_or_else(|| KVStoreError::BucketNotFound(name.to_string()))?;
+        if !bucket.path.is_dir() {
+            return Ok(());
+        }
+        if let Err(err) = File::create(bucket.path.join(DROPPED_FILE)).and_then(|f| f.sync_all()) {
+            buckets.insert(name.to_string(), bucket);
+            return Err(err.into());
+        }
+        remove_once_closed(bucket.engine, bucket.path);
+        Ok(())
+    }
+
+    /// Returns the names of all the buckets, apart from the default one, in order.
+    pub fn list_buckets(&self) -> Result<Vec<String>> {
+        let buckets = self.buckets.read().map_err(|_| KVStoreError::Lock)?;
+        Ok(buckets.keys().cloned().collect())
+    }
+
+    // Directory for a new bucket with the given name. The directories of dropped buckets with
+    // the same name may still be in use, so they're never reused.
+    fn new_bucket_dir(&self, name: &str) -> PathBuf {
+        let dir = buckets_dir(&self.path);
+        let mut path = dir.join(name);
+        let mut n = 0;
+        while path.exists() {
+            n += 1;
+            path = dir.join(format!("{}.{}", name, n));
+        }
+        path
+    }
+}
+
+// Send the changes made to the keys of the engine backing the bucket to `changes`, if given.
+fn watch(
+    changes: &Option<Arc<Changes>>,
+    bucket: Option<&str>,
+    engine: &dyn KvsEngine,
+) -> Result<()> {
+    match changes {
+        Some(changes) => engine.watch(ChangeSink::new(
+            Arc::clone(changes),
+            bucket.map(String::from),
+        )),
+        None => Ok(()),
+    }
+}
+
+// Publish every pair in the engine as a set to the sink, a page at a time.
+fn publish_pairs(engine: &dyn KvsEngine, sink: &ChangeSink) -> Result<()> {
+    let mut start = Bound::Unbounded;
+    loop {
+        let page = engine.scan_page((start, Bound::Unbounded), RESTORE_PAGE_SIZE)?;
+        for (key, val) in page.items {
+            sink.publish(Change::Set { key, val });
+        }
+        match page.next {
+            Some(next) => start = Bound::Excluded(next),
+            None => return Ok(()),
+        }
+    }
+}
+
+// Delete the directory of a dropped bucket once its engine has been closed, i.e. once the
+// requests which were still using it are done with it.
+fn remove_once_closed(engine: Arc<dyn KvsEngine>, path: PathBuf) {
+    let engine = Arc::downgrade(&engine);
+    thread::spawn(move || {
+        while engine.strong_count() > 0 {
+            thread::sleep(DROPPED_CHECK_INTERVAL);
+        }
+        if let Err(err) = fs::remove_dir_all(&path) {
+            error!(
+                "Could not delete dropped bucket {}: {}",
+                path.display(),
+                err
+            );
+        }
+    });
+}
+
+// Directory containing the directories of the named buckets.
+fn buckets_dir(path: &Path) -> PathBuf {
+    path.join("buckets")
+}
+
+// Name of the bucket stored in the directory with the given name, i.e. the name itself or the
+// name followed by a dot and a number. Bucket names can't contain dots, so there's no mixing
+// them up.
+fn bucket_name(dir_name: &str) -> Option<&str> {
+    let name = match dir_name.split_once('.') {
+        Some((name, n)) => {
+            n.parse::<u64>().ok()?;
+            name
+        }
+        None => dir_name,
+    };
+    Some(name).filter(|name| is_valid_name(name))
+}
+
+// Whether the name can be used for a bucket. Since names are used for directories, URLs and
+// pub/sub subjects, they're kept to characters which are safe in all of them.
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_BUCKET_NAME_LEN
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::watch::{EventId, DEFAULT_HISTORY_SIZE};
+    use rand::Rng;
+
+    #[test]
+    fn test_buckets() {
+        for &kind in &[EngineKind::Kvs, EngineKind::Sled, EngineKind::Memory] {
+            let n: u32 = rand::thread_rng().gen();
+            let dir = format!("/tmp/kvs-buckets-{}", n);
+            let buckets = Buckets::open(kind, dir.clone(), KVStoreConfig::default()).unwrap();
+            assert!(buckets.list_buckets().unwrap().is_empty());
+
+            // Every bucket has a keyspace of its own.
+            let users = buckets.create_bucket("users").unwrap();
+            let orders = buckets.create_bucket("orders").unwrap();
+            let default = buckets.default_bucket();
+            users.set(b"key".to_vec(), b"user".to_vec()).unwrap();
+            orders.set(b"key".to_vec(), b"order".to_vec()).unwrap();
+            assert_eq!(users.get(b"key".to_vec()).unwrap(), Some(b"user".to_vec()));
+            assert_eq!(
+                orders.get(b"key".to_vec()).unwrap(),
+                Some(b"order".to_vec())
+            );
+            assert!(matches!(
+                default.get(b"key".to_vec()),
+                Err(KVStoreError::KeyNotFound(_))
+            ));
+            assert_eq!(buckets.list_buckets().unwrap(), vec!["orders", "users"]);
+
+            assert!(matches!(
+                buckets.create_bucket("users"),
+                Err(KVStoreError::BucketExists(_))
+            ));
+            for name in &["", "a/b", "..", "a.b"] {
+                assert!(matches!(
+                    buckets.create_bucket(name),
+                    Err(KVStoreError::InvalidBucket(_))
+                ));
+            }
+
+            buckets.drop_bucket("orders").unwrap();
+            assert!(matches!(
+                buckets.bucket("orders"),
+                Err(KVStoreError::BucketNotFound(_))
+            ));
+            assert!(matches!(
+                buckets.drop_bucket("orders"),
+                Err(KVStoreError::BucketNotFound(_))
+            ));
+            // The engine of a dropped bucket keeps working for whoever still holds it, while
+            // a bucket with the same name starts out empty.
+            assert_eq!(
+                orders.get(b"key".to_vec()).unwrap(),
+                Some(b"order".to_vec())
+            );
+            let recreated = buckets.create_bucket("orders").unwrap();
+            assert!(matches!(
+                recreated.get(b"key".to_vec()),
+                Err(KVStoreError::KeyNotFound(_))
+            ));
+            buckets.drop_bucket("orders").unwrap();
+            drop((users, orders, recreated, default, buckets));
+
+            // Dropped buckets are deleted once their engines are closed.
+            let dropped = || match fs::read_dir(buckets_dir(Path::new(&dir))) {
+                Ok(entries) => entries
+                    .filter(|entry| entry.as_ref().unwrap().file_name() != "users")
+                    .count(),
+                Err(_) => 0,
+            };
+            for _ in 0..50 {
+                if dropped() == 0 {
+                    break;
+                }
+                thread::sleep(DROPPED_CHECK_INTERVAL);
+            }
+            assert_eq!(dropped(), 0);
+
+            // Buckets are found again once reopened, unless nothing is persisted.
+            let buckets = Buckets::open(kind, dir.clone(), KVStoreConfig::default()).unwrap();
+            if kind == EngineKind::Memory {
+                assert!(buckets.list_buckets().unwrap().is_empty());
+            } else {
+                assert_eq!(buckets.list_buckets().unwrap(), vec!["users"]);
+                let users = buckets.bucket("users").unwrap();
+                assert_eq!(users.get(b"key".to_vec()).unwrap(), Some(b"user".to_vec()));
+            }
+            drop(buckets);
+            let _ = fs::remove_dir_all(dir);
+        }
+    }
+
+    #[test]
+    fn test_drop_bucket_in_use() {
+        let n: u32 = rand::thread_rng().gen();
+        let dir = format!("/tmp/kvs-buckets-dropped-{}", n);
+        // Small segments, so that writes roll over to new ones.
+        let config = KVStoreConfig {
+            segment_size: 256,
+            ..KVStoreConfig::default()
+        };
+        let buckets = Buckets::open(EngineKind::Kvs, dir.clone(), config.clone()).unwrap();
+        let dropped = buckets.create_bucket("users").unwrap();
+        dropped.set(b"key".to_vec(), b"old".to_vec()).unwrap();
+        buckets.drop_bucket("users").unwrap();
+        let recreated = buckets.create_bucket("users").unwrap();
+        recreated.set(b"key".to_vec(), b"new".to_vec()).unwrap();
+
+        // The engine of the dropped bucket keeps writing to its own directory, past rolls and
+        // compactions, leaving the bucket created in its place alone.
+        for i in 0..100 {
+            dropped
+                .set(format!("key {}", i).into_bytes(), vec![b'x'; 32])
+                .unwrap();
+        }
+        dropped.compact().unwrap();
+        assert_eq!(
+            recreated.get(b"key".to_vec()).unwrap(),
+            Some(b"new".to_vec())
+        );
+        drop((dropped, recreated, buckets));
+
+        let buckets = Buckets::open(EngineKind::Kvs, dir.clone(), config).unwrap();
+        let users = buckets.bucket("users").unwrap();
+        assert_eq!(users.get(b"key".to_vec()).unwrap(), Some(b"new".to_vec()));
+        assert!(matches!(
+            users.get(b"key 0".to_vec()),
+            Err(KVStoreError::KeyNotFound(_))
+        ));
+        drop((users, buckets));
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_restore_bucket() {
+        let n: u32 = rand::thread_rng().gen();
+        let dir = format!("/tmp/kvs-buckets-restore-{}", n);
+        let snapshot = format!("{}-snapshot", dir);
+        let changes = Arc::new(Changes::new(DEFAULT_HISTORY_SIZE));
+        let buckets = Buckets::open_with_changes(
+            EngineKind::Kvs,
+            dir.clone(),
+            KVStoreConfig::default(),
+            Some(Arc::clone(&changes)),
+        )
+        .unwrap();
+        let default = buckets.default_bucket();
+        default.set(b"key".to_vec(), b"val".to_vec()).unwrap();
+        let info = default.snapshot(PathBuf::from(&snapshot)).unwrap();
+        assert_eq!(info.keys, 1);
+
+        let restored = buckets
+            .restore_bucket("restored", Path::new(&snapshot))
+            .unwrap();
+        assert_eq!(
+            restored.get(b"key".to_vec()).unwrap(),
+            Some(b"val".to_vec())
+        );
+        assert_eq!(buckets.list_buckets().unwrap(), vec!["restored"]);
+        // Restored pairs are published as sets in the restored bucket, along with the changes
+        // made to it afterwards.
+        restored.rm(b"key".to_vec()).unwrap();
+        let first = EventId {
+            epoch: changes.epoch(),
+            seq: 0,
+        };
+        let (events, _) = changes.subscribe(Some(first)).unwrap();
+        let events: Vec<(Option<&str>, &Change)> = events
+            .iter()
+            .map(|event| (event.bucket.as_deref(), &event.change))
+            .collect();
+        let set = Change::Set {
+            key: b"key".to_vec(),
+            val: b"val".to_vec(),
+        };
+        let rm = Change::Rm {
+            key: b"key".to_vec(),
+        };
+        assert_eq!(
+            events,
+            vec![
+                (None, &set),
+                (Some("restored"), &set),
+                (Some("restored"), &rm)
+            ]
+        );
+        assert!(matches!(
+            buckets.restore_bucket("restored", Path::new(&snapshot)),
+            Err(KVStoreError::BucketExists(_))
+        ));
+        // The name of a bucket is taken while it's being restored, and freed if that fails.
+        buckets
+            .restoring
+            .lock()
+            .unwrap()
+            .insert(String::from("pending"));
+        assert!(matches!(
+            buckets.create_bucket("pending"),
+            Err(KVStoreError::BucketExists(_))
+        ));
+        assert!(matches!(
+            buckets.restore_bucket("pending", Path::new(&snapshot)),
+            Err(KVStoreError::BucketExists(_))
+        ));
+        assert!(matches!(
+            buckets.restore_bucket("broken", Path::new(&dir).join("missing").as_path()),
+            Err(KVStoreError::InvalidSnapshot(_))
+        ));
+        buckets.create_bucket("broken").unwrap();
+        drop((default, restored, buckets));
+        let _ = fs::remove_dir_all(dir);
+        let _ = fs::remove_dir_all(snapshot);
+    }
+}
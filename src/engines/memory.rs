@@ -0,0 +1,341 @@
+use super::{apply_delta, is_empty_range, EngineStats, KeyRange, KvsEngine, Page, WriteOp};
+use crate::{
+    error::display_key,
+    record::FIRST_VERSION,
+    store::now_millis,
+    watch::{Change, ChangeSink},
+    KVStoreError, Result,
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::RwLock,
+    time::Duration,
+};
+
+// A value along with the time at which it expires, if it does, and the version of the key.
+#[derive(Debug)]
+struct Entry {
+    val: Vec<u8>,
+    expires_at: Option<u64>,
+    version: u64,
+}
+
+impl Entry {
+    fn is_expired(&self, now: u64) -> bool {
+        matches!(self.expires_at, Some(expires_at) if expires_at <= now)
+    }
+}
+
+/// An engine which keeps all the key-value pairs in memory. Nothing is persisted,
+/// which makes it useful for tests.
+#[derive(Debug, Default)]
+pub struct MemoryEngine {
+    map: RwLock<BTreeMap<Vec<u8>, Entry>>,
+    // Where the changes to the keys are sent as they're made, if they're watched.
+    sink: RwLock<Option<ChangeSink>>,
+}
+
+impl MemoryEngine {
+    pub fn new() -> Self {
+        MemoryEngine::default()
+    }
+
+    // Store the key and it's value, expiring it at the given time, if any. The check is passed
+    // the current version of the key, if it exists, and the key is only stored if it passes.
+    // Returns the old value and the new version of the key.
+    fn insert<F>(
+        &self,
+        key: Vec<u8>,
+        val: Vec<u8>,
+        expires_at: Option<u64>,
+        check: F,
+    ) -> Result<(Option<Vec<u8>>, u64)>
+    where
+        F: FnOnce(&[u8], Option<u64>) -> Result<()>,
+    {
+        let mut map = self.map.write().map_err(|_| KVStoreError::Lock)?;
+        let now = now_millis();
+        let current_version = map
+            .get(&key)
+            .filter(|entry| !entry.is_expired(now))
+            .map(|entry| entry.version);
+        check(&key, current_version)?;
+        self.publish(|| Change::Set {
+            key: key.clone(),
+            val: val.clone(),
+        })?;
+        Ok(put(&mut map, key, val, expires_at, now))
+    }
+
+    // Send the change to the sink, if the keys are watched. Callers hold the lock on the map,
+    // so the changes are sent in the order they're made.
+    fn publish(&self, change: impl FnOnce() -> Change) -> Result<()> {
+        if let Some(sink) = &*self.sink.read().map_err(|_| KVStoreError::Lock)? {
+            sink.publish(change());
+        }
+        Ok(())
+    }
+
+    // Returns the first entries, up to the limit, with their keys in the range, only going
+    // through the range as far as the entry after the last one returned.
+    fn page<T>(
+        &self,
+        range: KeyRange,
+        limit: usize,
+        item: impl Fn(&Vec<u8>, &Entry) -> T,
+    ) -> Result<Page<T>> {
+        if is_empty_range(&range) {
+            return Ok(Page {
+                items: vec![],
+                next: None,
+            });
+        }
+        let map = self.map.read().map_err(|_| KVStoreError::Lock)?;
+        let now = now_millis();
+        let mut entries = map.range(range).filter(|(_, entry)| !entry.is_expired(now));
+        let page: Vec<_> = entries.by_ref().take(limit.max(1)).collect();
+        let next = match entries.next() {
+            Some(_) => page.last().map(|(key, _)| (*key).clone()),
+            None => None,
+        };
+        let items = page
+            .into_iter()
+            .map(|(key, entry)| item(key, entry))
+            .collect();
+        Ok(Page { items, next })
+    }
+}
+
+// Store the key and it's value in the map, bumping the version of the key.
+// Returns the old value and the new version of the key.
+fn put(
+    map: &mut BTreeMap<Vec<u8>, Entry>,
+    key: Vec<u8>,
+    val: Vec<u8>,
+    expires_at: Option<u64>,
+    now: u64,
+) -> (Option<Vec<u8>>, u64) {
+    let version = map
+        .get(&key)
+        .filter(|entry| !entry.is_expired(now))
+        .map_or(FIRST_VERSION, |entry| entry.version + 1);
+    let entry = Entry {
+        val,
+        expires_at,
+        version,
+    };
+    let old_val = map
+        .insert(key, entry)
+        .filter(|entry| !entry.is_expired(now))
+        .map(|entry| entry.val);
+    (old_val, version)
+}
+
+impl KvsEngine for MemoryEngine {
+    fn get(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        let map = self.map.read().map_err(|_| KVStoreError::Lock)?;
+        match map.get(&key) {
+            Some(entry) if !entry.is_expired(now_millis()) => Ok(Some(entry.val.clone())),
+            _ => Err(KVStoreError::KeyNotFound(display_key(&key))),
+        }
+    }
+
+    fn set(&self, key: Vec<u8>, val: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        let (old_val, _) = self.insert(key, val, None, |_, _| Ok(()))?;
+        Ok(old_val)
+    }
+
+    fn set_with_ttl(&self, key: Vec<u8>, val: Vec<u8>, ttl: Duration) -> Result<Option<Vec<u8>>> {
+        let expires_at = now_millis().saturating_add(ttl.as_millis() as u64);
+        let (old_val, _) = self.insert(key, val, Some(expires_at), |_, _| Ok(()))?;
+        Ok(old_val)
+    }
+
+    fn get_with_version(&self, key: Vec<u8>) -> Result<(Vec<u8>, u64)> {
+        let map = self.map.read().map_err(|_| KVStoreError::Lock)?;
+        match map.get(&key) {
+            Some(entry) if !entry.is_expired(now_millis()) => {
+                Ok((entry.val.clone(), entry.version))
+            }
+            _ => Err(KVStoreError::KeyNotFound(display_key(&key))),
+        }
+    }
+
+    fn set_if_absent(&self, key: Vec<u8>, val: Vec<u8>) -> Result<u64> {
+        let (_, version) = self.insert(
+            key,
+            val,
+            None,
+            |key, current_version| match current_version {
+                Some(_) => Err(KVStoreError::KeyExists(display_key(key))),
+                None => Ok(()),
+            },
+        )?;
+        Ok(version)
+    }
+
+    fn set_if_version(&self, key: Vec<u8>, val: Vec<u8>, version: u64) -> Result<u64> {
+        let (_, version) = self.insert(
+            key,
+            val,
+            None,
+            |key, current_version| match current_version {
+                Some(current_version) if current_version == version => Ok(()),
+                _ => Err(KVStoreError::VersionMismatch(display_key(key), version)),
+            },
+        )?;
+        Ok(version)
+    }
+
+    fn rm_if_version(&self, key: Vec<u8>, version: u64) -> Result<Option<Vec<u8>>> {
+        let mut map = self.map.write().map_err(|_| KVStoreError::Lock)?;
+        match map.get(&key) {
+            Some(entry) if !entry.is_expired(now_millis()) && entry.version == version => {
+                let old_val = map.remove(&key).map(|entry| entry.val);
+                self.publish(|| Change::Rm { key })?;
+                Ok(old_val)
+            }
+            _ => Err(KVStoreError::VersionMismatch(display_key(&key), version)),
+        }
+    }
+
+    fn ttl(&self, key: Vec<u8>) -> Result<Option<Duration>> {
+        let map = self.map.read().map_err(|_| KVStoreError::Lock)?;
+        let now = now_millis();
+        match map.get(&key) {
+            Some(entry) if !entry.is_expired(now) => Ok(entry
+                .expires_at
+                .map(|expires_at| Duration::from_millis(expires_at - now))),
+            _ => Err(KVStoreError::KeyNotFound(display_key(&key))),
+        }
+    }
+
+    fn rm(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        let mut map = self.map.write().map_err(|_| KVStoreError::Lock)?;
+        match map.remove(&key) {
+            Some(entry) if !entry.is_expired(now_millis()) => {
+                self.publish(|| Change::Rm { key })?;
+                Ok(Some(entry.val))
+            }
+            _ => Err(KVStoreError::KeyNotFound(display_key(&key))),
+        }
+    }
+
+    fn incr(&self, key: Vec<u8>, delta: i64) -> Result<i64> {
+        let mut map = self.map.write().map_err(|_| KVStoreError::Lock)?;
+        let now = now_millis();
+        let (val, expires_at) = match map.get(&key) {
+            Some(entry) if !entry.is_expired(now) => (Some(entry.val.as_slice()), entry.expires_at),
+            _ => (None, None),
+        };
+        let counter = apply_delta(&key, val, delta)?;
+        let val = counter.to_string().into_bytes();
+        self.publish(|| Change::Set {
+            key: key.clone(),
+            val: val.clone(),
+        })?;
+        put(&mut map, key, val, expires_at, now);
+        Ok(counter)
+    }
+
+    fn commit_txn(&self, ops: Vec<WriteOp>) -> Result<Vec<Option<Vec<u8>>>> {
+        let mut map = self.map.write().map_err(|_| KVStoreError::Lock)?;
+        let now = now_millis();
+        // Make sure every key to be removed exists, as per the writes before it,
+        // before applying any of the writes.
+        let mut live: HashMap<&[u8], bool> = HashMap::new();
+        for op in &ops {
+            match op {
+                WriteOp::Set { key, .. } => {
+                    live.insert(key, true);
+                }
+                WriteOp::Rm { key } => {
+                    let exists = match live.get(key.as_slice()) {
+                        Some(&exists) => exists,
+                        None => matches!(map.get(key), Some(entry) if !entry.is_expired(now)),
+                    };
+                    if !exists {
+                        return Err(KVStoreError::KeyNotFound(display_key(key)));
+                    }
+                    live.insert(key, false);
+                }
+            }
+        }
+        drop(live);
+        let mut old_vals = Vec::with_capacity(ops.len());
+        for op in ops {
+            let old_val = match op {
+                WriteOp::Set { key, val } => {
+                    self.publish(|| Change::Set {
+                        key: key.clone(),
+                        val: val.clone(),
+                    })?;
+                    put(&mut map, key, val, None, now).0
+                }
+                WriteOp::Rm { key } => {
+                    let old_val = map
+                        .remove(&key)
+                        .filter(|entry| !entry.is_expired(now))
+                        .map(|entry| entry.val);
+                    self.publish(|| Change::Rm { key })?;
+                    old_val
+                }
+            };
+            old_vals.push(old_val);
+        }
+        Ok(old_vals)
+    }
+
+    fn scan(&self, range: KeyRange) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        if is_empty_range(&range) {
+            return Ok(vec![]);
+        }
+        let map = self.map.read().map_err(|_| KVStoreError::Lock)?;
+        let now = now_millis();
+        Ok(map
+            .range(range)
+            .filter(|(_, entry)| !entry.is_expired(now))
+            .map(|(key, entry)| (key.clone(), entry.val.clone()))
+            .collect())
+    }
+
+    fn scan_page(&self, range: KeyRange, limit: usize) -> Result<Page<(Vec<u8>, Vec<u8>)>> {
+        self.page(range, limit, |key, entry| (key.clone(), entry.val.clone()))
+    }
+
+    fn keys_page(&self, range: KeyRange, limit: usize) -> Result<Page<Vec<u8>>> {
+        self.page(range, limit, |key, _| key.clone())
+    }
+
+    fn stats(&self) -> Result<EngineStats> {
+        let map = self.map.read().map_err(|_| KVStoreError::Lock)?;
+        // Expired keys are only dropped by the reaper, until then they aren't counted.
+        let now = now_millis();
+        let live_keys = map.values().filter(|entry| !entry.is_expired(now)).count();
+        Ok(EngineStats {
+            engine: String::from("memory"),
+            live_keys: live_keys as u64,
+            ..EngineStats::default()
+        })
+    }
+
+    fn reap_expired(&self) -> Result<usize> {
+        let mut map = self.map.write().map_err(|_| KVStoreError::Lock)?;
+        let now = now_millis();
+        let expired: Vec<Vec<u8>> = map
+            .iter()
+            .filter(|(_, entry)| entry.is_expired(now))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in &expired {
+            map.remove(key);
+            self.publish(|| Change::Rm { key: key.clone() })?;
+        }
+        Ok(expired.len())
+    }
+
+    fn watch(&self, sink: ChangeSink) -> Result<()> {
+        *self.sink.write().map_err(|_| KVStoreError::Lock)? = Some(sink);
+        Ok(())
+    }
+}
@@ -0,0 +1,671 @@
+use crate::{error::display_key, watch::ChangeSink, KVStore, KVStoreConfig, KVStoreError, Result};
+use log::error;
+use serde::{Deserialize, Serialize};
+use std::{
+    ops::{Bound, RangeBounds},
+    path::PathBuf,
+    str::FromStr,
+    sync::{Arc, Weak},
+    thread,
+    time::Duration,
+};
+
+mod async_engine;
+mod buckets;
+mod memory;
+mod sled;
+pub use self::async_engine::AsyncEngine;
+pub use self::buckets::Buckets;
+pub use self::memory::MemoryEngine;
+pub use self::sled::SledEngine;
+
+/// Range of keys to scan, in terms of its start and end bounds.
+pub type KeyRange = (Bound<Vec<u8>>, Bound<Vec<u8>>);
+
+/// Returns the range of all the keys starting with the prefix.
+pub fn prefix_range(prefix: impl AsRef<[u8]>) -> KeyRange {
+    let prefix = prefix.as_ref();
+    let start = Bound::Included(prefix.to_vec());
+    // The smallest key greater than all the keys with the prefix is the prefix with
+    // its last byte bumped, if it can be.
+    let mut end = prefix.to_vec();
+    while let Some(byte) = end.pop() {
+        if byte < u8::MAX {
+            end.push(byte + 1);
+            return (start, Bound::Excluded(end));
+        }
+    }
+    (start, Bound::Unbounded)
+}
+
+// Whether no key can be in the range, e.g. when it starts after it ends. Ranges like
+// that make the ordered maps panic, rather than yield nothing.
+pub(crate) fn is_empty_range(range: &impl RangeBounds<Vec<u8>>) -> bool {
+    match (range.start_bound(), range.end_bound()) {
+        (Bound::Included(start), Bound::Included(end)) => start > end,
+        (Bound::Included(start), Bound::Excluded(end))
+        | (Bound::Excluded(start), Bound::Included(end))
+        | (Bound::Excluded(start), Bound::Excluded(end)) => start >= end,
+        _ => false,
+    }
+}
+
+// Add the delta to a counter, given its current value, which is 0 if the key doesn't exist.
+// Counters are stored as integers written out in decimal.
+pub(crate) fn apply_delta(key: &[u8], val: Option<&[u8]>, delta: i64) -> Result<i64> {
+    let current = match val {
+        Some(val) => std::str::from_utf8(val)
+            .ok()
+            .and_then(|val| val.parse::<i64>().ok())
+            .ok_or_else(|| KVStoreError::NotAnInteger(display_key(key)))?,
+        None => 0,
+    };
+    current
+        .checked_add(delta)
+        .ok_or_else(|| KVStoreError::Overflow(display_key(key)))
+}
+
+/// A page of the results of a scan, ordered by key.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    /// Cursor to continue the scan from, i.e. the last key in this page,
+    /// or None if there are no more results.
+    pub next: Option<Vec<u8>>,
+}
+
+impl<T> Page<T> {
+    // Build a page out of the first items, up to the limit, of all the results.
+    fn truncate(mut items: Vec<T>, limit: usize, key: impl Fn(&T) -> &Vec<u8>) -> Self {
+        let mut next = None;
+        if items.len() > limit {
+            items.truncate(limit);
+            next = items.last().map(|item| key(item).clone());
+        }
+        Page { items, next }
+    }
+}
+
+/// A write to be committed as part of a transaction.
+#[derive(Debug, Clone, PartialEq)]
+pub enum WriteOp {
+    /// Stores the key and it's value.
+    Set { key: Vec<u8>, val: Vec<u8> },
+    /// Removes the key. The transaction fails if the key doesn't exist.
+    Rm { key: Vec<u8> },
+}
+
+/// How often expired keys are removed from the engines opened by `open_engine`.
+pub const REAP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A storage engine that the server can be backed by.
+///
+/// The semantics of all the methods are the same as that of their counterparts on KVStore.
+pub trait KvsEngine: Send + Sync {
+    /// Gets the value related to the given key. If not found, returns a KeyNotFound error.
+    fn get(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>>;
+
+    /// Stores the key and it's value. If the key already existed, the old value is returned.
+    fn set(&self, key: Vec<u8>, val: Vec<u8>) -> Result<Option<Vec<u8>>>;
+
+    /// Same as `set`, but the key expires once the TTL has elapsed.
+    fn set_with_ttl(
+        &self,
+        _key: Vec<u8>,
+        _val: Vec<u8>,
+        _ttl: Duration,
+    ) -> Result<Option<Vec<u8>>> {
+        Err(KVStoreError::Unsupported(String::from("ttl")))
+    }
+
+    /// Returns the time left before the key expires, or None if it never does.
+    /// If not found, returns a KeyNotFound error.
+    fn ttl(&self, _key: Vec<u8>) -> Result<Option<Duration>> {
+        Err(KVStoreError::Unsupported(String::from("ttl")))
+    }
+
+    /// Gets the value related to the given key along with the version of the key.
+    /// If not found, returns a KeyNotFound error.
+    fn get_with_version(&self, _key: Vec<u8>) -> Result<(Vec<u8>, u64)> {
+        Err(KVStoreError::Unsupported(String::from("versions")))
+    }
+
+    /// Stores the key and it's value, only if the key doesn't exist. Returns the version of the
+    /// key. If the key already exists, returns a KeyExists error.
+    fn set_if_absent(&self, _key: Vec<u8>, _val: Vec<u8>) -> Result<u64> {
+        Err(KVStoreError::Unsupported(String::from("versions")))
+    }
+
+    /// Stores the key and it's value, only if the key is at the given version. Returns the new
+    /// version of the key. Otherwise, returns a VersionMismatch error.
+    fn set_if_version(&self, _key: Vec<u8>, _val: Vec<u8>, _version: u64) -> Result<u64> {
+        Err(KVStoreError::Unsupported(String::from("versions")))
+    }
+
+    /// Removes a key and it's value, only if the key is at the given version. Returns the
+    /// current value of the key. Otherwise, returns a VersionMismatch error.
+    fn rm_if_version(&self, _key: Vec<u8>, _version: u64) -> Result<Option<Vec<u8>>> {
+        Err(KVStoreError::Unsupported(String::from("versions")))
+    }
+
+    /// Removes a key and it's value. Returns the current value of the key.
+    /// If key is not found, returns a KeyNotFound error.
+    fn rm(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>>;
+
+    /// Adds the delta to the integer stored as the value of the key, atomically, and returns
+    /// the result. Keys that don't exist start at 0. If the value isn't an integer, returns
+    /// a NotAnInteger error and if the result doesn't fit in an i64, an Overflow error.
+    fn incr(&self, _key: Vec<u8>, _delta: i64) -> Result<i64> {
+        Err(KVStoreError::Unsupported(String::from("counters")))
+    }
+
+    /// Commits the writes atomically, either all of them are applied or none of them.
+    /// Returns the old value of the key for each write, in order.
+    fn commit_txn(&self, _ops: Vec<WriteOp>) -> Result<Vec<Option<Vec<u8>>>> {
+        Err(KVStoreError::Unsupported(String::from("transactions")))
+    }
+
+    /// Gets the values of all the keys, in order. Keys that don't exist have no value.
+    fn get_many(&self, keys: Vec<Vec<u8>>) -> Result<Vec<Option<Vec<u8>>>> {
+        keys.into_iter()
+            .map(|key| match self.get(key) {
+                Err(KVStoreError::KeyNotFound(_)) => Ok(None),
+                val => val,
+            })
+            .collect()
+    }
+
+    /// Applies the writes in order, each of which succeeds or fails on its own.
+    /// Returns the result of each write, in order: the old value of the key, or the error
+    /// the write failed with.
+    fn write_many(&self, ops: Vec<WriteOp>) -> Result<Vec<Result<Option<Vec<u8>>>>> {
+        Ok(ops
+            .into_iter()
+            .map(|op| match op {
+                WriteOp::Set { key, val } => self.set(key, val),
+                WriteOp::Rm { key } => self.rm(key),
+            })
+            .collect())
+    }
+
+    /// Returns all the key-value pairs with their keys in the range, ordered by key.
+    fn scan(&self, range: KeyRange) -> Result<Vec<(Vec<u8>, Vec<u8>)>>;
+
+    /// Returns the first key-value pairs, up to the limit, with their keys in the range.
+    /// Pass the cursor of the page as the excluded start of the range to get the next page.
+    /// By default, the whole range is scanned, engines should only read as far as the page goes.
+    fn scan_page(&self, range: KeyRange, limit: usize) -> Result<Page<(Vec<u8>, Vec<u8>)>> {
+        let pairs = self.scan(range)?;
+        Ok(Page::truncate(pairs, limit.max(1), |(key, _)| key))
+    }
+
+    /// Same as `scan_page`, but only returns the keys.
+    fn keys_page(&self, range: KeyRange, limit: usize) -> Result<Page<Vec<u8>>> {
+        let keys = self.scan(range)?.into_iter().map(|(key, _)| key).collect();
+        Ok(Page::truncate(keys, limit.max(1), |key| key))
+    }
+
+    /// Returns statistics about the data stored in the engine.
+    fn stats(&self) -> Result<EngineStats>;
+
+    /// Checks that the engine can still be written to, without changing any of the keys.
+    /// Engines that keep everything in memory always can.
+    fn check_writable(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Reclaims the space taken up by stale data. Returns the number of bytes reclaimed.
+    /// Engines that don't need to be compacted reclaim nothing.
+    fn compact(&self) -> Result<u64> {
+        Ok(0)
+    }
+
+    /// Removes the keys that have expired. Returns the number of keys removed.
+    fn reap_expired(&self) -> Result<usize> {
+        Ok(0)
+    }
+
+    /// Sends every change made to the keys from now on to the sink, in the order the changes
+    /// are made, whether they're made by a request, an import or the reaper.
+    fn watch(&self, sink: ChangeSink) -> Result<()>;
+
+    /// Writes a consistent copy of the data, as of a single point in time, to the directory,
+    /// which must not contain any data yet. Writes can continue while the copy is written.
+    fn snapshot(&self, _dest: PathBuf) -> Result<SnapshotInfo> {
+        Err(KVStoreError::Unsupported(String::from("snapshots")))
+    }
+}
+
+/// Statistics about the data stored in an engine.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct EngineStats {
+    pub engine: String,
+    pub live_keys: u64,
+    pub disk_bytes: u64,
+    pub stale_bytes: u64,
+    /// Number of compactions run since the engine was opened, for engines that compact a log.
+    #[serde(default)]
+    pub compactions: u64,
+    /// Number of reads served from, and missed by, the value cache, if the engine has one.
+    #[serde(default)]
+    pub cache_hits: u64,
+    #[serde(default)]
+    pub cache_misses: u64,
+    /// Size of the keys and values in the value cache.
+    #[serde(default)]
+    pub cache_bytes: u64,
+}
+
+/// Summary of a snapshot written by an engine.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SnapshotInfo {
+    /// Number of keys in the snapshot.
+    pub keys: u64,
+    /// Size of the snapshot on disk.
+    pub bytes: u64,
+}
+
+/// The kinds of storage engines available.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EngineKind {
+    /// KVStore, the log-structured store.
+    Kvs,
+    /// An engine backed by sled.
+    Sled,
+    /// An engine which keeps everything in memory. Nothing is persisted.
+    Memory,
+}
+
+impl Default for EngineKind {
+    fn default() -> Self {
+        EngineKind::Kvs
+    }
+}
+
+impl FromStr for EngineKind {
+    type Err = KVStoreError;
+
+    /// Parses one of `kvs`, `sled` or `memory`.
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "kvs" => Ok(EngineKind::Kvs),
+            "sled" => Ok(EngineKind::Sled),
+            "memory" => Ok(EngineKind::Memory),
+            _ => Err(KVStoreError::InvalidEngine(s.to_string())),
+        }
+    }
+}
+
+/// Opens the engine of the given kind, storing its data in the directory.
+pub fn open_engine(
+    kind: EngineKind,
+    path: impl Into<PathBuf>,
+    config: KVStoreConfig,
+) -> Result<Arc<dyn KvsEngine>> {
+    let path = path.into();
+    if kind != EngineKind::Kvs && config.legacy_log.is_some() {
+        return Err(KVStoreError::Unsupported(String::from(
+            "importing a legacy log",
+        )));
+    }
+    let engine: Arc<dyn KvsEngine> = match kind {
+        EngineKind::Kvs => Arc::new(KVStore::open_with_config(path, config)?),
+        // Keep sled's files apart from the segments of the log.
+        EngineKind::Sled => Arc::new(SledEngine::open(path.join("sled"))?),
+        EngineKind::Memory => Arc::new(MemoryEngine::new()),
+    };
+    spawn_reaper(Arc::downgrade(&engine), REAP_INTERVAL);
+    Ok(engine)
+}
+
+// Periodically remove the expired keys from the engine, until it's dropped.
+fn spawn_reaper(engine: Weak<dyn KvsEngine>, interval: Duration) {
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+        let engine = match engine.upgrade() {
+            Some(engine) => engine,
+            None => return,
+        };
+        if let Err(err) = engine.reap_expired() {
+            error!("Could not remove expired keys: {}", err);
+        }
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::watch::{Change, Changes, DEFAULT_HISTORY_SIZE};
+    use rand::Rng;
+    use std::{fs, sync::Arc};
+
+    // Run the same checks against every kind of engine.
+    fn check_engine(engine: &dyn KvsEngine) {
+        let key = b"this is".to_vec();
+        assert_eq!(engine.set(key.clone(), b"the way".to_vec()).unwrap(), None);
+        assert_eq!(
+            engine.set(key.clone(), b"not the way".to_vec()).unwrap(),
+            Some(b"the way".to_vec())
+        );
+        assert_eq!(
+            engine.get(key.clone()).unwrap(),
+            Some(b"not the way".to_vec())
+        );
+        for i in 0..5 {
+            engine
+                .set(
+                    format!("key {}", i).into_bytes(),
+                    format!("val {}", i).into_bytes(),
+                )
+                .unwrap();
+        }
+        let range = (
+            Bound::Included(b"key 1".to_vec()),
+            Bound::Excluded(b"key 3".to_vec()),
+        );
+        assert_eq!(
+            engine.scan(range).unwrap(),
+            vec![
+                (b"key 1".to_vec(), b"val 1".to_vec()),
+                (b"key 2".to_vec(), b"val 2".to_vec()),
+            ]
+        );
+        assert_eq!(
+            engine.rm(key.clone()).unwrap(),
+            Some(b"not the way".to_vec())
+        );
+        assert!(matches!(
+            engine.get(key.clone()),
+            Err(KVStoreError::KeyNotFound(_))
+        ));
+        assert!(matches!(engine.rm(key), Err(KVStoreError::KeyNotFound(_))));
+        // Keys and values are arbitrary bytes, not necessarily UTF-8.
+        let key = vec![0, 159, 146, 150];
+        let val = vec![255, 0, 254];
+        assert_eq!(engine.set(key.clone(), val.clone()).unwrap(), None);
+        assert_eq!(engine.get(key.clone()).unwrap(), Some(val.clone()));
+        assert_eq!(engine.rm(key).unwrap(), Some(val));
+        assert_eq!(engine.stats().unwrap().live_keys, 5);
+    }
+
+    // Run the checks for paginated scans against every kind of engine.
+    fn check_pages(engine: &dyn KvsEngine) {
+        let mut range = prefix_range("key");
+        let mut keys = vec![];
+        loop {
+            let page = engine.keys_page(range.clone(), 2).unwrap();
+            assert!(page.items.len() <= 2);
+            keys.extend(page.items);
+            match page.next {
+                Some(cursor) => range.0 = Bound::Excluded(cursor),
+                None => break,
+            }
+        }
+        let expected: Vec<Vec<u8>> = (0..5).map(|i| format!("key {}", i).into_bytes()).collect();
+        assert_eq!(keys, expected);
+        let page = engine.scan_page(prefix_range("key 4"), 2).unwrap();
+        assert_eq!(
+            page,
+            Page {
+                items: vec![(b"key 4".to_vec(), b"val 4".to_vec())],
+                next: None,
+            }
+        );
+        // A cursor past the end of the range leaves nothing to scan.
+        let range = (
+            Bound::Excluded(b"key 4".to_vec()),
+            Bound::Excluded(b"key 2".to_vec()),
+        );
+        assert!(engine.keys_page(range.clone(), 2).unwrap().items.is_empty());
+        assert!(engine.scan(range).unwrap().is_empty());
+    }
+
+    // Run the checks for expiring keys against the engines which support them.
+    fn check_ttl(engine: &dyn KvsEngine) {
+        let key = b"session".to_vec();
+        engine
+            .set_with_ttl(key.clone(), b"token".to_vec(), Duration::from_millis(50))
+            .unwrap();
+        assert!(engine.ttl(key.clone()).unwrap().is_some());
+        thread::sleep(Duration::from_millis(100));
+        assert!(matches!(
+            engine.get(key.clone()),
+            Err(KVStoreError::KeyNotFound(_))
+        ));
+        assert_eq!(
+            engine
+                .scan((Bound::Unbounded, Bound::Unbounded))
+                .unwrap()
+                .len(),
+            5
+        );
+        // Nor is it counted towards a page, or taken as the start of another one.
+        let page = engine
+            .keys_page((Bound::Unbounded, Bound::Unbounded), 5)
+            .unwrap();
+        assert_eq!((page.items.len(), page.next), (5, None));
+        assert_eq!(engine.stats().unwrap().live_keys, 5);
+        assert_eq!(engine.reap_expired().unwrap(), 1);
+        assert!(matches!(engine.ttl(key), Err(KVStoreError::KeyNotFound(_))));
+    }
+
+    // Run the checks for transactions against the engines which support them.
+    fn check_txn(engine: &dyn KvsEngine) {
+        let set = |key: &str, val: &str| WriteOp::Set {
+            key: key.as_bytes().to_vec(),
+            val: val.as_bytes().to_vec(),
+        };
+        let rm = |key: &str| WriteOp::Rm {
+            key: key.as_bytes().to_vec(),
+        };
+        engine.set(b"from".to_vec(), b"10".to_vec()).unwrap();
+        let old_vals = engine
+            .commit_txn(vec![set("from", "5"), set("to", "5"), rm("from")])
+            .unwrap();
+        assert_eq!(
+            old_vals,
+            vec![Some(b"10".to_vec()), None, Some(b"5".to_vec())]
+        );
+        assert!(engine.get(b"from".to_vec()).is_err());
+        // Nothing is applied if any of the writes fail.
+        assert!(matches!(
+            engine.commit_txn(vec![set("to", "0"), rm("missing")]),
+            Err(KVStoreError::KeyNotFound(_))
+        ));
+        assert_eq!(engine.get(b"to".to_vec()).unwrap(), Some(b"5".to_vec()));
+        engine.rm(b"to".to_vec()).unwrap();
+    }
+
+    // Run the checks for conditional writes against the engines which support them.
+    fn check_versions(engine: &dyn KvsEngine) {
+        let key = b"lock".to_vec();
+        let val = b"owner".to_vec();
+        assert_eq!(engine.set_if_absent(key.clone(), val.clone()).unwrap(), 1);
+        assert!(matches!(
+            engine.set_if_absent(key.clone(), val.clone()),
+            Err(KVStoreError::KeyExists(_))
+        ));
+        assert_eq!(
+            engine.get_with_version(key.clone()).unwrap(),
+            (val.clone(), 1)
+        );
+        assert!(matches!(
+            engine.set_if_version(key.clone(), b"other".to_vec(), 2),
+            Err(KVStoreError::VersionMismatch(_, 2))
+        ));
+        assert_eq!(
+            engine.set_if_version(key.clone(), val.clone(), 1).unwrap(),
+            2
+        );
+        assert!(matches!(
+            engine.rm_if_version(key.clone(), 1),
+            Err(KVStoreError::VersionMismatch(_, 1))
+        ));
+        assert_eq!(engine.rm_if_version(key.clone(), 2).unwrap(), Some(val));
+        assert!(matches!(
+            engine.set_if_version(key, b"other".to_vec(), 2),
+            Err(KVStoreError::VersionMismatch(_, 2))
+        ));
+    }
+
+    // Run the checks for counters against every kind of engine.
+    fn check_counters(engine: &dyn KvsEngine) {
+        let key = b"counter".to_vec();
+        assert_eq!(engine.incr(key.clone(), 1).unwrap(), 1);
+        assert_eq!(engine.incr(key.clone(), 41).unwrap(), 42);
+        assert_eq!(engine.incr(key.clone(), -50).unwrap(), -8);
+        assert_eq!(engine.get(key.clone()).unwrap(), Some(b"-8".to_vec()));
+        assert!(matches!(
+            engine.incr(key.clone(), i64::MIN),
+            Err(KVStoreError::Overflow(_))
+        ));
+        engine.set(key.clone(), b"not a number".to_vec()).unwrap();
+        assert!(matches!(
+            engine.incr(key.clone(), 1),
+            Err(KVStoreError::NotAnInteger(_))
+        ));
+        engine.rm(key).unwrap();
+    }
+
+    fn check_batch(engine: &dyn KvsEngine) {
+        let results = engine
+            .write_many(vec![
+                WriteOp::Set {
+                    key: b"a".to_vec(),
+                    val: b"1".to_vec(),
+                },
+                WriteOp::Rm {
+                    key: b"missing".to_vec(),
+                },
+                WriteOp::Set {
+                    key: b"a".to_vec(),
+                    val: b"2".to_vec(),
+                },
+                WriteOp::Set {
+                    key: b"b".to_vec(),
+                    val: b"3".to_vec(),
+                },
+            ])
+            .unwrap();
+        // A failed write doesn't affect the others, and later writes see earlier ones.
+        assert_eq!(results.len(), 4);
+        assert_eq!(results[0].as_ref().unwrap(), &None);
+        assert!(matches!(results[1], Err(KVStoreError::KeyNotFound(_))));
+        assert_eq!(results[2].as_ref().unwrap(), &Some(b"1".to_vec()));
+        assert_eq!(results[3].as_ref().unwrap(), &None);
+
+        let keys = vec![b"b".to_vec(), b"missing".to_vec(), b"a".to_vec()];
+        assert_eq!(
+            engine.get_many(keys).unwrap(),
+            vec![Some(b"3".to_vec()), None, Some(b"2".to_vec())]
+        );
+        engine.rm(b"a".to_vec()).unwrap();
+        engine.rm(b"b".to_vec()).unwrap();
+    }
+
+    #[test]
+    fn test_engines() {
+        for &kind in &[EngineKind::Kvs, EngineKind::Sled, EngineKind::Memory] {
+            let n: u32 = rand::thread_rng().gen();
+            let dir = format!("/tmp/kvs-engine-{}", n);
+            let engine = open_engine(kind, dir.clone(), KVStoreConfig::default()).unwrap();
+            check_engine(engine.as_ref());
+            check_pages(engine.as_ref());
+            check_counters(engine.as_ref());
+            check_batch(engine.as_ref());
+            if kind != EngineKind::Sled {
+                check_ttl(engine.as_ref());
+                check_versions(engine.as_ref());
+                check_txn(engine.as_ref());
+            }
+            drop(engine);
+            let _ = fs::remove_dir_all(dir);
+        }
+    }
+
+    #[test]
+    fn test_watch() {
+        for &kind in &[EngineKind::Kvs, EngineKind::Sled, EngineKind::Memory] {
+            let n: u32 = rand::thread_rng().gen();
+            let dir = format!("/tmp/kvs-engine-watch-{}", n);
+            let engine = open_engine(kind, dir.clone(), KVStoreConfig::default()).unwrap();
+            let changes = Arc::new(Changes::new(DEFAULT_HISTORY_SIZE));
+            let (_, mut receiver) = changes.subscribe(None).unwrap();
+            engine
+                .watch(ChangeSink::new(changes, Some(String::from("users"))))
+                .unwrap();
+
+            // Every change is sent, however it's made.
+            engine.set(b"a".to_vec(), b"1".to_vec()).unwrap();
+            engine.incr(b"n".to_vec(), 2).unwrap();
+            engine
+                .write_many(vec![WriteOp::Rm { key: b"a".to_vec() }])
+                .unwrap();
+            let mut expected = vec![
+                Change::Set {
+                    key: b"a".to_vec(),
+                    val: b"1".to_vec(),
+                },
+                Change::Set {
+                    key: b"n".to_vec(),
+                    val: b"2".to_vec(),
+                },
+                Change::Rm { key: b"a".to_vec() },
+            ];
+            if kind != EngineKind::Sled {
+                engine
+                    .set_with_ttl(b"s".to_vec(), b"t".to_vec(), Duration::from_millis(10))
+                    .unwrap();
+                thread::sleep(Duration::from_millis(50));
+                assert_eq!(engine.reap_expired().unwrap(), 1);
+                expected.push(Change::Set {
+                    key: b"s".to_vec(),
+                    val: b"t".to_vec(),
+                });
+                expected.push(Change::Rm { key: b"s".to_vec() });
+            }
+
+            // sled sends the changes from a thread of its own.
+            let mut received = vec![];
+            for _ in 0..100 {
+                while let Ok(event) = receiver.try_recv() {
+                    assert_eq!(event.bucket.as_deref(), Some("users"));
+                    received.push(event.change.clone());
+                }
+                if received.len() >= expected.len() {
+                    break;
+                }
+                thread::sleep(Duration::from_millis(10));
+            }
+            assert_eq!(received, expected);
+            drop(engine);
+            let _ = fs::remove_dir_all(dir);
+        }
+    }
+
+    #[test]
+    fn test_prefix_range() {
+        let range = prefix_range("ab");
+        assert_eq!(
+            range,
+            (
+                Bound::Included(b"ab".to_vec()),
+                Bound::Excluded(b"ac".to_vec())
+            )
+        );
+        let range = prefix_range([b'a', 0xff, 0xff]);
+        assert_eq!(range.1, Bound::Excluded(b"b".to_vec()));
+        let range = prefix_range("a\u{10ffff}");
+        assert_eq!(range.1, Bound::Excluded(vec![b'a', 0xf4, 0x8f, 0xbf, 0xc0]));
+        assert_eq!(prefix_range([0xff]).1, Bound::Unbounded);
+        assert_eq!(prefix_range("").1, Bound::Unbounded);
+    }
+
+    #[test]
+    fn test_engine_kind_from_str() {
+        assert_eq!("kvs".parse::<EngineKind>().unwrap(), EngineKind::Kvs);
+        assert_eq!("sled".parse::<EngineKind>().unwrap(), EngineKind::Sled);
+        assert_eq!("memory".parse::<EngineKind>().unwrap(), EngineKind::Memory);
+        assert!("rocks".parse::<EngineKind>().is_err());
+    }
+}
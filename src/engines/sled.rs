@@ -0,0 +1,151 @@
+use super::{apply_delta, is_empty_range, EngineStats, KeyRange, KvsEngine, Page};
+use crate::{
+    error::display_key,
+    watch::{Change, ChangeSink},
+    KVStoreError, Result,
+};
+use sled::{Db, Event};
+use std::{path::PathBuf, thread};
+
+/// An engine backed by sled, an embedded database.
+pub struct SledEngine {
+    db: Db,
+}
+
+impl SledEngine {
+    /// Opens or creates the sled database in the directory.
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        let db = sled::open(path.into())?;
+        Ok(SledEngine { db })
+    }
+}
+
+impl KvsEngine for SledEngine {
+    fn get(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        match self.db.get(&key)? {
+            Some(val) => Ok(Some(val.to_vec())),
+            None => Err(KVStoreError::KeyNotFound(display_key(&key))),
+        }
+    }
+
+    fn set(&self, key: Vec<u8>, val: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        let old_val = self.db.insert(key, val)?;
+        self.db.flush()?;
+        Ok(old_val.map(|old_val| old_val.to_vec()))
+    }
+
+    fn rm(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        let old_val = self.db.remove(&key)?;
+        self.db.flush()?;
+        match old_val {
+            Some(old_val) => Ok(Some(old_val.to_vec())),
+            None => Err(KVStoreError::KeyNotFound(display_key(&key))),
+        }
+    }
+
+    fn incr(&self, key: Vec<u8>, delta: i64) -> Result<i64> {
+        // Retry until the value isn't changed by anyone else in between.
+        loop {
+            let old_val = self.db.get(&key)?;
+            let counter = apply_delta(&key, old_val.as_deref(), delta)?;
+            let new_val = counter.to_string().into_bytes();
+            if self
+                .db
+                .compare_and_swap(&key, old_val, Some(new_val))?
+                .is_ok()
+            {
+                self.db.flush()?;
+                return Ok(counter);
+            }
+        }
+    }
+
+    fn scan(&self, range: KeyRange) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        if is_empty_range(&range) {
+            return Ok(vec![]);
+        }
+        self.db
+            .range(range)
+            .map(|pair| {
+                let (key, val) = pair?;
+                Ok((key.to_vec(), val.to_vec()))
+            })
+            .collect()
+    }
+
+    fn scan_page(&self, range: KeyRange, limit: usize) -> Result<Page<(Vec<u8>, Vec<u8>)>> {
+        if is_empty_range(&range) {
+            return Ok(Page {
+                items: vec![],
+                next: None,
+            });
+        }
+        let mut pairs = self.db.range(range).take(limit.max(1) + 1).map(|pair| {
+            let (key, val) = pair?;
+            Ok((key.to_vec(), val.to_vec()))
+        });
+        let items = pairs
+            .by_ref()
+            .take(limit.max(1))
+            .collect::<Result<Vec<_>>>()?;
+        let next = match pairs.next() {
+            Some(_) => items.last().map(|(key, _)| key.clone()),
+            None => None,
+        };
+        Ok(Page { items, next })
+    }
+
+    fn keys_page(&self, range: KeyRange, limit: usize) -> Result<Page<Vec<u8>>> {
+        if is_empty_range(&range) {
+            return Ok(Page {
+                items: vec![],
+                next: None,
+            });
+        }
+        let mut keys = self.db.range(range).take(limit.max(1) + 1).map(|pair| {
+            let (key, _) = pair?;
+            Ok(key.to_vec())
+        });
+        let items = keys
+            .by_ref()
+            .take(limit.max(1))
+            .collect::<Result<Vec<_>>>()?;
+        let next = match keys.next() {
+            Some(_) => items.last().cloned(),
+            None => None,
+        };
+        Ok(Page { items, next })
+    }
+
+    fn stats(&self) -> Result<EngineStats> {
+        Ok(EngineStats {
+            engine: String::from("sled"),
+            live_keys: self.db.len() as u64,
+            disk_bytes: self.db.size_on_disk()?,
+            ..EngineStats::default()
+        })
+    }
+
+    fn check_writable(&self) -> Result<()> {
+        self.db.flush()?;
+        Ok(())
+    }
+
+    fn watch(&self, sink: ChangeSink) -> Result<()> {
+        // sled sends the changes to every key in the order they're made, until the database
+        // is closed.
+        let events = self.db.watch_prefix(vec![]);
+        thread::spawn(move || {
+            for event in events {
+                sink.publish(match event {
+                    Event::Insert { key, value } => Change::Set {
+                        key: key.to_vec(),
+                        val: value.to_vec(),
+                    },
+                    Event::Remove { key } => Change::Rm { key: key.to_vec() },
+                });
+            }
+        });
+        Ok(())
+    }
+}
@@ -9,11 +9,74 @@ pub enum KVStoreError {
     Serde(#[from] serde_json::Error),
     #[error("Key `{0}` does not exist.")]
     KeyNotFound(String),
+    #[error("Key `{0}` already exists.")]
+    KeyExists(String),
+    #[error("Key `{0}` is not at version {1}.")]
+    VersionMismatch(String, u64),
     #[error("`{0}` is not a valid action.")]
     InvalidAction(String),
     #[error("Error while getting a lock.")]
     Lock,
+    #[error("`{0}` is not a valid durability policy.")]
+    InvalidDurability(String),
+    #[error("`{0}` is not a valid engine.")]
+    InvalidEngine(String),
+    #[error("`{1}` is not a valid value for `{0}`.")]
+    InvalidSetting(String, String),
+    #[error("Sled Error: `{0}`")]
+    Sled(#[from] sled::Error),
+    #[error("Invalid UTF-8: `{0}`")]
+    Utf8(#[from] std::string::FromUtf8Error),
+    #[error("Invalid base64: `{0}`")]
+    Base64(#[from] base64::DecodeError),
+    #[error("Found a corrupt or incomplete record in the log.")]
+    Corrupt,
+    #[error("The last write to the log failed: {0}")]
+    WriteFailed(String),
+    #[error("Segment `{0}` is not in any known format.")]
+    UnknownSegment(String),
+    #[error(
+        "Log `{0}` can't be imported, it's not a file. Logs are kept in a data directory now."
+    )]
+    LegacyLog(String),
+    #[error("`{0}` is not supported by this engine.")]
+    Unsupported(String),
+    #[error("Value of key `{0}` is not an integer.")]
+    NotAnInteger(String),
+    #[error("Value of key `{0}` would overflow.")]
+    Overflow(String),
+    #[error("Blocking task failed: `{0}`")]
+    Task(#[from] tokio::task::JoinError),
+    #[error("Bucket `{0}` does not exist.")]
+    BucketNotFound(String),
+    #[error("Bucket `{0}` already exists.")]
+    BucketExists(String),
+    #[error("`{0}` is not a valid bucket name.")]
+    InvalidBucket(String),
+    #[error("Directory `{0}` is not empty.")]
+    DirNotEmpty(String),
+    #[error("Directory `{0}` is in use by another process.")]
+    DirLocked(String),
+    #[error("`{0}` is not a path within the backup directory.")]
+    InvalidPath(String),
+    #[error("`{0}` is not a valid snapshot.")]
+    InvalidSnapshot(String),
+    #[error("`{0}` is not a valid format.")]
+    InvalidFormat(String),
+    #[error("Invalid record on line {0}: {1}")]
+    InvalidRecord(u64, String),
+    #[error("The changes after `{0}` are no longer available.")]
+    EventsUnavailable(String),
+    #[error("`{0}` is not a valid event id.")]
+    InvalidEventId(String),
+    #[error("The body is larger than {0} bytes.")]
+    TooLarge(u64),
 }
 
 /// Custom Result type for KVStore.
 pub type Result<T> = std::result::Result<T, KVStoreError>;
+
+// Keys are arbitrary bytes, errors show them as UTF-8, replacing any invalid sequences.
+pub(crate) fn display_key(key: &[u8]) -> String {
+    String::from_utf8_lossy(key).into_owned()
+}
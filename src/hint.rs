@@ -0,0 +1,240 @@
+use crate::{record, KVStoreError, Result};
+use std::{
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+};
+
+// A hint file is written alongside a closed segment, so that the index can be rebuilt
+// from it without reading every action in the segment.
+//
+// It starts with the magic bytes followed by its version (u16, little endian), then
+// contains framed records: the first one holds the number of stale bytes in the
+// segment and the number of entries in the file (both u64, little endian), each of the
+// others holds the state of a key as of the end of the segment. Files that don't hold as
+// many entries as they should, e.g. because they were cut short at the end of a record,
+// are rejected, so that the segment is scanned instead.
+const HINT_MAGIC: &[u8; 6] = b"KVSHNT";
+const HINT_VERSION: u16 = 3;
+
+const ENTRY_SET: u8 = 1;
+const ENTRY_REMOVED: u8 = 2;
+
+// State of a key as of the end of a segment.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum HintEntry {
+    // The latest action for the key is the set action at this offset in the segment,
+    // along with the time at which the key expires and the version of the key.
+    Set {
+        pos: u64,
+        len: u64,
+        expires_at: Option<u64>,
+        version: u64,
+    },
+    // The key was removed.
+    Removed,
+}
+
+// The state of every key touched in a segment, along with the number of bytes in the
+// segment which belong to actions that are no longer live as of its end.
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct Hint {
+    pub(crate) entries: Vec<(Vec<u8>, HintEntry)>,
+    pub(crate) stale: u64,
+}
+
+// Path of the hint file for the segment with the given generation.
+pub(crate) fn hint_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{}.hint", gen))
+}
+
+// Write the hint file for the segment with the given generation. The file is written
+// under a temporary name and only renamed once it's complete, then the directory is synced
+// so that the rename isn't lost.
+pub(crate) fn write_hint(dir: &Path, gen: u64, hint: &Hint) -> Result<()> {
+    let tmp_path = dir.join(format!("{}.hint.tmp", gen));
+    let mut writer = BufWriter::new(File::create(&tmp_path)?);
+    writer.write_all(HINT_MAGIC)?;
+    writer.write_all(&HINT_VERSION.to_le_bytes())?;
+    let mut counts = hint.stale.to_le_bytes().to_vec();
+    counts.extend_from_slice(&(hint.entries.len() as u64).to_le_bytes());
+    record::write_record(&mut writer, &counts)?;
+    for (key, entry) in &hint.entries {
+        record::write_record(&mut writer, &encode_entry(key, entry))?;
+    }
+    let hint_file = writer.into_inner().map_err(io::Error::from)?;
+    hint_file.sync_all()?;
+    fs::rename(tmp_path, hint_path(dir, gen))?;
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+// Read the hint file for the segment with the given generation.
+// Returns None if the segment has no hint file.
+pub(crate) fn read_hint(dir: &Path, gen: u64) -> Result<Option<Hint>> {
+    let hint_file = match File::open(hint_path(dir, gen)) {
+        Ok(hint_file) => hint_file,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    let mut reader = BufReader::new(hint_file);
+    let mut header = [0; 8];
+    reader.read_exact(&mut header)?;
+    if &header[..6] != HINT_MAGIC || header[6..] != HINT_VERSION.to_le_bytes() {
+        return Err(KVStoreError::Corrupt);
+    }
+    let (stale, count) = match record::read_record(&mut reader)? {
+        Some(payload) if payload.len() == 16 => {
+            (u64_from(&payload[..8])?, u64_from(&payload[8..])?)
+        }
+        _ => return Err(KVStoreError::Corrupt),
+    };
+    let mut entries = vec![];
+    while let Some(payload) = record::read_record(&mut reader)? {
+        entries.push(decode_entry(&payload)?);
+    }
+    if entries.len() as u64 != count {
+        return Err(KVStoreError::Corrupt);
+    }
+    Ok(Some(Hint { entries, stale }))
+}
+
+// Remove the hint file for the segment with the given generation, if there's one.
+pub(crate) fn remove_hint(dir: &Path, gen: u64) -> Result<()> {
+    match fs::remove_file(hint_path(dir, gen)) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err.into()),
+        _ => Ok(()),
+    }
+}
+
+// Encode an entry as: its type (u8), the key prefixed with its length (u32, little endian)
+// and for set entries, the offset and the length of the action, the version of the key
+// and the expiry time, 0 if the key never expires (all u64, little endian).
+fn encode_entry(key: &[u8], entry: &HintEntry) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(37 + key.len());
+    match entry {
+        HintEntry::Set { .. } => buf.push(ENTRY_SET),
+        HintEntry::Removed => buf.push(ENTRY_REMOVED),
+    }
+    buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+    buf.extend_from_slice(key);
+    if let HintEntry::Set {
+        pos,
+        len,
+        expires_at,
+        version,
+    } = entry
+    {
+        buf.extend_from_slice(&pos.to_le_bytes());
+        buf.extend_from_slice(&len.to_le_bytes());
+        buf.extend_from_slice(&version.to_le_bytes());
+        buf.extend_from_slice(&expires_at.unwrap_or(0).to_le_bytes());
+    }
+    buf
+}
+
+fn decode_entry(payload: &[u8]) -> Result<(Vec<u8>, HintEntry)> {
+    if payload.len() < 5 {
+        return Err(KVStoreError::Corrupt);
+    }
+    let mut key_len = [0; 4];
+    key_len.copy_from_slice(&payload[1..5]);
+    let key_end = 5 + u32::from_le_bytes(key_len) as usize;
+    if payload.len() < key_end {
+        return Err(KVStoreError::Corrupt);
+    }
+    let key = payload[5..key_end].to_vec();
+    let rest = &payload[key_end..];
+    let entry = match payload[0] {
+        ENTRY_SET if rest.len() == 32 => HintEntry::Set {
+            pos: u64_from(&rest[..8])?,
+            len: u64_from(&rest[8..16])?,
+            version: u64_from(&rest[16..24])?,
+            expires_at: Some(u64_from(&rest[24..])?).filter(|&expires_at| expires_at != 0),
+        },
+        ENTRY_REMOVED if rest.is_empty() => HintEntry::Removed,
+        _ => return Err(KVStoreError::Corrupt),
+    };
+    Ok((key, entry))
+}
+
+fn u64_from(bytes: &[u8]) -> Result<u64> {
+    if bytes.len() != 8 {
+        return Err(KVStoreError::Corrupt);
+    }
+    let mut buf = [0; 8];
+    buf.copy_from_slice(bytes);
+    Ok(u64::from_le_bytes(buf))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::Rng;
+
+    #[test]
+    fn test_write_read_hint() {
+        let n: u32 = rand::thread_rng().gen();
+        let dir = PathBuf::from(format!("/tmp/kvs-hint-{}", n));
+        fs::create_dir_all(&dir).unwrap();
+        let hint = Hint {
+            entries: vec![
+                (b"gone".to_vec(), HintEntry::Removed),
+                (
+                    b"expiring".to_vec(),
+                    HintEntry::Set {
+                        pos: 38,
+                        len: 30,
+                        expires_at: Some(1_634_000_000_000),
+                        version: 3,
+                    },
+                ),
+                (
+                    vec![0, 159, 146, 150],
+                    HintEntry::Set {
+                        pos: 8,
+                        len: 30,
+                        expires_at: None,
+                        version: record::FIRST_VERSION,
+                    },
+                ),
+            ],
+            stale: 42,
+        };
+        write_hint(&dir, 1, &hint).unwrap();
+        let read = read_hint(&dir, 1).unwrap();
+        let missing = read_hint(&dir, 2).unwrap();
+        // Hint files in another version are rejected, so that the segment is scanned instead.
+        let mut other_version = fs::read(hint_path(&dir, 1)).unwrap();
+        other_version[6..8].copy_from_slice(&(HINT_VERSION - 1).to_le_bytes());
+        fs::write(hint_path(&dir, 3), other_version).unwrap();
+        let outdated = read_hint(&dir, 3);
+        // Truncated hint files must not be trusted.
+        let len = fs::metadata(hint_path(&dir, 1)).unwrap().len();
+        fs::OpenOptions::new()
+            .write(true)
+            .open(hint_path(&dir, 1))
+            .unwrap()
+            .set_len(len - 1)
+            .unwrap();
+        let truncated = read_hint(&dir, 1);
+        // Even if they're cut short at the end of a record.
+        write_hint(&dir, 4, &hint).unwrap();
+        let (key, entry) = hint.entries.last().unwrap();
+        let last_len = record::HEADER_LEN + encode_entry(key, entry).len();
+        fs::OpenOptions::new()
+            .write(true)
+            .open(hint_path(&dir, 4))
+            .unwrap()
+            .set_len(len - last_len as u64)
+            .unwrap();
+        let missing_entry = read_hint(&dir, 4);
+        fs::remove_dir_all(dir).unwrap();
+
+        assert_eq!(read, Some(hint));
+        assert_eq!(missing, None);
+        assert!(truncated.is_err());
+        assert!(matches!(missing_entry, Err(KVStoreError::Corrupt)));
+        assert!(outdated.is_err());
+    }
+}
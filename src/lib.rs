@@ -1,24 +1,46 @@
+pub mod bulk;
+mod cache;
+pub mod engines;
 mod error;
+mod hint;
+pub mod metrics;
+mod record;
 pub mod store;
+pub mod watch;
+pub use engines::{Buckets, EngineKind, KvsEngine, WriteOp};
 pub use error::{KVStoreError, Result};
-pub use store::KVStore;
+pub use store::{Durability, KVStore, KVStoreConfig, Transaction};
 pub mod models;
 pub mod pubsub;
 
+use std::path::{Component, Path, PathBuf};
+
 #[derive(Debug, Clone, Default)]
 pub struct ConnStrings {
     server_host: String,
     nats_host: String,
-    log_file_path: String,
+    data_dir: String,
+    compaction_threshold: u64,
+    segment_size: u64,
+    durability: Durability,
+    engine: EngineKind,
+    cache_size: u64,
+    watch_history: u64,
+    legacy_log: Option<String>,
+    backup_dir: String,
 }
 
 const SERVER_HOST: &str = "http://127.0.0.1:8000";
 const NATS_HOST: &str = "127.0.0.1:4444";
+const DATA_DIR: &str = "kvs-data";
+const BACKUP_DIR: &str = "kvs-backups";
+// Where the log used to be kept, before it was split into segments in the data directory.
 const LOG_FILE_PATH: &str = "kvs.log";
 
 impl ConnStrings {
-    // Try to load the strings from environment. Use specified defaults if not found.
-    pub fn load() -> Self {
+    // Try to load the strings from environment. Use specified defaults if not found, but fail
+    // on settings that are set to invalid values.
+    pub fn load() -> Result<Self> {
         dotenv::dotenv().ok();
         let mut server_host = String::from(SERVER_HOST);
         if let Ok(val) = std::env::var("KVSTORE_SERVER_HOST") {
@@ -28,15 +50,58 @@ impl ConnStrings {
         if let Ok(val) = std::env::var("KVSTORE_NATS_HOST") {
             nats_host = val;
         }
-        let mut log_file_path = String::from(LOG_FILE_PATH);
+        let mut data_dir = String::from(DATA_DIR);
+        if let Ok(val) = std::env::var("KVSTORE_DATA_DIR") {
+            data_dir = val;
+        }
+        let mut compaction_threshold = store::DEFAULT_COMPACTION_THRESHOLD;
+        if let Ok(val) = std::env::var("KVSTORE_COMPACTION_THRESHOLD") {
+            compaction_threshold = parse_size("KVSTORE_COMPACTION_THRESHOLD", val)?;
+        }
+        let mut segment_size = store::DEFAULT_SEGMENT_SIZE;
+        if let Ok(val) = std::env::var("KVSTORE_SEGMENT_SIZE") {
+            segment_size = parse_size("KVSTORE_SEGMENT_SIZE", val)?;
+        }
+        let mut durability = Durability::default();
+        if let Ok(val) = std::env::var("KVSTORE_DURABILITY") {
+            durability = val.parse()?;
+        }
+        let mut engine = EngineKind::default();
+        if let Ok(val) = std::env::var("KVSTORE_ENGINE") {
+            engine = val.parse()?;
+        }
+        let mut cache_size = store::DEFAULT_CACHE_SIZE;
+        if let Ok(val) = std::env::var("KVSTORE_CACHE_SIZE") {
+            cache_size = parse_size("KVSTORE_CACHE_SIZE", val)?;
+        }
+        let mut watch_history = watch::DEFAULT_HISTORY_SIZE;
+        if let Ok(val) = std::env::var("KVSTORE_WATCH_HISTORY") {
+            watch_history = parse_size("KVSTORE_WATCH_HISTORY", val)?;
+        }
+        // A log left behind by an older version is imported into the data directory.
+        let mut legacy_log = None;
         if let Ok(val) = std::env::var("KVSTORE_LOG_FILE_PATH") {
-            log_file_path = val;
+            legacy_log = Some(val);
+        } else if Path::new(LOG_FILE_PATH).is_file() {
+            legacy_log = Some(String::from(LOG_FILE_PATH));
+        }
+        let mut backup_dir = String::from(BACKUP_DIR);
+        if let Ok(val) = std::env::var("KVSTORE_BACKUP_DIR") {
+            backup_dir = val;
         }
-        ConnStrings {
+        Ok(ConnStrings {
             server_host,
             nats_host,
-            log_file_path,
-        }
+            data_dir,
+            compaction_threshold,
+            segment_size,
+            durability,
+            engine,
+            cache_size,
+            watch_history,
+            legacy_log,
+            backup_dir,
+        })
     }
 
     pub fn server_host(&self) -> String {
@@ -47,7 +112,118 @@ impl ConnStrings {
         self.nats_host.clone()
     }
 
-    pub fn log_file_path(&self) -> String {
-        self.log_file_path.clone()
+    pub fn data_dir(&self) -> String {
+        self.data_dir.clone()
+    }
+
+    pub fn compaction_threshold(&self) -> u64 {
+        self.compaction_threshold
+    }
+
+    pub fn segment_size(&self) -> u64 {
+        self.segment_size
+    }
+
+    pub fn durability(&self) -> Durability {
+        self.durability
+    }
+
+    pub fn engine(&self) -> EngineKind {
+        self.engine
+    }
+
+    pub fn cache_size(&self) -> u64 {
+        self.cache_size
+    }
+
+    pub fn watch_history(&self) -> u64 {
+        self.watch_history
+    }
+
+    pub fn legacy_log(&self) -> Option<String> {
+        self.legacy_log.clone()
+    }
+
+    // Directory that snapshots are written to and restored from.
+    pub fn backup_dir(&self) -> String {
+        self.backup_dir.clone()
+    }
+
+    // Config to open the KVStore with.
+    pub fn store_config(&self) -> KVStoreConfig {
+        KVStoreConfig {
+            compaction_threshold: self.compaction_threshold,
+            segment_size: self.segment_size,
+            durability: self.durability,
+            cache_size: self.cache_size,
+            legacy_log: self.legacy_log.as_ref().map(PathBuf::from),
+        }
+    }
+}
+
+// Parse the number of bytes a setting is set to.
+fn parse_size(name: &str, val: String) -> Result<u64> {
+    val.parse()
+        .map_err(|_| KVStoreError::InvalidSetting(name.to_string(), val))
+}
+
+/// Resolves the path to a snapshot, as given by a client, within the backup directory.
+/// Relative paths are taken as relative to the backup directory, while absolute paths have
+/// to be within it. Returns an InvalidPath error for any other path, or one with `..` in it.
+pub fn backup_path(backup_dir: &Path, path: &str) -> Result<PathBuf> {
+    let invalid = || KVStoreError::InvalidPath(path.to_string());
+    let rel = Path::new(path);
+    let rel = if rel.is_absolute() {
+        rel.strip_prefix(backup_dir).map_err(|_| invalid())?
+    } else {
+        rel
+    };
+    let mut resolved = backup_dir.to_path_buf();
+    for component in rel.components() {
+        match component {
+            Component::Normal(name) => resolved.push(name),
+            Component::CurDir => {}
+            _ => return Err(invalid()),
+        }
+    }
+    // The backup directory itself holds all the snapshots, it isn't one.
+    if resolved == backup_dir {
+        return Err(invalid());
+    }
+    Ok(resolved)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_backup_path() {
+        let backup_dir = Path::new("/backups");
+        for &(path, resolved) in &[
+            ("today", "/backups/today"),
+            ("./users/today", "/backups/users/today"),
+            ("/backups/today", "/backups/today"),
+        ] {
+            assert_eq!(
+                backup_path(backup_dir, path).unwrap(),
+                PathBuf::from(resolved)
+            );
+        }
+        for path in &[
+            "",
+            ".",
+            "../today",
+            "users/../../today",
+            "/backups",
+            "/backups/../etc",
+            "/etc",
+            "/backupsx/today",
+        ] {
+            assert!(matches!(
+                backup_path(backup_dir, path),
+                Err(KVStoreError::InvalidPath(_))
+            ));
+        }
     }
 }
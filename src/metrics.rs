@@ -0,0 +1,405 @@
+use crate::{engines::EngineStats, KVStoreError, Result};
+use std::{
+    collections::BTreeMap,
+    fs,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+// Upper bounds of the buckets of the histogram of request durations, in seconds.
+const DURATION_BUCKETS: [f64; 12] = [
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0,
+];
+
+// CPU times in /proc are in clock ticks, of which there are 100 a second on Linux.
+const CLOCK_TICKS_PER_SECOND: f64 = 100.0;
+
+/// Metrics about the requests served by the server and the changes it published to NATS,
+/// rendered in the Prometheus text format along with the stats of the buckets and of the
+/// process.
+#[derive(Default)]
+pub struct Metrics {
+    // Keyed by the method and the route.
+    requests: Mutex<BTreeMap<(String, String), RequestStats>>,
+    published: AtomicU64,
+    publish_failures: AtomicU64,
+}
+
+#[derive(Default)]
+struct RequestStats {
+    // Number of responses with each status.
+    statuses: BTreeMap<u16, u64>,
+    // Number of requests which took longer than the previous bound, up to the one at the
+    // same index in DURATION_BUCKETS. Summed up when rendered, since Prometheus buckets are
+    // cumulative.
+    buckets: [u64; DURATION_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Metrics {
+    /// Records a request to a route, the name of its handler, e.g. `get_key`, along with the
+    /// status of the response and the time it took to respond.
+    pub fn observe_request(
+        &self,
+        method: &str,
+        route: &str,
+        status: u16,
+        elapsed: Duration,
+    ) -> Result<()> {
+        let mut requests = self.requests.lock().map_err(|_| KVStoreError::Lock)?;
+        let stats = requests
+            .entry((method.to_string(), route.to_string()))
+            .or_default();
+        *stats.statuses.entry(status).or_default() += 1;
+        let secs = elapsed.as_secs_f64();
+        if let Some(i) = DURATION_BUCKETS.iter().position(|&bound| secs <= bound) {
+            stats.buckets[i] += 1;
+        }
+        stats.sum += secs;
+        stats.count += 1;
+        Ok(())
+    }
+
+    /// Records whether a change was published to NATS.
+    pub fn observe_publish(&self, published: bool) {
+        let counter = if published {
+            &self.published
+        } else {
+            &self.publish_failures
+        };
+        counter.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Renders the metrics, along with the stats of the buckets, given by name, None being
+    /// the default bucket, and the stats of the process, if they're available.
+    pub fn render(&self, buckets: &[(Option<String>, EngineStats)]) -> Result<String> {
+        let mut out = String::new();
+        {
+            let requests = self.requests.lock().map_err(|_| KVStoreError::Lock)?;
+            header(
+                &mut out,
+                "kvstore_http_requests_total",
+                "counter",
+                "Number of HTTP requests served, by route and status.",
+            );
+            for ((method, route), stats) in requests.iter() {
+                for (status, count) in &stats.statuses {
+                    let status = status.to_string();
+                    let labels = [
+                        ("method", &method[..]),
+                        ("route", route),
+                        ("status", &status),
+                    ];
+                    sample(&mut out, "kvstore_http_requests_total", &labels, *count);
+                }
+            }
+            header(
+                &mut out,
+                "kvstore_http_request_duration_seconds",
+                "histogram",
+                "Time taken to respond to HTTP requests, by route.",
+            );
+            for ((method, route), stats) in requests.iter() {
+                let mut cumulative = 0;
+                for (bound, count) in DURATION_BUCKETS.iter().zip(&stats.buckets) {
+                    cumulative += count;
+                    let bound = bound.to_string();
+                    let labels = [("method", &method[..]), ("route", route), ("le", &bound)];
+                    sample(
+                        &mut out,
+                        "kvstore_http_request_duration_seconds_bucket",
+                        &labels,
+                        cumulative,
+                    );
+                }
+                let labels = [("method", &method[..]), ("route", route), ("le", "+Inf")];
+                sample(
+                    &mut out,
+                    "kvstore_http_request_duration_seconds_bucket",
+                    &labels,
+                    stats.count,
+                );
+                let labels = [("method", &method[..]), ("route", route)];
+                sample(
+                    &mut out,
+                    "kvstore_http_request_duration_seconds_sum",
+                    &labels,
+                    stats.sum,
+                );
+                sample(
+                    &mut out,
+                    "kvstore_http_request_duration_seconds_count",
+                    &labels,
+                    stats.count,
+                );
+            }
+        }
+
+        header(
+            &mut out,
+            "kvstore_nats_publishes_total",
+            "counter",
+            "Number of changes published to NATS, by result.",
+        );
+        let published = self.published.load(Ordering::SeqCst);
+        let failures = self.publish_failures.load(Ordering::SeqCst);
+        for &(result, count) in &[("success", published), ("failure", failures)] {
+            sample(
+                &mut out,
+                "kvstore_nats_publishes_total",
+                &[("result", result)],
+                count,
+            );
+        }
+
+        type Stat = fn(&EngineStats) -> u64;
+        let bucket_stats: [(&str, &str, &str, Stat); 7] = [
+            (
+                "kvstore_live_keys",
+                "gauge",
+                "Number of live keys.",
+                |stats| stats.live_keys,
+            ),
+            (
+                "kvstore_disk_bytes",
+                "gauge",
+                "Size of the data on disk.",
+                |stats| stats.disk_bytes,
+            ),
+            (
+                "kvstore_stale_bytes",
+                "gauge",
+                "Size of the stale data in the log, reclaimed by compactions.",
+                |stats| stats.stale_bytes,
+            ),
+            (
+                "kvstore_compactions_total",
+                "counter",
+                "Number of compactions run.",
+                |stats| stats.compactions,
+            ),
+            (
+                "kvstore_cache_hits_total",
+                "counter",
+                "Number of reads served from the value cache.",
+                |stats| stats.cache_hits,
+            ),
+            (
+                "kvstore_cache_misses_total",
+                "counter",
+                "Number of reads missed by the value cache.",
+                |stats| stats.cache_misses,
+            ),
+            (
+                "kvstore_cache_bytes",
+                "gauge",
+                "Size of the keys and values in the value cache.",
+                |stats| stats.cache_bytes,
+            ),
+        ];
+        for (name, kind, help, stat) in &bucket_stats {
+            header(&mut out, name, kind, help);
+            for (bucket, stats) in buckets {
+                // The default bucket has an empty name, which is the same as no name at all
+                // to Prometheus.
+                let labels = [
+                    ("bucket", bucket.as_deref().unwrap_or("")),
+                    ("engine", &stats.engine[..]),
+                ];
+                sample(&mut out, name, &labels, stat(stats));
+            }
+        }
+        header(
+            &mut out,
+            "kvstore_buckets",
+            "gauge",
+            "Number of buckets, including the default one.",
+        );
+        sample(&mut out, "kvstore_buckets", &[], buckets.len());
+
+        if let Some(process) = process_stats() {
+            header(
+                &mut out,
+                "process_cpu_seconds_total",
+                "counter",
+                "Total user and system CPU time spent in seconds.",
+            );
+            sample(
+                &mut out,
+                "process_cpu_seconds_total",
+                &[],
+                process.cpu_seconds,
+            );
+            header(
+                &mut out,
+                "process_resident_memory_bytes",
+                "gauge",
+                "Resident memory size in bytes.",
+            );
+            sample(
+                &mut out,
+                "process_resident_memory_bytes",
+                &[],
+                process.resident_bytes,
+            );
+            header(
+                &mut out,
+                "process_virtual_memory_bytes",
+                "gauge",
+                "Virtual memory size in bytes.",
+            );
+            sample(
+                &mut out,
+                "process_virtual_memory_bytes",
+                &[],
+                process.virtual_bytes,
+            );
+            header(
+                &mut out,
+                "process_open_fds",
+                "gauge",
+                "Number of open file descriptors.",
+            );
+            sample(&mut out, "process_open_fds", &[], process.open_fds);
+            header(
+                &mut out,
+                "process_start_time_seconds",
+                "gauge",
+                "Start time of the process since unix epoch in seconds.",
+            );
+            sample(
+                &mut out,
+                "process_start_time_seconds",
+                &[],
+                process.start_time,
+            );
+        }
+        Ok(out)
+    }
+}
+
+// Writes the help and the type of a metric, which come before its samples.
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    out.push_str(&format!(
+        "# HELP {} {}\n# TYPE {} {}\n",
+        name, help, name, kind
+    ));
+}
+
+// Writes a sample of a metric, with its labels, if there are any. Label values are quoted,
+// with backslashes, quotes and line breaks escaped.
+fn sample(out: &mut String, name: &str, labels: &[(&str, &str)], value: impl ToString) {
+    out.push_str(name);
+    if !labels.is_empty() {
+        let labels: Vec<String> = labels
+            .iter()
+            .map(|(label, value)| {
+                let value = value
+                    .replace('\\', "\\\\")
+                    .replace('"', "\\\"")
+                    .replace('\n', "\\n");
+                format!("{}=\"{}\"", label, value)
+            })
+            .collect();
+        out.push_str(&format!("{{{}}}", labels.join(",")));
+    }
+    out.push_str(&format!(" {}\n", value.to_string()));
+}
+
+// Stats of the process, the same as the ones reported by the official Prometheus clients.
+struct ProcessStats {
+    cpu_seconds: f64,
+    resident_bytes: u64,
+    virtual_bytes: u64,
+    open_fds: usize,
+    start_time: f64,
+}
+
+// Reads the stats of the process from /proc. Returns None if they can't be read, e.g.
+// since /proc is only available on Linux.
+fn process_stats() -> Option<ProcessStats> {
+    let stat = fs::read_to_string("/proc/self/stat").ok()?;
+    // The name of the command is in parentheses and can have spaces in it, the fields after
+    // it start with the third one, the state of the process.
+    let fields: Vec<&str> = stat.get(stat.rfind(')')? + 2..)?.split(' ').collect();
+    let field = |n: usize| fields.get(n - 3)?.parse::<u64>().ok();
+    let cpu_ticks = field(14)? + field(15)?;
+    let start_ticks = field(22)?;
+    let virtual_bytes = field(23)?;
+
+    let boot_time: u64 = fs::read_to_string("/proc/stat")
+        .ok()?
+        .lines()
+        .find_map(|line| line.strip_prefix("btime "))?
+        .trim()
+        .parse()
+        .ok()?;
+    let resident_kib: u64 = fs::read_to_string("/proc/self/status")
+        .ok()?
+        .lines()
+        .find_map(|line| line.strip_prefix("VmRSS:"))?
+        .trim()
+        .trim_end_matches("kB")
+        .trim()
+        .parse()
+        .ok()?;
+    let open_fds = fs::read_dir("/proc/self/fd").ok()?.count();
+
+    Some(ProcessStats {
+        cpu_seconds: cpu_ticks as f64 / CLOCK_TICKS_PER_SECOND,
+        resident_bytes: resident_kib * 1024,
+        virtual_bytes,
+        open_fds,
+        start_time: boot_time as f64 + start_ticks as f64 / CLOCK_TICKS_PER_SECOND,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::default();
+        let ms = Duration::from_millis;
+        metrics.observe_request("GET", "get", 200, ms(3)).unwrap();
+        metrics.observe_request("GET", "get", 404, ms(30)).unwrap();
+        metrics
+            .observe_request("GET", "get", 200, ms(10_000))
+            .unwrap();
+        metrics.observe_publish(true);
+        metrics.observe_publish(false);
+        metrics.observe_publish(true);
+        let stats = EngineStats {
+            engine: String::from("kvs"),
+            live_keys: 2,
+            compactions: 1,
+            ..EngineStats::default()
+        };
+        let buckets = vec![(None, stats.clone()), (Some(String::from("a\"b")), stats)];
+
+        let text = metrics.render(&buckets).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        for line in &[
+            "# TYPE kvstore_http_requests_total counter",
+            "kvstore_http_requests_total{method=\"GET\",route=\"get\",status=\"200\"} 2",
+            "kvstore_http_requests_total{method=\"GET\",route=\"get\",status=\"404\"} 1",
+            "kvstore_http_request_duration_seconds_bucket{method=\"GET\",route=\"get\",le=\"0.001\"} 0",
+            "kvstore_http_request_duration_seconds_bucket{method=\"GET\",route=\"get\",le=\"0.005\"} 1",
+            "kvstore_http_request_duration_seconds_bucket{method=\"GET\",route=\"get\",le=\"5\"} 2",
+            "kvstore_http_request_duration_seconds_bucket{method=\"GET\",route=\"get\",le=\"+Inf\"} 3",
+            "kvstore_http_request_duration_seconds_count{method=\"GET\",route=\"get\"} 3",
+            "kvstore_nats_publishes_total{result=\"success\"} 2",
+            "kvstore_nats_publishes_total{result=\"failure\"} 1",
+            "kvstore_live_keys{bucket=\"\",engine=\"kvs\"} 2",
+            "kvstore_compactions_total{bucket=\"a\\\"b\",engine=\"kvs\"} 1",
+            "kvstore_buckets 2",
+        ] {
+            assert!(lines.contains(line), "{} is missing from:\n{}", line, text);
+        }
+    }
+}
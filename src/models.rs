@@ -1,5 +1,67 @@
+use crate::{
+    engines::SnapshotInfo,
+    watch::{Change, Event},
+    WriteOp,
+};
 use rocket::serde::{Deserialize, Serialize};
-use std::fmt;
+use std::{collections::BTreeMap, fmt};
+
+/// How the keys and values in a payload are encoded.
+#[derive(Serialize, Deserialize, rocket::FromFormField, Debug, Clone, Copy, PartialEq)]
+#[serde(crate = "rocket::serde", rename_all = "lowercase")]
+pub enum Encoding {
+    /// As UTF-8 strings.
+    Utf8,
+    /// As base64 strings, which works for arbitrary bytes.
+    Base64,
+}
+
+impl Default for Encoding {
+    fn default() -> Self {
+        Encoding::Utf8
+    }
+}
+
+impl Encoding {
+    pub(crate) fn is_utf8(&self) -> bool {
+        *self == Encoding::Utf8
+    }
+
+    /// Decodes a key or a value sent in this encoding.
+    pub fn decode(self, s: &str) -> crate::Result<Vec<u8>> {
+        match self {
+            Encoding::Utf8 => Ok(s.as_bytes().to_vec()),
+            Encoding::Base64 => Ok(base64::decode(s)?),
+        }
+    }
+
+    /// Encodes a key or a value to be sent in this encoding. Bytes which aren't valid UTF-8
+    /// are replaced when encoding them as UTF-8, use `fit` to pick the encoding beforehand.
+    pub fn encode(self, bytes: &[u8]) -> String {
+        match self {
+            Encoding::Utf8 => String::from_utf8_lossy(bytes).into_owned(),
+            Encoding::Base64 => base64::encode(bytes),
+        }
+    }
+
+    /// Returns the encoding which the keys and values can be sent in: this one, unless
+    /// some of them are not valid UTF-8, in which case it's base64.
+    pub fn fit<'a>(self, all: impl IntoIterator<Item = &'a [u8]>) -> Encoding {
+        let mut all = all.into_iter();
+        if self.is_utf8() && all.any(|bytes| std::str::from_utf8(bytes).is_err()) {
+            return Encoding::Base64;
+        }
+        self
+    }
+}
+
+// Suffix added when displaying payloads which aren't encoded as UTF-8.
+fn encoding_suffix(encoding: Encoding) -> &'static str {
+    match encoding {
+        Encoding::Utf8 => "",
+        Encoding::Base64 => ", encoding: base64",
+    }
+}
 
 // Represents the payload for a Set action.
 #[derive(Serialize, Deserialize)]
@@ -7,11 +69,27 @@ use std::fmt;
 pub struct SetItem {
     pub key: String,
     pub val: String,
+    // Number of seconds after which the key expires.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ttl: Option<u64>,
+    // Only set the key if it doesn't exist.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub if_absent: bool,
+    // Only set the key if it's at this version.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub if_version: Option<u64>,
+    // How the key and the value are encoded.
+    #[serde(default, skip_serializing_if = "Encoding::is_utf8")]
+    pub encoding: Encoding,
 }
 
 impl fmt::Display for SetItem {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{{key: {}, val: {}}}", self.key, self.val)
+        if let Some(ttl) = self.ttl {
+            write!(f, "{{key: {}, val: {}, ttl: {}}}", self.key, self.val, ttl)
+        } else {
+            write!(f, "{{key: {}, val: {}}}", self.key, self.val)
+        }
     }
 }
 
@@ -20,6 +98,12 @@ impl fmt::Display for SetItem {
 #[serde(crate = "rocket::serde")]
 pub struct RmItem {
     pub key: String,
+    // Only remove the key if it's at this version.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub if_version: Option<u64>,
+    // How the key is encoded.
+    #[serde(default, skip_serializing_if = "Encoding::is_utf8")]
+    pub encoding: Encoding,
 }
 
 impl fmt::Display for RmItem {
@@ -28,30 +112,312 @@ impl fmt::Display for RmItem {
     }
 }
 
+// Represents the payload for an Incr or a Decr action.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct IncrItem {
+    pub key: String,
+    // Amount to add to, or subtract from, the counter.
+    #[serde(default = "default_delta")]
+    pub delta: i64,
+    // How the key is encoded.
+    #[serde(default, skip_serializing_if = "Encoding::is_utf8")]
+    pub encoding: Encoding,
+}
+
+fn default_delta() -> i64 {
+    1
+}
+
+impl fmt::Display for IncrItem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{{key: {}, delta: {}}}", self.key, self.delta)
+    }
+}
+
+// An operation in a batch.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde", tag = "op", rename_all = "lowercase")]
+pub enum BatchOp {
+    Get { key: String },
+    Set { key: String, val: String },
+    Rm { key: String },
+}
+
+// Represents the payload for a batch of operations.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct BatchItem {
+    pub ops: Vec<BatchOp>,
+    // How the keys and the values are encoded.
+    #[serde(default, skip_serializing_if = "Encoding::is_utf8")]
+    pub encoding: Encoding,
+}
+
+// Represents the payload for a Backup action.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct BackupItem {
+    // Directory on the server to write the snapshot to.
+    pub path: String,
+}
+
+impl fmt::Display for BackupItem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{{path: {}}}", self.path)
+    }
+}
+
+// Represents the payload for a Restore action.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct RestoreItem {
+    // Directory on the server to read the snapshot from.
+    pub path: String,
+}
+
+impl fmt::Display for RestoreItem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{{path: {}}}", self.path)
+    }
+}
+
 // Response body returned while trying to perform get.
 #[derive(Serialize, Deserialize)]
 #[serde(crate = "rocket::serde")]
 pub struct GetBody {
     found: bool,
     val: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    version: Option<u64>,
+    #[serde(default, skip_serializing_if = "Encoding::is_utf8")]
+    encoding: Encoding,
 }
 
-impl From<(bool, Option<String>)> for GetBody {
-    fn from(body: (bool, Option<String>)) -> Self {
+impl From<(bool, Option<Vec<u8>>, Encoding)> for GetBody {
+    fn from(body: (bool, Option<Vec<u8>>, Encoding)) -> Self {
+        GetBody::from((body.0, body.1, None, body.2))
+    }
+}
+
+impl From<(bool, Option<Vec<u8>>, Option<u64>, Encoding)> for GetBody {
+    fn from(body: (bool, Option<Vec<u8>>, Option<u64>, Encoding)) -> Self {
+        let encoding = body.3.fit(body.1.as_deref());
         GetBody {
             found: body.0,
-            val: body.1,
+            val: body.1.map(|val| encoding.encode(&val)),
+            version: body.2,
+            encoding,
         }
     }
 }
 
 impl fmt::Display for GetBody {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        if let Some(val) = &self.val {
-            write!(f, "{{found: {}, val: {}}}", self.found, val)
+        match (&self.val, self.version) {
+            (Some(val), Some(version)) => write!(
+                f,
+                "{{found: {}, val: {}, version: {}",
+                self.found, val, version
+            )?,
+            (Some(val), None) => write!(f, "{{found: {}, val: {}", self.found, val)?,
+            (None, _) => write!(f, "{{found: {}, val: null", self.found)?,
+        }
+        write!(f, "{}}}", encoding_suffix(self.encoding))
+    }
+}
+
+// A write in a transaction.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde", tag = "op", rename_all = "lowercase")]
+pub enum TxnOp {
+    Set { key: String, val: String },
+    Rm { key: String },
+}
+
+// Represents the payload for a transaction.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct TxnItem {
+    pub ops: Vec<TxnOp>,
+    // How the keys and the values are encoded.
+    #[serde(default, skip_serializing_if = "Encoding::is_utf8")]
+    pub encoding: Encoding,
+}
+
+impl TxnItem {
+    /// Decodes the writes in the transaction.
+    pub fn write_ops(&self) -> crate::Result<Vec<WriteOp>> {
+        self.ops
+            .iter()
+            .map(|op| match op {
+                TxnOp::Set { key, val } => Ok(WriteOp::Set {
+                    key: self.encoding.decode(key)?,
+                    val: self.encoding.decode(val)?,
+                }),
+                TxnOp::Rm { key } => Ok(WriteOp::Rm {
+                    key: self.encoding.decode(key)?,
+                }),
+            })
+            .collect()
+    }
+}
+
+// Response body returned while trying to commit a transaction.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct TxnBody {
+    committed: bool,
+    ejected_vals: Vec<Option<String>>,
+    #[serde(default, skip_serializing_if = "Encoding::is_utf8")]
+    encoding: Encoding,
+}
+
+impl From<(bool, Vec<Option<Vec<u8>>>, Encoding)> for TxnBody {
+    fn from(body: (bool, Vec<Option<Vec<u8>>>, Encoding)) -> Self {
+        let encoding = body.2.fit(body.1.iter().flatten().map(Vec::as_slice));
+        TxnBody {
+            committed: body.0,
+            ejected_vals: body
+                .1
+                .iter()
+                .map(|val| val.as_ref().map(|val| encoding.encode(val)))
+                .collect(),
+            encoding,
+        }
+    }
+}
+
+impl fmt::Display for TxnBody {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let vals: Vec<&str> = self
+            .ejected_vals
+            .iter()
+            .map(|val| val.as_deref().unwrap_or("null"))
+            .collect();
+        write!(
+            f,
+            "{{committed: {}, ejected_vals: [{}]{}}}",
+            self.committed,
+            vals.join(", "),
+            encoding_suffix(self.encoding)
+        )
+    }
+}
+
+// Response body returned while trying to get the remaining TTL of a key, in seconds.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct TtlBody {
+    found: bool,
+    ttl: Option<u64>,
+}
+
+impl From<(bool, Option<u64>)> for TtlBody {
+    fn from(body: (bool, Option<u64>)) -> Self {
+        TtlBody {
+            found: body.0,
+            ttl: body.1,
+        }
+    }
+}
+
+impl fmt::Display for TtlBody {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(ttl) = self.ttl {
+            write!(f, "{{found: {}, ttl: {}}}", self.found, ttl)
+        } else {
+            write!(f, "{{found: {}, ttl: null}}", self.found)
+        }
+    }
+}
+
+// A key-value pair returned by a scan.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Pair {
+    pub key: String,
+    pub val: String,
+}
+
+// Response body returned while trying to perform scan.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct ScanBody {
+    pairs: Vec<Pair>,
+    next: Option<String>,
+    #[serde(default, skip_serializing_if = "Encoding::is_utf8")]
+    encoding: Encoding,
+}
+
+impl From<(Vec<(Vec<u8>, Vec<u8>)>, Option<Vec<u8>>, Encoding)> for ScanBody {
+    fn from(body: (Vec<(Vec<u8>, Vec<u8>)>, Option<Vec<u8>>, Encoding)) -> Self {
+        let all = body
+            .0
+            .iter()
+            .flat_map(|(key, val)| vec![key.as_slice(), val.as_slice()]);
+        let encoding = body.2.fit(all);
+        ScanBody {
+            pairs: body
+                .0
+                .iter()
+                .map(|(key, val)| Pair {
+                    key: encoding.encode(key),
+                    val: encoding.encode(val),
+                })
+                .collect(),
+            next: body.1.map(|next| encoding.encode(&next)),
+            encoding,
+        }
+    }
+}
+
+impl fmt::Display for ScanBody {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for pair in &self.pairs {
+            writeln!(f, "{}: {}", pair.key, pair.val)?;
+        }
+        if let Some(next) = &self.next {
+            write!(f, "{{next: {}", next)?;
+        } else {
+            write!(f, "{{next: null")?;
+        }
+        write!(f, "{}}}", encoding_suffix(self.encoding))
+    }
+}
+
+// Response body returned while trying to list keys.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct KeysBody {
+    keys: Vec<String>,
+    next: Option<String>,
+    #[serde(default, skip_serializing_if = "Encoding::is_utf8")]
+    encoding: Encoding,
+}
+
+impl From<(Vec<Vec<u8>>, Option<Vec<u8>>, Encoding)> for KeysBody {
+    fn from(body: (Vec<Vec<u8>>, Option<Vec<u8>>, Encoding)) -> Self {
+        let encoding = body.2.fit(body.0.iter().map(Vec::as_slice));
+        KeysBody {
+            keys: body.0.iter().map(|key| encoding.encode(key)).collect(),
+            next: body.1.map(|next| encoding.encode(&next)),
+            encoding,
+        }
+    }
+}
+
+impl fmt::Display for KeysBody {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for key in &self.keys {
+            writeln!(f, "{}", key)?;
+        }
+        if let Some(next) = &self.next {
+            write!(f, "{{next: {}", next)?;
         } else {
-            write!(f, "{{found: {}, val: null}}", self.found)
+            write!(f, "{{next: null")?;
         }
+        write!(f, "{}}}", encoding_suffix(self.encoding))
     }
 }
 
@@ -61,13 +427,27 @@ impl fmt::Display for GetBody {
 pub struct SetBody {
     inserted: bool,
     ejected_val: Option<String>,
+    // New version of the key, returned for conditional sets.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    version: Option<u64>,
+    #[serde(default, skip_serializing_if = "Encoding::is_utf8")]
+    encoding: Encoding,
+}
+
+impl From<(bool, Option<Vec<u8>>, Encoding)> for SetBody {
+    fn from(body: (bool, Option<Vec<u8>>, Encoding)) -> Self {
+        SetBody::from((body.0, body.1, None, body.2))
+    }
 }
 
-impl From<(bool, Option<String>)> for SetBody {
-    fn from(body: (bool, Option<String>)) -> Self {
+impl From<(bool, Option<Vec<u8>>, Option<u64>, Encoding)> for SetBody {
+    fn from(body: (bool, Option<Vec<u8>>, Option<u64>, Encoding)) -> Self {
+        let encoding = body.3.fit(body.1.as_deref());
         SetBody {
             inserted: body.0,
-            ejected_val: body.1,
+            ejected_val: body.1.map(|val| encoding.encode(&val)),
+            version: body.2,
+            encoding,
         }
     }
 }
@@ -75,10 +455,14 @@ impl From<(bool, Option<String>)> for SetBody {
 impl fmt::Display for SetBody {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         if let Some(val) = &self.ejected_val {
-            write!(f, "{{inserted: {}, ejected_val: {}}}", self.inserted, val)
+            write!(f, "{{inserted: {}, ejected_val: {}", self.inserted, val)?;
         } else {
-            write!(f, "{{inserted: {}, ejected_val: null}}", self.inserted)
+            write!(f, "{{inserted: {}, ejected_val: null", self.inserted)?;
+        }
+        if let Some(version) = self.version {
+            write!(f, ", version: {}", version)?;
         }
+        write!(f, "{}}}", encoding_suffix(self.encoding))
     }
 }
 
@@ -89,14 +473,18 @@ pub struct RmBody {
     removed: bool,
     found: bool,
     ejected_val: Option<String>,
+    #[serde(default, skip_serializing_if = "Encoding::is_utf8")]
+    encoding: Encoding,
 }
 
-impl From<(bool, Option<String>)> for RmBody {
-    fn from(body: (bool, Option<String>)) -> Self {
+impl From<(bool, Option<Vec<u8>>, Encoding)> for RmBody {
+    fn from(body: (bool, Option<Vec<u8>>, Encoding)) -> Self {
+        let encoding = body.2.fit(body.1.as_deref());
         RmBody {
             removed: body.0,
             found: body.0,
-            ejected_val: body.1,
+            ejected_val: body.1.map(|val| encoding.encode(&val)),
+            encoding,
         }
     }
 }
@@ -106,15 +494,452 @@ impl fmt::Display for RmBody {
         if let Some(val) = &self.ejected_val {
             write!(
                 f,
-                "{{removed: {}, found: {}, ejected_val: {}}}",
+                "{{removed: {}, found: {}, ejected_val: {}",
                 self.removed, self.found, val
-            )
+            )?;
         } else {
             write!(
                 f,
-                "{{removed: {}, found: {}, ejected_val: null}}",
+                "{{removed: {}, found: {}, ejected_val: null",
                 self.removed, self.found
-            )
+            )?;
+        }
+        write!(f, "{}}}", encoding_suffix(self.encoding))
+    }
+}
+
+// Response body returned while trying to perform compaction.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct CompactBody {
+    compacted: bool,
+    reclaimed_bytes: u64,
+}
+
+impl From<(bool, u64)> for CompactBody {
+    fn from(body: (bool, u64)) -> Self {
+        CompactBody {
+            compacted: body.0,
+            reclaimed_bytes: body.1,
+        }
+    }
+}
+
+impl fmt::Display for CompactBody {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{{compacted: {}, reclaimed_bytes: {}}}",
+            self.compacted, self.reclaimed_bytes
+        )
+    }
+}
+
+/// Status of the server, or of one of its components. Components that aren't in use, e.g.
+/// NATS if the server couldn't connect to it on start up, are disabled.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(crate = "rocket::serde", rename_all = "lowercase")]
+pub enum HealthStatus {
+    Up,
+    Down,
+    Disabled,
+}
+
+impl fmt::Display for HealthStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HealthStatus::Up => write!(f, "up"),
+            HealthStatus::Down => write!(f, "down"),
+            HealthStatus::Disabled => write!(f, "disabled"),
+        }
+    }
+}
+
+// Status of a component of the server, along with the error it's down with.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct ComponentHealth {
+    pub status: HealthStatus,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl ComponentHealth {
+    pub fn disabled() -> Self {
+        ComponentHealth {
+            status: HealthStatus::Disabled,
+            error: None,
+        }
+    }
+}
+
+// The result of checking on a component.
+impl From<Result<(), String>> for ComponentHealth {
+    fn from(check: Result<(), String>) -> Self {
+        match check {
+            Ok(()) => ComponentHealth {
+                status: HealthStatus::Up,
+                error: None,
+            },
+            Err(err) => ComponentHealth {
+                status: HealthStatus::Down,
+                error: Some(err),
+            },
+        }
+    }
+}
+
+impl fmt::Display for ComponentHealth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.error {
+            Some(err) => write!(f, "{} ({})", self.status, err),
+            None => write!(f, "{}", self.status),
+        }
+    }
+}
+
+// Response body returned by the health checks. The server is down if any of its components is.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct HealthBody {
+    pub status: HealthStatus,
+    pub components: BTreeMap<String, ComponentHealth>,
+}
+
+impl From<BTreeMap<String, ComponentHealth>> for HealthBody {
+    fn from(components: BTreeMap<String, ComponentHealth>) -> Self {
+        let down = components
+            .values()
+            .any(|component| component.status == HealthStatus::Down);
+        HealthBody {
+            status: if down {
+                HealthStatus::Down
+            } else {
+                HealthStatus::Up
+            },
+            components,
         }
     }
 }
+
+impl fmt::Display for HealthBody {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{{status: {}", self.status)?;
+        for (name, component) in &self.components {
+            write!(f, ", {}: {}", name, component)?;
+        }
+        write!(f, "}}")
+    }
+}
+
+// Response body returned while trying to perform incr or decr.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct IncrBody {
+    val: i64,
+}
+
+impl From<i64> for IncrBody {
+    fn from(val: i64) -> Self {
+        IncrBody { val }
+    }
+}
+
+impl fmt::Display for IncrBody {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{{val: {}}}", self.val)
+    }
+}
+
+// Response body returned while trying to create a bucket.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct CreateBucketBody {
+    bucket: String,
+    created: bool,
+}
+
+impl From<(String, bool)> for CreateBucketBody {
+    fn from(body: (String, bool)) -> Self {
+        CreateBucketBody {
+            bucket: body.0,
+            created: body.1,
+        }
+    }
+}
+
+impl fmt::Display for CreateBucketBody {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{{bucket: {}, created: {}}}", self.bucket, self.created)
+    }
+}
+
+// Response body returned while trying to drop a bucket.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct DropBucketBody {
+    bucket: String,
+    dropped: bool,
+}
+
+impl From<(String, bool)> for DropBucketBody {
+    fn from(body: (String, bool)) -> Self {
+        DropBucketBody {
+            bucket: body.0,
+            dropped: body.1,
+        }
+    }
+}
+
+impl fmt::Display for DropBucketBody {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{{bucket: {}, dropped: {}}}", self.bucket, self.dropped)
+    }
+}
+
+// Result of an operation in a batch: the value of the key for a get, the old value of the
+// key for a set or an rm, or the error the operation failed with.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct BatchResult {
+    ok: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    val: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ejected_val: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl fmt::Display for BatchResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{{ok: {}", self.ok)?;
+        if let Some(val) = &self.val {
+            write!(f, ", val: {}", val)?;
+        }
+        if let Some(ejected_val) = &self.ejected_val {
+            write!(f, ", ejected_val: {}", ejected_val)?;
+        }
+        if let Some(error) = &self.error {
+            write!(f, ", error: {}", error)?;
+        }
+        write!(f, "}}")
+    }
+}
+
+// Response body returned while trying to perform a batch of operations.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct BatchBody {
+    results: Vec<BatchResult>,
+    #[serde(default, skip_serializing_if = "Encoding::is_utf8")]
+    encoding: Encoding,
+}
+
+impl BatchBody {
+    /// Results of the operations in the batch, in order.
+    pub fn results(&self) -> &[BatchResult] {
+        &self.results
+    }
+}
+
+// The results are given along with whether the operation was a get.
+impl From<(Vec<(bool, crate::Result<Option<Vec<u8>>>)>, Encoding)> for BatchBody {
+    fn from(body: (Vec<(bool, crate::Result<Option<Vec<u8>>>)>, Encoding)) -> Self {
+        let all = body
+            .0
+            .iter()
+            .filter_map(|(_, result)| result.as_ref().ok()?.as_deref());
+        let encoding = body.1.fit(all);
+        let results = body
+            .0
+            .into_iter()
+            .map(|(get, result)| match result {
+                Ok(val) => {
+                    let val = val.map(|val| encoding.encode(&val));
+                    let (val, ejected_val) = if get { (val, None) } else { (None, val) };
+                    BatchResult {
+                        ok: true,
+                        val,
+                        ejected_val,
+                        error: None,
+                    }
+                }
+                Err(err) => BatchResult {
+                    ok: false,
+                    val: None,
+                    ejected_val: None,
+                    error: Some(err.to_string()),
+                },
+            })
+            .collect();
+        BatchBody { results, encoding }
+    }
+}
+
+impl fmt::Display for BatchBody {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let results: Vec<String> = self.results.iter().map(ToString::to_string).collect();
+        write!(f, "{}", results.join("\n"))
+    }
+}
+
+// A change to a key, as streamed to its watchers: the value it was set to, or none if it was
+// removed, along with the id of the change.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct ChangeBody {
+    pub id: String,
+    pub op: String,
+    pub key: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub val: Option<String>,
+    #[serde(default, skip_serializing_if = "Encoding::is_utf8")]
+    pub encoding: Encoding,
+}
+
+impl From<(&Event, Encoding)> for ChangeBody {
+    fn from(body: (&Event, Encoding)) -> Self {
+        let (event, encoding) = body;
+        let (op, key, val) = match &event.change {
+            Change::Set { key, val } => ("set", key, Some(val)),
+            Change::Rm { key } => ("rm", key, None),
+        };
+        let all = std::iter::once(&key[..]).chain(val.map(|val| &val[..]));
+        let encoding = encoding.fit(all);
+        ChangeBody {
+            id: event.id.to_string(),
+            op: op.to_string(),
+            key: encoding.encode(key),
+            val: val.map(|val| encoding.encode(val)),
+            encoding,
+        }
+    }
+}
+
+impl fmt::Display for ChangeBody {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.val {
+            Some(val) => write!(
+                f,
+                "{{id: {}, op: {}, key: {}, val: {}",
+                self.id, self.op, self.key, val
+            )?,
+            None => write!(f, "{{id: {}, op: {}, key: {}", self.id, self.op, self.key)?,
+        }
+        write!(f, "{}}}", encoding_suffix(self.encoding))
+    }
+}
+
+// Response body returned while trying to perform backup.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct BackupBody {
+    path: String,
+    keys: u64,
+    bytes: u64,
+}
+
+impl From<(String, SnapshotInfo)> for BackupBody {
+    fn from(body: (String, SnapshotInfo)) -> Self {
+        BackupBody {
+            path: body.0,
+            keys: body.1.keys,
+            bytes: body.1.bytes,
+        }
+    }
+}
+
+impl fmt::Display for BackupBody {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{{path: {}, keys: {}, bytes: {}}}",
+            self.path, self.keys, self.bytes
+        )
+    }
+}
+
+// Response body returned while trying to restore a bucket.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct RestoreBody {
+    bucket: String,
+    restored: bool,
+}
+
+impl From<(String, bool)> for RestoreBody {
+    fn from(body: (String, bool)) -> Self {
+        RestoreBody {
+            bucket: body.0,
+            restored: body.1,
+        }
+    }
+}
+
+impl fmt::Display for RestoreBody {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{{bucket: {}, restored: {}}}",
+            self.bucket, self.restored
+        )
+    }
+}
+
+// Response body returned while trying to perform import.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct ImportBody {
+    pub imported: u64,
+}
+
+impl From<u64> for ImportBody {
+    fn from(imported: u64) -> Self {
+        ImportBody { imported }
+    }
+}
+
+impl fmt::Display for ImportBody {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{{imported: {}}}", self.imported)
+    }
+}
+
+// Response body returned while listing the buckets.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct BucketsBody {
+    buckets: Vec<String>,
+}
+
+impl From<Vec<String>> for BucketsBody {
+    fn from(buckets: Vec<String>) -> Self {
+        BucketsBody { buckets }
+    }
+}
+
+impl fmt::Display for BucketsBody {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{{buckets: [{}]}}", self.buckets.join(", "))
+    }
+}
+
+// Response body returned when a request fails.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct ErrorBody {
+    error: String,
+}
+
+impl From<String> for ErrorBody {
+    fn from(error: String) -> Self {
+        ErrorBody { error }
+    }
+}
+
+impl fmt::Display for ErrorBody {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{{error: {}}}", self.error)
+    }
+}
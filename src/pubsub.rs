@@ -1,18 +1,32 @@
 use log::error;
 use nats::{Connection, Subscription};
 use rocket::serde::{Deserialize, Serialize};
+use tokio::task;
 
 use crate::Result;
 
-/// Publish an action to a subject in the NATS server.
-pub fn publish_action<'de, T>(conn: &Connection, subject: &str, action: Box<T>) -> Result<()>
+/// Publish an action to a subject in the NATS server. Publishing may block on the connection,
+/// so it's done on tokio's blocking thread pool.
+pub async fn publish_action<'de, T>(conn: &Connection, subject: &str, action: Box<T>) -> Result<()>
 where
     T: Serialize + Deserialize<'de>,
 {
-    conn.publish(subject, serde_json::to_vec(&action)?)?;
+    let payload = serde_json::to_vec(&action)?;
+    let conn = conn.clone();
+    let subject = subject.to_string();
+    task::spawn_blocking(move || conn.publish(&subject, payload)).await??;
     Ok(())
 }
 
+/// Subject to which the actions of the given kind, e.g. `set`, on the keys in a bucket are
+/// published. Actions on the default bucket are published to the subject named after the kind.
+pub fn subject(bucket: Option<&str>, action: &str) -> String {
+    match bucket {
+        Some(bucket) => format!("buckets.{}.{}", bucket, action),
+        None => action.to_string(),
+    }
+}
+
 /// Subscribe to a subject in the NATS server.
 pub fn subscribe(conn: &Connection, subject: &str) -> Result<Subscription> {
     let sub = conn.subscribe(subject)?;
@@ -0,0 +1,503 @@
+use crate::{KVStoreError, Result};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use serde_json::Deserializer;
+use std::{
+    io::{self, Read, Seek, SeekFrom, Write},
+    ops::Range,
+};
+
+// Every segment starts with a header: the magic bytes followed by the version
+// of the format (u16, little endian) in which the actions in it are encoded.
+pub(crate) const SEGMENT_MAGIC: &[u8; 6] = b"KVSLOG";
+pub(crate) const SEGMENT_HEADER_LEN: u64 = 8;
+// Actions encoded in the binary format described by `Action::encode`.
+pub(crate) const SEGMENT_VERSION: u16 = 2;
+// Segments written before the header was introduced, with JSON encoded actions.
+pub(crate) const LEGACY_VERSION: u16 = 1;
+// Logs written before records were framed, as a stream of JSON encoded actions with
+// nothing in between them. They have no header either.
+pub(crate) const UNFRAMED_VERSION: u16 = 0;
+
+// Every record in the log is framed as: the length of the payload (u32, little endian),
+// the CRC32 checksum of the payload (u32, little endian) and then the payload itself.
+// This lets a reader detect records that are corrupt or that were only partially
+// written, e.g. because the process crashed in the middle of a write.
+pub(crate) const HEADER_LEN: usize = 8;
+
+// Op types 1 and 3 were taken by set actions without a version, they aren't reused.
+const OP_REMOVE: u8 = 2;
+const OP_SET: u8 = 4;
+const OP_BATCH: u8 = 5;
+// Length of the op type and the number of actions at the start of a batch.
+const BATCH_HEADER_LEN: usize = 5;
+
+// Version of a key when it's first set. Set actions written before keys had versions
+// are treated as being at this version.
+pub(crate) const FIRST_VERSION: u64 = 1;
+
+fn first_version() -> u64 {
+    FIRST_VERSION
+}
+
+// Action to be stored in the log.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Action {
+    Set {
+        key: Vec<u8>,
+        val: Vec<u8>,
+        // Time at which the key expires, in milliseconds since the UNIX epoch.
+        expires_at: Option<u64>,
+        // Version of the key, bumped on every set and reset once the key is removed.
+        version: u64,
+    },
+    Remove {
+        key: Vec<u8>,
+    },
+}
+
+// Action as encoded in JSON in legacy segments, when keys and values were always UTF-8.
+#[derive(Serialize, Deserialize)]
+enum LegacyAction {
+    Set {
+        key: String,
+        val: String,
+        #[serde(default)]
+        expires_at: Option<u64>,
+        #[serde(default = "first_version")]
+        version: u64,
+    },
+    Remove {
+        key: String,
+    },
+}
+
+impl From<LegacyAction> for Action {
+    fn from(action: LegacyAction) -> Self {
+        match action {
+            LegacyAction::Set {
+                key,
+                val,
+                expires_at,
+                version,
+            } => Action::Set {
+                key: key.into_bytes(),
+                val: val.into_bytes(),
+                expires_at,
+                version,
+            },
+            LegacyAction::Remove { key } => Action::Remove {
+                key: key.into_bytes(),
+            },
+        }
+    }
+}
+
+impl Action {
+    pub(crate) fn key(&self) -> &[u8] {
+        match self {
+            Action::Set { key, .. } | Action::Remove { key } => key,
+        }
+    }
+
+    // Encode the action as: the op type (u8), followed by the key and for set actions,
+    // the value, each prefixed with its length (u32, little endian). Set actions are then
+    // followed by the version of the key and the expiry time, 0 if the key never expires
+    // (both u64, little endian).
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        match self {
+            Action::Set {
+                key,
+                val,
+                expires_at,
+                version,
+            } => {
+                buf.reserve(25 + key.len() + val.len());
+                buf.push(OP_SET);
+                put_bytes(&mut buf, key);
+                put_bytes(&mut buf, val);
+                buf.extend_from_slice(&version.to_le_bytes());
+                buf.extend_from_slice(&expires_at.unwrap_or(0).to_le_bytes());
+            }
+            Action::Remove { key } => {
+                buf.reserve(5 + key.len());
+                buf.push(OP_REMOVE);
+                put_bytes(&mut buf, key);
+            }
+        }
+        buf
+    }
+
+    // Decode an action encoded in the given version of the format.
+    pub(crate) fn decode(version: u16, payload: &[u8]) -> Result<Action> {
+        if version == LEGACY_VERSION {
+            let action: LegacyAction = serde_json::from_slice(payload)?;
+            return Ok(action.into());
+        }
+        let mut buf = payload;
+        let op = take_bytes(&mut buf, 1)?[0];
+        let key = take_vec(&mut buf)?;
+        let action = match op {
+            OP_SET => {
+                let val = take_vec(&mut buf)?;
+                let version = take_u64(&mut buf)?;
+                let expires_at = Some(take_u64(&mut buf)?).filter(|&expires_at| expires_at != 0);
+                Action::Set {
+                    key,
+                    val,
+                    expires_at,
+                    version,
+                }
+            }
+            OP_REMOVE => Action::Remove { key },
+            _ => return Err(KVStoreError::Corrupt),
+        };
+        if !buf.is_empty() {
+            return Err(KVStoreError::Corrupt);
+        }
+        Ok(action)
+    }
+}
+
+// Encode actions which have to be applied all-or-nothing as a batch: the op type (u8) and
+// the number of actions (u32, little endian), followed by each action framed as a record
+// of its own. Since the batch is itself written as a single record, it's either read
+// back entirely or not at all, whereas the records in it can be pointed to and read like
+// any other record in the log.
+//
+// Returns the payload along with the offsets of the records in it.
+pub(crate) fn encode_batch(actions: &[Action]) -> (Vec<u8>, Vec<Range<usize>>) {
+    let mut buf = vec![OP_BATCH];
+    buf.extend_from_slice(&(actions.len() as u32).to_le_bytes());
+    let mut records = Vec::with_capacity(actions.len());
+    for action in actions {
+        let start = buf.len();
+        // Writing to a Vec never fails.
+        let _ = write_record(&mut buf, &action.encode());
+        records.push(start..buf.len());
+    }
+    (buf, records)
+}
+
+// Decode an action read back from a segment encoded in the given version of the format,
+// along with its framing, if the version has any.
+pub(crate) fn decode_action(version: u16, buf: &[u8]) -> Result<Action> {
+    match version {
+        UNFRAMED_VERSION => Action::decode(LEGACY_VERSION, buf),
+        _ => Action::decode(version, decode_record(buf)?),
+    }
+}
+
+// Read the actions in a segment encoded in `UNFRAMED_VERSION` and pass each of them to the
+// callback, along with its offset and its length. Expects the reader to be at the start of
+// the segment.
+//
+// Since there's no framing to tell an incomplete action apart from a corrupt one, only an
+// action cut short at the end of the segment, e.g. because the store crashed in the middle
+// of a write, is skipped. Anything else that isn't an action is an error.
+pub(crate) fn read_unframed<R: Read>(reader: R, mut f: impl FnMut(Action, u64, u64)) -> Result<()> {
+    let mut stream = Deserializer::from_reader(reader).into_iter::<LegacyAction>();
+    let mut pos = 0;
+    while let Some(action) = stream.next() {
+        match action {
+            Ok(action) => {
+                let end = stream.byte_offset() as u64;
+                f(action.into(), pos, end - pos);
+                pos = end;
+            }
+            Err(err) if err.is_eof() => {
+                warn!("Skipping an incomplete action at offset {}", pos);
+                break;
+            }
+            Err(err) => return Err(err.into()),
+        }
+    }
+    Ok(())
+}
+
+// Whether the payload, encoded in the given version of the format, is a batch of actions.
+pub(crate) fn is_batch(version: u16, payload: &[u8]) -> bool {
+    version == SEGMENT_VERSION && payload.first() == Some(&OP_BATCH)
+}
+
+// Decode a batch of actions, along with the offsets of their records in the payload.
+pub(crate) fn decode_batch(payload: &[u8]) -> Result<Vec<(Range<usize>, Action)>> {
+    let mut buf = payload;
+    if take_bytes(&mut buf, 1)?[0] != OP_BATCH {
+        return Err(KVStoreError::Corrupt);
+    }
+    let mut count = [0; 4];
+    count.copy_from_slice(take_bytes(&mut buf, 4)?);
+    let mut actions = vec![];
+    let mut pos = BATCH_HEADER_LEN;
+    for _ in 0..u32::from_le_bytes(count) {
+        if buf.len() < HEADER_LEN {
+            return Err(KVStoreError::Corrupt);
+        }
+        let mut header = [0; HEADER_LEN];
+        header.copy_from_slice(&buf[..HEADER_LEN]);
+        let (len, _) = parse_header(&header);
+        let record = take_bytes(&mut buf, HEADER_LEN + len)?;
+        let action = Action::decode(SEGMENT_VERSION, decode_record(record)?)?;
+        actions.push((pos..pos + record.len(), action));
+        pos += record.len();
+    }
+    if !buf.is_empty() {
+        return Err(KVStoreError::Corrupt);
+    }
+    Ok(actions)
+}
+
+fn put_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    buf.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    buf.extend_from_slice(bytes);
+}
+
+fn take_bytes<'a>(buf: &mut &'a [u8], len: usize) -> Result<&'a [u8]> {
+    if buf.len() < len {
+        return Err(KVStoreError::Corrupt);
+    }
+    let (bytes, rest) = buf.split_at(len);
+    *buf = rest;
+    Ok(bytes)
+}
+
+fn take_u64(buf: &mut &[u8]) -> Result<u64> {
+    let mut n = [0; 8];
+    n.copy_from_slice(take_bytes(buf, 8)?);
+    Ok(u64::from_le_bytes(n))
+}
+
+fn take_vec(buf: &mut &[u8]) -> Result<Vec<u8>> {
+    let mut len = [0; 4];
+    len.copy_from_slice(take_bytes(buf, 4)?);
+    Ok(take_bytes(buf, u32::from_le_bytes(len) as usize)?.to_vec())
+}
+
+// Write the header for a new segment.
+pub(crate) fn write_segment_header<W: Write>(writer: &mut W) -> Result<()> {
+    writer.write_all(SEGMENT_MAGIC)?;
+    writer.write_all(&SEGMENT_VERSION.to_le_bytes())?;
+    Ok(())
+}
+
+// Read the header of a segment and return its version, leaving the reader at the
+// first record. Segments without a header are legacy ones. Returns None if the segment
+// isn't in any of the versions of the format.
+pub(crate) fn read_segment_header<R: Read + Seek>(reader: &mut R) -> Result<Option<u16>> {
+    reader.seek(SeekFrom::Start(0))?;
+    let mut header = [0; SEGMENT_HEADER_LEN as usize];
+    let read = read_full(reader, &mut header)?;
+    let magic_len = SEGMENT_MAGIC.len();
+    if read == header.len() && &header[..magic_len] == SEGMENT_MAGIC {
+        let mut version = [0; 2];
+        version.copy_from_slice(&header[magic_len..]);
+        // Segments with a header have only ever been written in the current version.
+        let version = u16::from_le_bytes(version);
+        return Ok(Some(version).filter(|&version| version == SEGMENT_VERSION));
+    }
+    // The store went down while the header of a new segment was being written,
+    // there's nothing else in it.
+    let prefix_len = read.min(magic_len);
+    if read < header.len() && header[..prefix_len] == SEGMENT_MAGIC[..prefix_len] {
+        return Ok(Some(SEGMENT_VERSION));
+    }
+    // Every legacy action is a JSON object with the kind of action as its only key,
+    // whereas framed ones are preceded by the length of the record.
+    let version = if read >= 2 && &header[..2] == b"{\"" {
+        Some(UNFRAMED_VERSION)
+    } else {
+        reader.seek(SeekFrom::Start(0))?;
+        match read_record(reader) {
+            Ok(Some(payload)) if payload.first() == Some(&b'{') => Some(LEGACY_VERSION),
+            _ => None,
+        }
+    };
+    reader.seek(SeekFrom::Start(0))?;
+    Ok(version)
+}
+
+// Write the payload to the writer as a framed record.
+pub(crate) fn write_record<W: Write>(writer: &mut W, payload: &[u8]) -> Result<()> {
+    let mut header = [0; HEADER_LEN];
+    header[..4].copy_from_slice(&(payload.len() as u32).to_le_bytes());
+    header[4..].copy_from_slice(&crc32fast::hash(payload).to_le_bytes());
+    writer.write_all(&header)?;
+    writer.write_all(payload)?;
+    Ok(())
+}
+
+// Read the next framed record from the reader and return its payload.
+// Returns None if the reader is at the end, and a Corrupt error if the record is
+// incomplete or its checksum does not match.
+pub(crate) fn read_record<R: Read>(reader: &mut R) -> Result<Option<Vec<u8>>> {
+    let mut header = [0; HEADER_LEN];
+    let read = read_full(reader, &mut header)?;
+    if read == 0 {
+        return Ok(None);
+    }
+    if read < HEADER_LEN {
+        return Err(KVStoreError::Corrupt);
+    }
+    let (len, crc) = parse_header(&header);
+    let mut payload = Vec::new();
+    reader.take(len as u64).read_to_end(&mut payload)?;
+    if payload.len() < len || crc32fast::hash(&payload) != crc {
+        return Err(KVStoreError::Corrupt);
+    }
+    Ok(Some(payload))
+}
+
+// Validate a complete framed record and return its payload.
+pub(crate) fn decode_record(buf: &[u8]) -> Result<&[u8]> {
+    if buf.len() < HEADER_LEN {
+        return Err(KVStoreError::Corrupt);
+    }
+    let mut header = [0; HEADER_LEN];
+    header.copy_from_slice(&buf[..HEADER_LEN]);
+    let (len, crc) = parse_header(&header);
+    let payload = &buf[HEADER_LEN..];
+    if payload.len() != len || crc32fast::hash(payload) != crc {
+        return Err(KVStoreError::Corrupt);
+    }
+    Ok(payload)
+}
+
+fn parse_header(header: &[u8; HEADER_LEN]) -> (usize, u32) {
+    let mut len = [0; 4];
+    let mut crc = [0; 4];
+    len.copy_from_slice(&header[..4]);
+    crc.copy_from_slice(&header[4..]);
+    (u32::from_le_bytes(len) as usize, u32::from_le_bytes(crc))
+}
+
+// Read into the buffer until it's full or the reader is exhausted.
+// Returns the number of bytes read.
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(read)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn bytes(s: &str) -> Vec<u8> {
+        s.as_bytes().to_vec()
+    }
+
+    // Encode the action in JSON, as it was in legacy segments.
+    fn to_json(action: &Action) -> Vec<u8> {
+        let utf8 = |bytes: &Vec<u8>| String::from_utf8(bytes.clone()).unwrap();
+        let action = match action {
+            Action::Set {
+                key,
+                val,
+                expires_at,
+                version,
+            } => LegacyAction::Set {
+                key: utf8(key),
+                val: utf8(val),
+                expires_at: *expires_at,
+                version: *version,
+            },
+            Action::Remove { key } => LegacyAction::Remove { key: utf8(key) },
+        };
+        serde_json::to_vec(&action).unwrap()
+    }
+
+    #[test]
+    fn test_encode_decode() {
+        let actions = vec![
+            Action::Set {
+                key: bytes("this is"),
+                val: bytes("the \"way\""),
+                expires_at: None,
+                version: FIRST_VERSION,
+            },
+            Action::Set {
+                key: bytes("this is"),
+                val: bytes("the way"),
+                expires_at: Some(1_634_000_000_000),
+                version: 42,
+            },
+            Action::Set {
+                key: vec![],
+                val: vec![],
+                expires_at: None,
+                version: FIRST_VERSION,
+            },
+            Action::Remove {
+                key: bytes("this is"),
+            },
+        ];
+        for action in actions {
+            let encoded = action.encode();
+            assert_eq!(Action::decode(SEGMENT_VERSION, &encoded).unwrap(), action);
+            let json = to_json(&action);
+            assert_eq!(Action::decode(LEGACY_VERSION, &json).unwrap(), action);
+            assert!(Action::decode(SEGMENT_VERSION, &encoded[..encoded.len() - 1]).is_err());
+        }
+        // Keys and values are not necessarily UTF-8.
+        let action = Action::Set {
+            key: vec![0, 159, 146, 150],
+            val: vec![255, 0, 254],
+            expires_at: None,
+            version: FIRST_VERSION,
+        };
+        assert_eq!(
+            Action::decode(SEGMENT_VERSION, &action.encode()).unwrap(),
+            action
+        );
+    }
+
+    #[test]
+    fn test_encode_decode_batch() {
+        let actions = vec![
+            Action::Set {
+                key: bytes("this is"),
+                val: bytes("the way"),
+                expires_at: None,
+                version: 2,
+            },
+            Action::Remove { key: bytes("gone") },
+        ];
+        let (payload, records) = encode_batch(&actions);
+        assert!(is_batch(SEGMENT_VERSION, &payload));
+        assert!(!is_batch(SEGMENT_VERSION, &actions[0].encode()));
+        let decoded = decode_batch(&payload).unwrap();
+        assert_eq!(decoded.len(), 2);
+        for ((range, action), (record, expected)) in
+            decoded.into_iter().zip(records.into_iter().zip(actions))
+        {
+            assert_eq!(range, record);
+            // Each action in the batch can be read as a record of its own.
+            let payload = decode_record(&payload[range]).unwrap();
+            assert_eq!(Action::decode(SEGMENT_VERSION, payload).unwrap(), expected);
+            assert_eq!(action, expected);
+        }
+        assert!(decode_batch(&payload[..payload.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_decode_unversioned() {
+        // Set actions written before keys had versions are at the first version.
+        let action = Action::Set {
+            key: bytes("this is"),
+            val: bytes("the way"),
+            expires_at: Some(1_634_000_000_000),
+            version: FIRST_VERSION,
+        };
+        let json = br#"{"Set":{"key":"this is","val":"the way","expires_at":1634000000000}}"#;
+        assert_eq!(Action::decode(LEGACY_VERSION, json).unwrap(), action);
+    }
+}
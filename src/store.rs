@@ -1,34 +1,79 @@
-use crate::{error::Result, KVStoreError};
-use serde::{Deserialize, Serialize};
-use serde_json::Deserializer;
+use crate::{
+    bulk::{self, Format},
+    cache::ShardedCache,
+    engines::{self, EngineStats, KeyRange, KvsEngine, Page, SnapshotInfo, WriteOp},
+    error::{display_key, Result},
+    hint::{self, Hint, HintEntry},
+    record::{self, Action},
+    watch::{Change, ChangeSink},
+    KVStoreError,
+};
+use fs2::FileExt;
+use log::{error, info, warn};
 use std::{
-    collections::BTreeMap,
-    fs::{File, OpenOptions},
+    collections::{hash_map::Entry, BTreeMap, BTreeSet, HashMap, VecDeque},
+    ffi::OsStr,
+    fs::{self, File, OpenOptions},
     io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
-    ops::Range,
-    path::PathBuf,
-    sync::Mutex,
+    ops::{Bound, Range, RangeBounds},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Condvar, Mutex, RwLock, TryLockError, Weak,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-// Ser/Derializable action to be stored in the log.
-#[derive(Serialize, Deserialize, Debug)]
-enum Action {
-    Set { key: String, val: String },
-    Remove { key: String },
-}
+/// Default amount of stale bytes in the log after which it gets compacted.
+pub const DEFAULT_COMPACTION_THRESHOLD: u64 = 1024 * 1024;
+
+/// Default size in bytes after which the active segment is closed and a new one is started.
+pub const DEFAULT_SEGMENT_SIZE: u64 = 4 * 1024 * 1024;
+
+/// Default size in bytes of the value cache, which is disabled by default.
+pub const DEFAULT_CACHE_SIZE: u64 = 0;
 
-// Pointer to a stored action in the log.
-#[derive(Debug)]
+// Generation of the only segment in a snapshot.
+const SNAPSHOT_GEN: u64 = 1;
+
+// Number of key-value pairs read at a time by a scan.
+const SCAN_BATCH_SIZE: usize = 128;
+
+// File in the directory which is locked by the store that has it open.
+const LOCK_FILE: &str = "LOCK";
+// File written to the directory to check that it can be written to. It's a temporary file,
+// so it's removed when the store is opened, if it's left behind.
+const WRITABLE_CHECK_FILE: &str = "writable.tmp";
+
+// Pointer to a stored action in the log, i.e. the generation of the segment
+// containing the action and the offset of the action in the segment, along with
+// the time at which the key expires, if it does, and the version of the key.
+#[derive(Debug, Clone, Copy)]
 struct ActionPointer {
+    gen: u64,
     pos: u64,
     len: u64,
+    expires_at: Option<u64>,
+    version: u64,
+}
+
+impl ActionPointer {
+    // Whether the key has expired as of the given time.
+    fn is_expired(&self, now: u64) -> bool {
+        matches!(self.expires_at, Some(expires_at) if expires_at <= now)
+    }
 }
 
-impl From<Range<u64>> for ActionPointer {
-    fn from(range: Range<u64>) -> Self {
+impl From<(u64, Range<u64>)> for ActionPointer {
+    fn from((gen, range): (u64, Range<u64>)) -> Self {
         ActionPointer {
+            gen,
             pos: range.start,
             len: range.end - range.start,
+            expires_at: None,
+            version: record::FIRST_VERSION,
         }
     }
 }
@@ -41,7 +86,7 @@ struct BufReaderWithPointer<R: Read + Seek> {
 
 impl<R: Read + Seek> BufReaderWithPointer<R> {
     fn new(mut _inner: R) -> Result<Self> {
-        let pointer = _inner.seek(SeekFrom::Current(0))?;
+        let pointer = _inner.stream_position()?;
         Ok(BufReaderWithPointer {
             reader: BufReader::new(_inner),
             pointer,
@@ -72,7 +117,7 @@ struct BufWriterWithPointer<W: Write + Seek> {
 
 impl<W: Write + Seek> BufWriterWithPointer<W> {
     fn new(mut _inner: W) -> Result<Self> {
-        let pointer = _inner.seek(SeekFrom::Current(0))?;
+        let pointer = _inner.stream_position()?;
         Ok(BufWriterWithPointer {
             writer: BufWriter::new(_inner),
             pointer,
@@ -99,221 +144,3203 @@ impl<W: Write + Seek> Seek for BufWriterWithPointer<W> {
     }
 }
 
+/// Policy deciding when the writes to the log are fsynced to disk.
+///
+/// Writes are always flushed to the OS, but unless they're fsynced, they can be
+/// lost if the machine goes down.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Durability {
+    /// Never fsync explicitly, leave it to the OS.
+    Never,
+    /// fsync after every write.
+    Always,
+    /// fsync at most once every given number of milliseconds.
+    Interval(u64),
+    /// fsync after every given number of writes.
+    Writes(u64),
+}
+
+impl Default for Durability {
+    fn default() -> Self {
+        Durability::Never
+    }
+}
+
+impl FromStr for Durability {
+    type Err = KVStoreError;
+
+    /// Parses one of `never`, `always`, `interval:{ms}` or `writes:{n}`.
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || KVStoreError::InvalidDurability(s.to_string());
+        let mut parts = s.splitn(2, ':');
+        let durability = match (parts.next(), parts.next()) {
+            (Some("never"), None) => Durability::Never,
+            (Some("always"), None) => Durability::Always,
+            (Some("interval"), Some(ms)) => {
+                Durability::Interval(ms.parse().map_err(|_| invalid())?)
+            }
+            (Some("writes"), Some(n)) => Durability::Writes(n.parse().map_err(|_| invalid())?),
+            _ => return Err(invalid()),
+        };
+        match durability {
+            Durability::Interval(0) | Durability::Writes(0) => Err(invalid()),
+            durability => Ok(durability),
+        }
+    }
+}
+
+// Writer for the active segment of the log.
+struct SegmentWriter {
+    gen: u64,
+    path: PathBuf,
+    writer: BufWriterWithPointer<File>,
+    durability: Durability,
+    // Number of writes since the last fsync.
+    unsynced: u64,
+    last_sync: Instant,
+    // Error the last write or fsync failed with, if it did.
+    failed: Option<String>,
+    // Keys committed to the segment, so that its hint can be built from the index once it's
+    // closed, rather than by reading it again.
+    keys: BTreeSet<Vec<u8>>,
+}
+
+impl SegmentWriter {
+    // Create the segment with the given generation in the directory.
+    fn create(dir: &Path, gen: u64, durability: Durability) -> Result<Self> {
+        let path = log_path(dir, gen);
+        let log_file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let mut writer = BufWriterWithPointer::new(log_file)?;
+        record::write_segment_header(&mut writer)?;
+        writer.flush()?;
+        Ok(SegmentWriter {
+            gen,
+            path,
+            writer,
+            durability,
+            unsynced: 0,
+            last_sync: Instant::now(),
+            failed: None,
+            keys: BTreeSet::new(),
+        })
+    }
+
+    // Open the existing segment with the given generation in the directory, to append to it.
+    fn open(dir: &Path, gen: u64, durability: Durability) -> Result<Self> {
+        let path = log_path(dir, gen);
+        let mut log_file = OpenOptions::new().append(true).open(&path)?;
+        log_file.seek(SeekFrom::End(0))?;
+        Ok(SegmentWriter {
+            gen,
+            path,
+            writer: BufWriterWithPointer::new(log_file)?,
+            durability,
+            unsynced: 0,
+            last_sync: Instant::now(),
+            failed: None,
+            keys: BTreeSet::new(),
+        })
+    }
+
+    // Write the payload as a record to the segment and return a pointer to it.
+    // The record is not guaranteed to be visible to readers until the segment is committed.
+    fn write(&mut self, payload: &[u8]) -> Result<ActionPointer> {
+        let pointer = self.writer.pointer;
+        record::write_record(&mut self.writer, payload)?;
+        self.unsynced += 1;
+        Ok((self.gen, pointer..self.writer.pointer).into())
+    }
+
+    // Write the actions as a single batch record to the segment and return pointers to the
+    // records of the actions in it, along with the number of bytes taken up by the rest of it.
+    fn write_batch(&mut self, actions: &[Action]) -> Result<(Vec<ActionPointer>, u64)> {
+        let (payload, records) = record::encode_batch(actions);
+        let batch_pointer = self.write(&payload)?;
+        let start = batch_pointer.pos + record::HEADER_LEN as u64;
+        let mut overhead = batch_pointer.len;
+        let mut action_pointers = Vec::with_capacity(records.len());
+        for range in records {
+            overhead -= (range.end - range.start) as u64;
+            let range = start + range.start as u64..start + range.end as u64;
+            action_pointers.push((self.gen, range).into());
+        }
+        Ok((action_pointers, overhead))
+    }
+
+    // Flush the records written so far and fsync them, if the durability policy requires it.
+    fn commit(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        let sync = match self.durability {
+            Durability::Never => false,
+            Durability::Always => true,
+            Durability::Interval(ms) => self.last_sync.elapsed() >= Duration::from_millis(ms),
+            Durability::Writes(n) => self.unsynced >= n,
+        };
+        if sync {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    // Discard everything written since the given position, so that the records of a write
+    // which failed half-way through aren't committed along with the next one. The segment is
+    // opened again, in case it's the file handle that failed.
+    fn rollback(&mut self, pos: u64) -> Result<()> {
+        let log_file = OpenOptions::new().append(true).open(&self.path)?;
+        // The old writer flushes whatever it has buffered as it's dropped, so the segment is
+        // only truncated after it's been replaced.
+        self.writer = BufWriterWithPointer::new(log_file.try_clone()?)?;
+        log_file.set_len(pos)?;
+        self.writer.seek(SeekFrom::Start(pos))?;
+        Ok(())
+    }
+
+    // Run the writes and commit them, or roll them all back if any of them fails.
+    fn commit_or_rollback<T>(&mut self, writes: impl FnOnce(&mut Self) -> Result<T>) -> Result<T> {
+        let pos = self.writer.pointer;
+        let res = writes(self).and_then(|written| {
+            self.commit()?;
+            Ok(written)
+        });
+        match &res {
+            Ok(_) => self.failed = None,
+            Err(err) => {
+                self.failed = Some(err.to_string());
+                if let Err(err) = self.rollback(pos) {
+                    error!("Could not roll back segment {}: {}", self.gen, err);
+                }
+            }
+        }
+        res
+    }
+
+    // fsync the segment, if anything has been written to it since the last fsync.
+    fn sync(&mut self) -> Result<()> {
+        if self.unsynced > 0 {
+            self.writer.flush()?;
+            self.writer.writer.get_ref().sync_data()?;
+            self.unsynced = 0;
+        }
+        self.last_sync = Instant::now();
+        Ok(())
+    }
+}
+
+// fsync the active segment every interval, so that writes made while the store is
+// idle don't wait indefinitely. The thread exits once the store is dropped.
+fn spawn_syncer(writer: Weak<Mutex<SegmentWriter>>, interval: Duration) {
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+        let writer = match writer.upgrade() {
+            Some(writer) => writer,
+            None => return,
+        };
+        let mut writer = match writer.lock() {
+            Ok(writer) => writer,
+            Err(_) => return,
+        };
+        if writer.last_sync.elapsed() >= interval {
+            if let Err(err) = writer.sync() {
+                error!("Could not sync segment {}: {}", writer.gen, err);
+                writer.failed = Some(err.to_string());
+            }
+        }
+    });
+}
+
+// Condition that has to hold for a write to be committed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Condition {
+    // Sets are always committed, removes only if the key exists.
+    None,
+    // The key exists in the index, but has expired.
+    Expired,
+    // The key doesn't exist.
+    Absent,
+    // The key exists and is at this version.
+    Version(u64),
+}
+
+// Writes waiting to be committed, which are applied all-or-nothing.
+struct PendingWrite {
+    ticket: u64,
+    writes: Vec<(Action, Condition)>,
+}
+
+// Result of a committed write: the old value of the key and for sets, the new version of the key.
+struct Written {
+    old_val: Option<Vec<u8>>,
+    version: Option<u64>,
+}
+
+// Actions of a pending write, along with pointers to them, once they're appended to the log.
+type Appended = Vec<(Action, ActionPointer)>;
+
+// Writes waiting to be committed, along with the results of the committed ones.
+#[derive(Default)]
+struct CommitQueue {
+    pending: Vec<PendingWrite>,
+    results: HashMap<u64, Result<Vec<Written>>>,
+    next_ticket: u64,
+    // Whether a writer is committing a batch at the moment.
+    committing: bool,
+}
+
+/// Configuration used while opening a KVStore.
+#[derive(Debug, Clone)]
+pub struct KVStoreConfig {
+    /// Amount of stale bytes, i.e. bytes belonging to actions that have been overwritten
+    /// or removed, after which the log is compacted automatically.
+    pub compaction_threshold: u64,
+    /// Size in bytes after which the active segment is closed and a new one is started.
+    pub segment_size: u64,
+    /// When to fsync the writes to the log.
+    pub durability: Durability,
+    /// Size in bytes up to which the values read by `get`, along with their keys, are cached
+    /// in memory. The least recently used values are evicted first. Caches of a few MiB or more
+    /// are split into shards, each with its share of the size. 0 disables the cache.
+    pub cache_size: u64,
+    /// Log written as a single file, before the log was split into segments. It's imported
+    /// as the first segment of a store that has no segments yet, and is left untouched.
+    pub legacy_log: Option<PathBuf>,
+}
+
+impl Default for KVStoreConfig {
+    fn default() -> Self {
+        KVStoreConfig {
+            compaction_threshold: DEFAULT_COMPACTION_THRESHOLD,
+            segment_size: DEFAULT_SEGMENT_SIZE,
+            durability: Durability::default(),
+            cache_size: DEFAULT_CACHE_SIZE,
+            legacy_log: None,
+        }
+    }
+}
+
 /// KVStore provides methods to set, get and delete key-value pairs.
 ///
-/// All mutable actions are stored in a log file for persistence.
-/// A map stores the key along with a pointer, pointing to the file offset
-/// where the latest action corresponding to the key is stored in the file.
+/// All mutable actions are stored in a log for persistence. The log is split into
+/// segments, i.e. files in the store's directory named after their generation.
+/// Only the segment with the latest generation is ever written to, once it grows
+/// beyond the configured size it's closed and a new segment is started.
+/// A map stores the key along with a pointer, pointing to the segment and the offset
+/// where the latest action corresponding to the key is stored.
+///
+/// Since the log is append-only, it keeps growing with every action. Once the
+/// amount of stale bytes crosses the configured threshold, the log is compacted,
+/// i.e. the live actions are rewritten into a new segment and the older segments
+/// are deleted.
+///
+/// Reads only share the locks on the index and the segments, and read the segments with
+/// positional reads, so any number of them can proceed in parallel.
+///
+/// Keys and values are arbitrary bytes, anything that converts into a `Vec<u8>`
+/// can be passed in, e.g. a `String`, a `&str` or a `&[u8]`.
 ///
 /// ```rust
 /// use kv_store::store::KVStore;
 /// fn main() {
-///     let log_path = "/tmp/store.log";
-///     let store = KVStore::open(log_path).unwrap();
-///     let key = String::from("this is");
-///     let val = String::from("the way");
-///     if let Some(value) = store.set(key.clone(), val).unwrap() {
-///         println!("{}", value);
+///     let dir = "/tmp/store";
+///     let store = KVStore::open(dir).unwrap();
+///     if let Some(value) = store.set("this is", "the way").unwrap() {
+///         println!("{}", String::from_utf8_lossy(&value));
 ///     }
-///     if let Some(value) = store.get(key).unwrap() {
-///         println!("{}", value);
+///     if let Some(value) = store.get("this is").unwrap() {
+///         println!("{}", String::from_utf8_lossy(&value));
 ///     }
+///     store.set(&[0xde, 0xad][..], vec![0xbe, 0xef]).unwrap();
 /// }
 /// ```
 pub struct KVStore {
-    reader: Mutex<BufReaderWithPointer<File>>,
-    writer: Mutex<BufWriterWithPointer<File>>,
-    index: Mutex<BTreeMap<String, ActionPointer>>,
+    path: PathBuf,
+    config: KVStoreConfig,
+    // Locked for as long as the store is open, so that no other process opens the directory.
+    _lock: File,
+    // Segments are read with positional reads, so they can be read from concurrently.
+    readers: RwLock<HashMap<u64, File>>,
+    writer: Arc<Mutex<SegmentWriter>>,
+    index: RwLock<BTreeMap<Vec<u8>, ActionPointer>>,
+    // Number of keys in the index that expire at each point in time, so that the keys which
+    // have expired can be counted without going through the index. It's only updated while
+    // holding the index lock for writing.
+    expiring: Mutex<BTreeMap<u64, u64>>,
+    // Values of the keys read recently, if caching is enabled. It's only updated while holding
+    // the index lock, for reading when caching values and for writing when evicting them.
+    cache: Option<ShardedCache>,
+    // Number of bytes in the log which belong to actions that are no longer live.
+    uncompacted: AtomicU64,
+    // Number of compactions run since the store was opened.
+    compactions: AtomicU64,
+    // Held while compacting or taking a snapshot, since a compaction deletes the segments
+    // that a snapshot is being copied from.
+    compaction: Mutex<()>,
+    queue: Mutex<CommitQueue>,
+    committed: Condvar,
+    // Where the changes to the keys are sent as they're applied, if they're watched.
+    sink: RwLock<Option<ChangeSink>>,
 }
 
 impl KVStore {
-    /// Accepts a path to the open/create the log file. Parses the log file and load
-    /// the keys and the pointers in the index. Returns a KVStore for use.
+    /// Accepts a path to the open/create the directory containing the log. Parses all
+    /// segments of the log and load the keys and the pointers in the index.
+    /// Returns a KVStore for use.
     pub fn open(path: impl Into<PathBuf>) -> Result<KVStore> {
+        KVStore::open_with_config(path, KVStoreConfig::default())
+    }
+
+    /// Same as `open`, but uses the provided config instead of the default one.
+    pub fn open_with_config(path: impl Into<PathBuf>, config: KVStoreConfig) -> Result<KVStore> {
         let path = path.into();
-        let log_file = OpenOptions::new()
-            .create(true)
-            .write(true)
-            .append(true)
-            .open(&path)?;
-
-        let mut index = Mutex::new(BTreeMap::new());
-        let mut reader = Mutex::new(BufReaderWithPointer::new(File::open(&path)?)?);
-        let mut writer = Mutex::new(BufWriterWithPointer::new(log_file)?);
-
-        let load_index = index.get_mut().map_err(|_| KVStoreError::Lock)?;
-        let load_reader = reader.get_mut().map_err(|_| KVStoreError::Lock)?;
-        let load_writer = writer.get_mut().map_err(|_| KVStoreError::Lock)?;
-        load(load_reader, load_index, load_writer)?;
-
-        Ok(KVStore {
-            reader,
-            index,
+        fs::create_dir_all(&path)?;
+        let lock = lock_dir(&path)?;
+        remove_temp_files(&path)?;
+        if let Some(legacy_log) = &config.legacy_log {
+            import_legacy_log(&path, legacy_log)?;
+        }
+
+        let mut index = BTreeMap::new();
+        let mut readers = HashMap::new();
+        let mut uncompacted = 0;
+
+        let mut migrate = false;
+        let gens = sorted_gens(&path)?;
+        let last_gen = gens.last().copied();
+        let mut last_keys = BTreeSet::new();
+        for &gen in &gens {
+            let (mut reader, version) = open_segment(&path, gen)?;
+            migrate |= version != record::SEGMENT_VERSION;
+            let hint = match version {
+                record::SEGMENT_VERSION => load_hint(&path, gen),
+                _ => None,
+            };
+            let hint = match hint {
+                Some(hint) => hint,
+                None => {
+                    let hint = scan_segment(&path, gen, version, &mut reader)?;
+                    // The other segments are closed, so they can have hint files now, whereas
+                    // the last one might be appended to, see below.
+                    if version == record::SEGMENT_VERSION && Some(gen) != last_gen {
+                        hint::write_hint(&path, gen, &hint)?;
+                    }
+                    hint
+                }
+            };
+            if Some(gen) == last_gen {
+                last_keys = hint.entries.iter().map(|(key, _)| key.clone()).collect();
+            }
+            uncompacted += apply_hint(gen, hint, &mut index);
+            readers.insert(gen, reader.reader.into_inner());
+        }
+
+        // Rather than starting a new segment every time the store is opened, the last one is
+        // appended to until it's full. Segments in an older format are never appended to.
+        let reopen = match last_gen {
+            Some(gen) if !migrate => {
+                let len = fs::metadata(log_path(&path, gen))?.len();
+                len >= record::SEGMENT_HEADER_LEN && len < config.segment_size
+            }
+            _ => false,
+        };
+        let writer = match last_gen {
+            Some(gen) if reopen => {
+                // Its hint file, if it has one, would be out of date once it's appended to.
+                hint::remove_hint(&path, gen)?;
+                let mut writer = SegmentWriter::open(&path, gen, config.durability)?;
+                writer.keys = last_keys;
+                writer
+            }
+            _ => {
+                let gen = last_gen.unwrap_or(0) + 1;
+                let writer = SegmentWriter::create(&path, gen, config.durability)?;
+                readers.insert(gen, File::open(log_path(&path, gen))?);
+                writer
+            }
+        };
+        let writer = Arc::new(Mutex::new(writer));
+        if let Durability::Interval(ms) = config.durability {
+            spawn_syncer(Arc::downgrade(&writer), Duration::from_millis(ms));
+        }
+
+        let cache = match config.cache_size {
+            0 => None,
+            size => Some(ShardedCache::new(size)),
+        };
+        let mut expiring = BTreeMap::new();
+        for action_pointer in index.values() {
+            count_expiry(&mut expiring, None, Some(action_pointer));
+        }
+        let store = KVStore {
+            path,
+            config,
+            _lock: lock,
+            readers: RwLock::new(readers),
             writer,
-        })
+            index: RwLock::new(index),
+            expiring: Mutex::new(expiring),
+            cache,
+            uncompacted: AtomicU64::new(uncompacted),
+            compactions: AtomicU64::new(0),
+            compaction: Mutex::new(()),
+            queue: Mutex::new(CommitQueue::default()),
+            committed: Condvar::new(),
+            sink: RwLock::new(None),
+        };
+        // Segments in an older format are migrated by compacting them, since compaction
+        // rewrites all the live actions in the current format.
+        if migrate {
+            info!(
+                "Migrating the log to segment format v{}",
+                record::SEGMENT_VERSION
+            );
+            store.compact()?;
+        }
+        Ok(store)
     }
 
     /// Stores the key and it's value. If the key already existed, the old value is returned.
-    pub fn set(&self, key: String, val: String) -> Result<Option<String>> {
-        let action_key = key.clone();
-        let action = Action::Set {
-            key: action_key,
-            val,
+    pub fn set(&self, key: impl Into<Vec<u8>>, val: impl Into<Vec<u8>>) -> Result<Option<Vec<u8>>> {
+        self.set_with_expiry(key.into(), val.into(), None)
+    }
+
+    /// Same as `set`, but the key expires once the TTL has elapsed. Expired keys are treated
+    /// as missing and are eventually removed from the log by `reap_expired`.
+    pub fn set_with_ttl(
+        &self,
+        key: impl Into<Vec<u8>>,
+        val: impl Into<Vec<u8>>,
+        ttl: Duration,
+    ) -> Result<Option<Vec<u8>>> {
+        let expires_at = now_millis().saturating_add(ttl.as_millis() as u64);
+        self.set_with_expiry(key.into(), val.into(), Some(expires_at))
+    }
+
+    /// Gets the value related to the given key. If not found, returns a KeyNotFound error.
+    pub fn get(&self, key: impl Into<Vec<u8>>) -> Result<Option<Vec<u8>>> {
+        let key = key.into();
+        let index = self.index.read().map_err(|_| KVStoreError::Lock)?;
+        match index.get(&key) {
+            Some(action_pointer) if !action_pointer.is_expired(now_millis()) => {
+                self.read_cached_val(&key, action_pointer)
+            }
+            _ => Err(KVStoreError::KeyNotFound(display_key(&key))),
+        }
+    }
+
+    /// Gets the value related to the given key along with the version of the key, which is
+    /// bumped every time the key is set. If not found, returns a KeyNotFound error.
+    pub fn get_with_version(&self, key: impl Into<Vec<u8>>) -> Result<(Vec<u8>, u64)> {
+        let key = key.into();
+        let index = self.index.read().map_err(|_| KVStoreError::Lock)?;
+        match index.get(&key) {
+            Some(action_pointer) if !action_pointer.is_expired(now_millis()) => {
+                match self.read_cached_val(&key, action_pointer)? {
+                    Some(val) => Ok((val, action_pointer.version)),
+                    None => Err(KVStoreError::KeyNotFound(display_key(&key))),
+                }
+            }
+            _ => Err(KVStoreError::KeyNotFound(display_key(&key))),
+        }
+    }
+
+    /// Stores the key and it's value, only if the key doesn't exist. Returns the version of the
+    /// key. If the key already exists, returns a KeyExists error.
+    pub fn set_if_absent(&self, key: impl Into<Vec<u8>>, val: impl Into<Vec<u8>>) -> Result<u64> {
+        self.set_if(key.into(), val.into(), Condition::Absent)
+    }
+
+    /// Stores the key and it's value, only if the key is at the given version. Returns the new
+    /// version of the key. If the key doesn't exist or is at another version, returns a
+    /// VersionMismatch error.
+    pub fn set_if_version(
+        &self,
+        key: impl Into<Vec<u8>>,
+        val: impl Into<Vec<u8>>,
+        version: u64,
+    ) -> Result<u64> {
+        self.set_if(key.into(), val.into(), Condition::Version(version))
+    }
+
+    /// Returns the time left before the key expires, or None if it never does.
+    /// If not found, returns a KeyNotFound error.
+    pub fn ttl(&self, key: impl Into<Vec<u8>>) -> Result<Option<Duration>> {
+        let key = key.into();
+        let index = self.index.read().map_err(|_| KVStoreError::Lock)?;
+        let now = now_millis();
+        match index.get(&key) {
+            Some(action_pointer) if !action_pointer.is_expired(now) => Ok(action_pointer
+                .expires_at
+                .map(|expires_at| Duration::from_millis(expires_at - now))),
+            _ => Err(KVStoreError::KeyNotFound(display_key(&key))),
+        }
+    }
+
+    /// Returns an iterator over the key-value pairs with their keys in the range, ordered by key.
+    pub fn scan(&self, range: impl RangeBounds<Vec<u8>>) -> Scan<'_> {
+        Scan {
+            store: self,
+            range: (range.start_bound().cloned(), range.end_bound().cloned()),
+            pairs: VecDeque::new(),
+            done: false,
+        }
+    }
+
+    /// Returns an iterator over the key-value pairs with their keys starting with the prefix,
+    /// ordered by key.
+    pub fn scan_prefix(&self, prefix: impl AsRef<[u8]>) -> Scan<'_> {
+        self.scan(engines::prefix_range(prefix))
+    }
+
+    /// Returns the first key-value pairs, up to the limit, with their keys in the range.
+    /// Pass the cursor of the page as the excluded start of the range to get the next page.
+    pub fn scan_page(
+        &self,
+        range: impl RangeBounds<Vec<u8>>,
+        limit: usize,
+    ) -> Result<Page<(Vec<u8>, Vec<u8>)>> {
+        self.page(range, limit, |key, action_pointer| {
+            let val = self.read_val(action_pointer)?;
+            Ok(val.map(|val| (key.clone(), val)))
+        })
+    }
+
+    /// Same as `scan_page`, but only returns the keys, without reading their values.
+    pub fn keys_page(
+        &self,
+        range: impl RangeBounds<Vec<u8>>,
+        limit: usize,
+    ) -> Result<Page<Vec<u8>>> {
+        self.page(range, limit, |key, _| Ok(Some(key.clone())))
+    }
+
+    /// Checks that the log can still be written to, i.e. that the last write to it didn't fail,
+    /// and that files can still be written to its directory. If the last write did fail, the
+    /// active segment is flushed and synced again, to find out whether it's been fixed since.
+    /// Nothing is written to the log itself, a file is written next to it and removed instead.
+    pub fn check_writable(&self) -> Result<()> {
+        {
+            let mut writer = self.writer.lock().map_err(|_| KVStoreError::Lock)?;
+            if let Some(failed) = writer.failed.clone() {
+                let retry = writer
+                    .writer
+                    .flush()
+                    .and_then(|_| writer.writer.writer.get_ref().sync_data());
+                match retry {
+                    Ok(()) => writer.failed = None,
+                    Err(_) => return Err(KVStoreError::WriteFailed(failed)),
+                }
+            }
+        }
+        check_dir_writable(&self.path)
+    }
+
+    /// Returns statistics about the keys and the log. Keys which have expired aren't counted,
+    /// even if they haven't been reaped yet.
+    pub fn stats(&self) -> Result<EngineStats> {
+        let live_keys = {
+            let index = self.index.read().map_err(|_| KVStoreError::Lock)?;
+            let expiring = self.expiring.lock().map_err(|_| KVStoreError::Lock)?;
+            let expired: u64 = expiring
+                .range(..=now_millis())
+                .map(|(_, count)| count)
+                .sum();
+            index.len() as u64 - expired
+        };
+        let mut disk_bytes = 0;
+        {
+            let readers = self.readers.read().map_err(|_| KVStoreError::Lock)?;
+            for reader in readers.values() {
+                disk_bytes += reader.metadata()?.len();
+            }
+        }
+        let (cache_hits, cache_misses, cache_bytes) = match &self.cache {
+            Some(cache) => cache.stats()?,
+            None => (0, 0, 0),
+        };
+        Ok(EngineStats {
+            engine: String::from("kvs"),
+            live_keys,
+            disk_bytes,
+            stale_bytes: self.uncompacted.load(Ordering::SeqCst),
+            compactions: self.compactions.load(Ordering::SeqCst),
+            cache_hits,
+            cache_misses,
+            cache_bytes,
+        })
+    }
+
+    /// Removes a key and it's value from the store. Returns the current value of the key.
+    /// If key is not found, returns a KeyNotFound error.
+    pub fn rm(&self, key: impl Into<Vec<u8>>) -> Result<Option<Vec<u8>>> {
+        let action = Action::Remove { key: key.into() };
+        let written = self.write(action, Condition::None)?;
+        self.maybe_compact()?;
+        Ok(written.old_val)
+    }
+
+    /// Removes a key and it's value from the store, only if the key is at the given version.
+    /// Returns the current value of the key. If the key doesn't exist or is at another version,
+    /// returns a VersionMismatch error.
+    ///
+    /// Versions start over once a key is removed, so a key that's set again after being
+    /// removed can be at a version it was at before.
+    pub fn rm_if_version(&self, key: impl Into<Vec<u8>>, version: u64) -> Result<Option<Vec<u8>>> {
+        let action = Action::Remove { key: key.into() };
+        let written = self.write(action, Condition::Version(version))?;
+        self.maybe_compact()?;
+        Ok(written.old_val)
+    }
+
+    /// Adds the delta to the integer stored as the value of the key and returns the result.
+    /// Keys that don't exist start at 0, while keys that do keep their TTL. If the value isn't
+    /// an integer, returns a NotAnInteger error and if the result doesn't fit in an i64,
+    /// an Overflow error.
+    ///
+    /// The value is read and the result is written while holding the writer lock, so
+    /// concurrent increments of the same key never get lost.
+    pub fn incr(&self, key: impl Into<Vec<u8>>, delta: i64) -> Result<i64> {
+        let key = key.into();
+        let mut writer = self.writer.lock().map_err(|_| KVStoreError::Lock)?;
+        let now = now_millis();
+        let (val, expires_at, version) = {
+            let index = self.index.read().map_err(|_| KVStoreError::Lock)?;
+            match index.get(&key) {
+                Some(action_pointer) if !action_pointer.is_expired(now) => (
+                    self.read_val(action_pointer)?,
+                    action_pointer.expires_at,
+                    action_pointer.version + 1,
+                ),
+                _ => (None, None, record::FIRST_VERSION),
+            }
         };
-        let pointer = {
-            let writer = &mut *self.writer.lock().map_err(|_| KVStoreError::Lock)?;
-            let point = writer.pointer;
-            serde_json::to_writer(writer, &action)?;
-            point
+        let counter = engines::apply_delta(&key, val.as_deref(), delta)?;
+        let action = Action::Set {
+            key,
+            val: counter.to_string().into_bytes(),
+            expires_at,
+            version,
         };
+        let mut action_pointer =
+            writer.commit_or_rollback(|writer| writer.write(&action.encode()))?;
+        writer.keys.insert(action.key().to_vec());
+        action_pointer.expires_at = expires_at;
+        action_pointer.version = version;
         {
-            let writer = &mut *self.writer.lock().map_err(|_| KVStoreError::Lock)?;
-            writer.flush()?;
+            let mut index = self.index.write().map_err(|_| KVStoreError::Lock)?;
+            self.apply(&mut index, action, action_pointer, now)?;
+        }
+        self.maybe_roll(&mut writer)?;
+        drop(writer);
+        self.maybe_compact()?;
+        Ok(counter)
+    }
+
+    /// Same as `incr`, but subtracts the delta instead.
+    pub fn decr(&self, key: impl Into<Vec<u8>>, delta: i64) -> Result<i64> {
+        let key = key.into();
+        match delta.checked_neg() {
+            Some(delta) => self.incr(key, delta),
+            None => Err(KVStoreError::Overflow(display_key(&key))),
+        }
+    }
+
+    /// Starts a transaction, to stage writes which are then committed atomically.
+    pub fn begin(&self) -> Transaction<'_> {
+        Transaction {
+            store: self,
+            ops: vec![],
+        }
+    }
+
+    /// Commits the writes atomically, in a single record in the log, either all of them are
+    /// applied or none of them, even if the store goes down in the middle of the commit.
+    /// Returns the old value of the key for each write, in order. If any of the keys to be
+    /// removed doesn't exist, returns a KeyNotFound error.
+    pub fn commit_txn(&self, ops: Vec<WriteOp>) -> Result<Vec<Option<Vec<u8>>>> {
+        let writes = ops
+            .into_iter()
+            .map(|op| (write_action(op), Condition::None))
+            .collect();
+        let written = self.write_batch(writes)?;
+        self.maybe_compact()?;
+        Ok(written.into_iter().map(|written| written.old_val).collect())
+    }
+
+    /// Gets the values of all the keys at once, as of the same point in time, in order.
+    /// Keys that don't exist have no value.
+    pub fn get_many(&self, keys: Vec<Vec<u8>>) -> Result<Vec<Option<Vec<u8>>>> {
+        let index = self.index.read().map_err(|_| KVStoreError::Lock)?;
+        let now = now_millis();
+        keys.into_iter()
+            .map(|key| match index.get(&key) {
+                Some(action_pointer) if !action_pointer.is_expired(now) => {
+                    self.read_cached_val(&key, action_pointer)
+                }
+                _ => Ok(None),
+            })
+            .collect()
+    }
+
+    /// Applies the writes in order, with a single commit. Unlike with `commit_txn`, each write
+    /// succeeds or fails on its own, e.g. removing a key that doesn't exist fails without
+    /// affecting the other writes. Returns the result of each write, in order: the old value
+    /// of the key, or the error the write failed with.
+    pub fn write_many(&self, ops: Vec<WriteOp>) -> Result<Vec<Result<Option<Vec<u8>>>>> {
+        let groups = ops
+            .into_iter()
+            .map(|op| vec![(write_action(op), Condition::None)])
+            .collect();
+        let results = self.write_groups(groups)?;
+        self.maybe_compact()?;
+        Ok(results
+            .into_iter()
+            .map(|written| Ok(written?.remove(0).old_val))
+            .collect())
+    }
+
+    /// Appends a remove action for every key that has expired. Returns the number of keys removed.
+    pub fn reap_expired(&self) -> Result<usize> {
+        let expired: Vec<Vec<u8>> = {
+            let index = self.index.read().map_err(|_| KVStoreError::Lock)?;
+            let now = now_millis();
+            index
+                .iter()
+                .filter(|(_, action_pointer)| action_pointer.is_expired(now))
+                .map(|(key, _)| key.clone())
+                .collect()
         };
-        let writer = &mut *self.writer.lock().map_err(|_| KVStoreError::Lock)?;
-        let action_pointer: ActionPointer = (pointer..writer.pointer).into();
-        let mut index = self.index.lock().map_err(|_| KVStoreError::Lock)?;
-        if let Some(old_action_pointer) = index.insert(key, action_pointer) {
-            let mut reader = self.reader.lock().map_err(|_| KVStoreError::Lock)?;
-            reader.seek(SeekFrom::Start(old_action_pointer.pos))?;
-            let mut buf = vec![0; old_action_pointer.len as usize];
-            reader.read_exact(&mut buf)?;
-            if let Action::Set { val, .. } = serde_json::from_slice(buf.as_slice())? {
-                return Ok(Some(val));
+        let mut reaped = 0;
+        for key in expired {
+            // The key might have been set again since, in which case it's left alone.
+            match self.write(Action::Remove { key }, Condition::Expired) {
+                Ok(_) => reaped += 1,
+                Err(KVStoreError::KeyNotFound(_)) => {}
+                Err(err) => return Err(err),
             }
         }
-        Ok(None)
+        self.maybe_compact()?;
+        Ok(reaped)
     }
 
-    /// Gets the value related to the given key. If not found, returns a KeyNotFound error.
-    pub fn get(&self, key: String) -> Result<Option<String>> {
-        let index = self.index.lock().map_err(|_| KVStoreError::Lock)?;
-        if let Some(action_pointer) = index.get(&key) {
-            let mut reader = self.reader.lock().map_err(|_| KVStoreError::Lock)?;
-            reader.seek(SeekFrom::Start(action_pointer.pos))?;
+    /// Rewrites the live actions into a new segment and deletes all the older segments.
+    /// Returns the number of bytes reclaimed.
+    ///
+    /// Writers are blocked for the entire duration of the compaction, whereas readers
+    /// are only blocked while the index is pointed to the compacted segment.
+    /// If a snapshot is being taken, the compaction waits until it's done.
+    pub fn compact(&self) -> Result<u64> {
+        let _compaction = self.compaction.lock().map_err(|_| KVStoreError::Lock)?;
+        self.compact_locked()
+    }
+
+    /// Writes a consistent and compacted copy of the store, as of the moment it's called, to
+    /// the directory, which is created if needed and must not contain any segments yet.
+    /// The copy is a store of its own, so it can be opened as is, or copied elsewhere
+    /// with `restore`. Expired keys are left out of it.
+    ///
+    /// Writers are only blocked while the position in the log to copy up to is taken, the
+    /// live actions are then copied while writes continue. Compactions wait until the copy
+    /// is done, whereas automatic ones are skipped in the meantime.
+    pub fn snapshot(&self, dest: impl Into<PathBuf>) -> Result<SnapshotInfo> {
+        let dest = dest.into();
+        fs::create_dir_all(&dest)?;
+        if !sorted_gens(&dest)?.is_empty() {
+            return Err(KVStoreError::DirNotEmpty(dest.display().to_string()));
+        }
+        let _compaction = self.compaction.lock().map_err(|_| KVStoreError::Lock)?;
+
+        // Every mutation is applied to the index while holding the writer lock, so while it's
+        // held, the index is exactly the state of the store as of the end of the log.
+        let live: Vec<(Vec<u8>, ActionPointer)> = {
+            let mut writer = self.writer.lock().map_err(|_| KVStoreError::Lock)?;
+            writer.writer.flush()?;
+            let index = self.index.read().map_err(|_| KVStoreError::Lock)?;
+            let now = now_millis();
+            index
+                .iter()
+                .filter(|(_, action_pointer)| !action_pointer.is_expired(now))
+                .map(|(key, action_pointer)| (key.clone(), *action_pointer))
+                .collect()
+        };
+
+        // Segments are never rewritten in place, and the ones pointed to can't be deleted
+        // while the compaction lock is held, so the actions can be copied without the index.
+        let snapshot_path = dest.join(format!("{}.compact", SNAPSHOT_GEN));
+        let mut snapshot_writer = BufWriterWithPointer::new(File::create(&snapshot_path)?)?;
+        record::write_segment_header(&mut snapshot_writer)?;
+        let mut entries = Vec::with_capacity(live.len());
+        for (key, action_pointer) in live {
             let mut buf = vec![0; action_pointer.len as usize];
-            reader.read_exact(&mut buf)?;
-            if let Action::Set { val, .. } = serde_json::from_slice(buf.as_slice())? {
-                return Ok(Some(val));
+            {
+                let readers = self.readers.read().map_err(|_| KVStoreError::Lock)?;
+                let reader = readers
+                    .get(&action_pointer.gen)
+                    .ok_or(KVStoreError::Corrupt)?;
+                read_exact_at(reader, &mut buf, action_pointer.pos)?;
             }
-            return Ok(None);
+            let pos = snapshot_writer.pointer;
+            snapshot_writer.write_all(&buf)?;
+            let entry = HintEntry::Set {
+                pos,
+                len: action_pointer.len,
+                expires_at: action_pointer.expires_at,
+                version: action_pointer.version,
+            };
+            entries.push((key, entry));
         }
-        Err(KVStoreError::KeyNotFound(key))
+        snapshot_writer.flush()?;
+        snapshot_writer.writer.get_ref().sync_all()?;
+        fs::rename(&snapshot_path, log_path(&dest, SNAPSHOT_GEN))?;
+        let keys = entries.len() as u64;
+        hint::write_hint(&dest, SNAPSHOT_GEN, &Hint { entries, stale: 0 })?;
+        let bytes =
+            snapshot_writer.pointer + fs::metadata(hint::hint_path(&dest, SNAPSHOT_GEN))?.len();
+        Ok(SnapshotInfo { keys, bytes })
     }
 
-    /// Removes a key and it's value from the store. Returns the current value of the key.
-    /// If key is not found, returns a KeyNotFound error.
-    pub fn rm(&self, key: String) -> Result<Option<String>> {
-        let index = self.index.lock().map_err(|_| KVStoreError::Lock)?;
-        if index.contains_key(&key) {
-            let action = Action::Remove { key };
-            {
-                let writer = &mut *self.writer.lock().map_err(|_| KVStoreError::Lock)?;
-                serde_json::to_writer(writer, &action)?;
+    /// Copies the snapshot in the given directory, written by `snapshot`, to the directory
+    /// of a new store, which must not contain any segments yet, and opens the new store
+    /// using the provided config. The snapshot itself is left untouched.
+    pub fn restore(
+        snapshot: impl AsRef<Path>,
+        path: impl Into<PathBuf>,
+        config: KVStoreConfig,
+    ) -> Result<KVStore> {
+        let snapshot = snapshot.as_ref();
+        let path = path.into();
+        let gens = match sorted_gens(snapshot) {
+            Ok(gens) if !gens.is_empty() => gens,
+            _ => {
+                return Err(KVStoreError::InvalidSnapshot(
+                    snapshot.display().to_string(),
+                ))
             }
-            {
-                let writer = &mut *self.writer.lock().map_err(|_| KVStoreError::Lock)?;
-                writer.flush()?;
-            }
-            if let Action::Remove { key } = action {
-                std::mem::drop(index);
-                println!("adsdad");
-                let val = self.get(key.clone());
-                let index = &mut *self.index.lock().map_err(|_| KVStoreError::Lock)?;
-                index.remove(&key);
-                return val;
-            }
-            Ok(None)
-        } else {
-            Err(KVStoreError::KeyNotFound(key))
+        };
+        fs::create_dir_all(&path)?;
+        if !sorted_gens(&path)?.is_empty() {
+            return Err(KVStoreError::DirNotEmpty(path.display().to_string()));
         }
+        for gen in gens {
+            copy_file(&log_path(snapshot, gen), &log_path(&path, gen))?;
+            let hint_path = hint::hint_path(snapshot, gen);
+            if hint_path.is_file() {
+                copy_file(&hint_path, &hint::hint_path(&path, gen))?;
+            }
+        }
+        KVStore::open_with_config(path, config)
     }
-}
 
-// Parse the log file and populate the index.
-fn load(
-    reader: &mut BufReaderWithPointer<File>,
-    index: &mut BTreeMap<String, ActionPointer>,
-    writer: &mut BufWriterWithPointer<File>
-) -> Result<()> {
-    let mut pointer = reader.seek(SeekFrom::Start(0))?;
-    let mut stream = Deserializer::from_reader(reader).into_iter::<Action>();
-    while let Some(action) = stream.next() {
-        let new_pointer = stream.byte_offset() as u64;
-        match action? {
-            Action::Set { key, .. } => {
-                let action_pointer: ActionPointer = (pointer..new_pointer).into();
-                index.insert(key, action_pointer);
+    /// Stores every key-value pair read from the reader, in the given format, overwriting the
+    /// keys that already exist. Returns the number of pairs imported. See `bulk::import`.
+    pub fn import(&self, reader: impl Read, format: Format) -> Result<u64> {
+        bulk::import(self, reader, format)
+    }
+
+    /// Writes every key-value pair in the store to the writer, in the given format, ordered
+    /// by key. Returns the number of pairs exported. See `bulk::export`.
+    pub fn export(&self, writer: impl Write, format: Format) -> Result<u64> {
+        bulk::export(self, writer, format)
+    }
+
+    // Compact the log, while holding the compaction lock.
+    fn compact_locked(&self) -> Result<u64> {
+        let mut writer = self.writer.lock().map_err(|_| KVStoreError::Lock)?;
+        writer.writer.flush()?;
+
+        // Since every mutation needs the writer lock, the index can't change
+        // until this compaction is done. Take a copy and let the readers be.
+        let live: Vec<(Vec<u8>, ActionPointer)> = {
+            let index = self.index.read().map_err(|_| KVStoreError::Lock)?;
+            index
+                .iter()
+                .map(|(key, action_pointer)| (key.clone(), *action_pointer))
+                .collect()
+        };
+        let stale_gens = sorted_gens(&self.path)?;
+        let mut old_len = 0;
+        for &gen in &stale_gens {
+            old_len += fs::metadata(log_path(&self.path, gen))?.len();
+        }
+
+        // The compacted segment is written under a temporary name and only renamed
+        // once it's complete, so that a crash never leaves a partial segment behind.
+        let compaction_gen = writer.gen + 1;
+        let compaction_path = self.path.join(format!("{}.compact", compaction_gen));
+        let mut compaction_writer = BufWriterWithPointer::new(
+            OpenOptions::new()
+                .create(true)
+                .write(true)
+                .truncate(true)
+                .open(&compaction_path)?,
+        )?;
+        record::write_segment_header(&mut compaction_writer)?;
+        let mut log_readers = HashMap::new();
+        let mut compacted = Vec::with_capacity(live.len());
+        // Expired keys are not rewritten. Since all the older segments are deleted
+        // along with their actions, they don't need a remove action either.
+        let mut expired = vec![];
+        let now = now_millis();
+        for (key, action_pointer) in live {
+            if action_pointer.is_expired(now) {
+                expired.push(key);
+                continue;
             }
-            Action::Remove { key } => {
-                index.remove(&key);
+            let (log_reader, version) = match log_readers.entry(action_pointer.gen) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => entry.insert(open_segment(&self.path, action_pointer.gen)?),
+            };
+            log_reader.seek(SeekFrom::Start(action_pointer.pos))?;
+            let pointer = compaction_writer.pointer;
+            if *version == record::SEGMENT_VERSION {
+                let mut action_reader = log_reader.take(action_pointer.len);
+                io::copy(&mut action_reader, &mut compaction_writer)?;
+            } else {
+                // Actions in an older format are re-encoded in the current one.
+                let mut buf = vec![0; action_pointer.len as usize];
+                log_reader.read_exact(&mut buf)?;
+                let action = record::decode_action(*version, &buf)?;
+                record::write_record(&mut compaction_writer, &action.encode())?;
             }
+            compacted.push((
+                key,
+                ActionPointer {
+                    expires_at: action_pointer.expires_at,
+                    version: action_pointer.version,
+                    ..(compaction_gen, pointer..compaction_writer.pointer).into()
+                },
+            ));
         }
-        pointer = new_pointer;
+        compaction_writer.flush()?;
+        compaction_writer.writer.get_ref().sync_all()?;
+        let new_len = compaction_writer.pointer;
+        fs::rename(&compaction_path, log_path(&self.path, compaction_gen))?;
+        let compaction_hint = Hint {
+            entries: compacted
+                .iter()
+                .map(|(key, action_pointer): &(Vec<u8>, ActionPointer)| {
+                    let entry = HintEntry::Set {
+                        pos: action_pointer.pos,
+                        len: action_pointer.len,
+                        expires_at: action_pointer.expires_at,
+                        version: action_pointer.version,
+                    };
+                    (key.clone(), entry)
+                })
+                .collect(),
+            stale: 0,
+        };
+        hint::write_hint(&self.path, compaction_gen, &compaction_hint)?;
+
+        let new_writer =
+            SegmentWriter::create(&self.path, compaction_gen + 1, self.config.durability)?;
+        {
+            let mut index = self.index.write().map_err(|_| KVStoreError::Lock)?;
+            let mut readers = self.readers.write().map_err(|_| KVStoreError::Lock)?;
+            for gen in compaction_gen..=new_writer.gen {
+                readers.insert(gen, File::open(log_path(&self.path, gen))?);
+            }
+            for (key, action_pointer) in compacted {
+                let old_action_pointer = index.insert(key, action_pointer);
+                self.track_expiry(old_action_pointer.as_ref(), Some(&action_pointer))?;
+            }
+            // Expired keys left out of the compacted segment are gone as if they were reaped.
+            for key in expired {
+                self.evict(&key)?;
+                self.publish(|| Change::Rm { key: key.clone() })?;
+                let old_action_pointer = index.remove(&key);
+                self.track_expiry(old_action_pointer.as_ref(), None)?;
+            }
+            for gen in &stale_gens {
+                readers.remove(gen);
+            }
+        }
+        *writer = new_writer;
+        self.uncompacted.store(0, Ordering::SeqCst);
+        self.compactions.fetch_add(1, Ordering::SeqCst);
+
+        for gen in stale_gens {
+            fs::remove_file(log_path(&self.path, gen))?;
+            hint::remove_hint(&self.path, gen)?;
+        }
+        Ok(old_len.saturating_sub(new_len))
     }
-    writer.seek(SeekFrom::Start(pointer))?;
-    Ok(())
-}
 
-#[cfg(test)]
-mod test {
+    // Append the action to the log and update the index, if the condition holds.
+    fn write(&self, action: Action, condition: Condition) -> Result<Written> {
+        let mut written = self.write_batch(vec![(action, condition)])?;
+        Ok(written.remove(0))
+    }
 
-    use super::*;
-    use rand::Rng;
-    use std::{fs, panic};
+    // Append the actions to the log and update the index, if all of their conditions hold.
+    // Either all of the actions are applied or none of them.
+    //
+    // Concurrent writes are committed in groups: the first writer to find no commit in
+    // progress becomes the leader and commits all the writes queued up till then with a
+    // single flush (and fsync, as per the durability policy). The other writers wait for
+    // the leader to hand over their results, which happens once their writes are durable.
+    fn write_batch(&self, writes: Vec<(Action, Condition)>) -> Result<Vec<Written>> {
+        self.write_groups(vec![writes])?.remove(0)
+    }
 
-    fn run_test<T>(test: T) -> ()
-    where
-        T: FnOnce(KVStore) -> () + panic::UnwindSafe,
-    {
-        // Since tests are run in parallel by default, we cannot have multiple unit tests working on the same file.
-        let mut rng = rand::thread_rng();
-        let n: u16 = rng.gen();
-        let log_path = format!("/tmp/{}.log", n);
-        let store = KVStore::open(log_path.clone()).unwrap();
+    // Same as `write_batch`, but for several groups of actions, each of which is applied
+    // all-or-nothing on its own. Returns the result of each group, in order.
+    //
+    // The groups are queued up together, so they're all committed by the same leader.
+    fn write_groups(
+        &self,
+        groups: Vec<Vec<(Action, Condition)>>,
+    ) -> Result<Vec<Result<Vec<Written>>>> {
+        let mut queue = self.queue.lock().map_err(|_| KVStoreError::Lock)?;
+        let mut tickets = Vec::with_capacity(groups.len());
+        for writes in groups {
+            let ticket = queue.next_ticket;
+            queue.next_ticket += 1;
+            queue.pending.push(PendingWrite { ticket, writes });
+            tickets.push(ticket);
+        }
+        loop {
+            if tickets
+                .iter()
+                .all(|ticket| queue.results.contains_key(ticket))
+            {
+                return Ok(tickets
+                    .iter()
+                    .filter_map(|ticket| queue.results.remove(ticket))
+                    .collect());
+            }
+            if queue.committing {
+                queue = self.committed.wait(queue).map_err(|_| KVStoreError::Lock)?;
+                continue;
+            }
+            queue.committing = true;
+            let batch = std::mem::take(&mut queue.pending);
+            drop(queue);
 
-        let result = panic::catch_unwind(move || test(store));
+            let tickets: Vec<u64> = batch.iter().map(|write| write.ticket).collect();
+            let results: Vec<(u64, Result<Vec<Written>>)> = match self.commit(batch) {
+                Ok(results) => tickets.into_iter().zip(results).collect(),
+                Err(err) => tickets
+                    .into_iter()
+                    .map(|ticket| (ticket, Err(batch_error(&err))))
+                    .collect(),
+            };
 
-        fs::remove_file(log_path).unwrap();
+            queue = self.queue.lock().map_err(|_| KVStoreError::Lock)?;
+            queue.committing = false;
+            queue.results.extend(results);
+            self.committed.notify_all();
+        }
+    }
 
-        assert!(result.is_ok())
+    // Append a batch of pending writes to the log with a single commit and update the index.
+    // Returns the result of each pending write, in order.
+    //
+    // The conditions, and the versions of the keys being set, are evaluated against the
+    // index along with the writes earlier in the batch, so actions are only encoded here.
+    // Pending writes with more than one action are written as a single batch record, so
+    // that they're applied all-or-nothing when the log is loaded as well.
+    fn commit(&self, batch: Vec<PendingWrite>) -> Result<Vec<Result<Vec<Written>>>> {
+        // Locks are always acquired in the order: writer, index, readers or cache.
+        let mut writer = self.writer.lock().map_err(|_| KVStoreError::Lock)?;
+        let now = now_millis();
+        let (appended, overhead) = {
+            let index = self.index.read().map_err(|_| KVStoreError::Lock)?;
+            // If any write fails, none of the batch is committed.
+            writer.commit_or_rollback(|writer| append(writer, &index, batch, now))?
+        };
+        self.uncompacted.fetch_add(overhead, Ordering::SeqCst);
+
+        let results = {
+            let mut index = self.index.write().map_err(|_| KVStoreError::Lock)?;
+            appended
+                .into_iter()
+                .map(|appended| {
+                    appended?
+                        .into_iter()
+                        .map(|(action, action_pointer)| {
+                            writer.keys.insert(action.key().to_vec());
+                            self.apply(&mut index, action, action_pointer, now)
+                        })
+                        .collect()
+                })
+                .collect()
+        };
+        self.maybe_roll(&mut writer)?;
+        Ok(results)
     }
 
-    #[test]
-    fn test_set() {
-        run_test(|store: KVStore| {
-            let key = String::from("this is");
-            let val = String::from("the way");
-            let res = store.set(key.clone(), val).unwrap();
-            assert_eq!(res, None);
-            let val = String::from("not the way");
-            let res = store.set(key, val).unwrap();
-            assert_eq!(res, Some(String::from("the way")));
-        })
+    /// Sends every change made to the keys from now on to the sink. Changes are sent as
+    /// they're applied to the index, while the writer lock is held, so they're sent in the
+    /// same order as they're written to the log.
+    pub fn watch(&self, sink: ChangeSink) -> Result<()> {
+        *self.sink.write().map_err(|_| KVStoreError::Lock)? = Some(sink);
+        Ok(())
     }
 
-    #[test]
-    fn test_get() {
-        run_test(|store: KVStore| {
-            let key = String::from("this is");
-            let val = String::from("the way");
-            let res = store.set(key.clone(), val).unwrap();
-            assert_eq!(res, None);
-            let res = store.get(key).unwrap();
-            assert_eq!(res, Some(String::from("the way")));
-        })
+    // Send the change to the sink, if the keys are watched.
+    fn publish(&self, change: impl FnOnce() -> Change) -> Result<()> {
+        if let Some(sink) = &*self.sink.read().map_err(|_| KVStoreError::Lock)? {
+            sink.publish(change());
+        }
+        Ok(())
     }
 
-    #[test]
-    fn test_rm() {
-        run_test(|store: KVStore| {
-            let key = String::from("this is");
-            let val = String::from("the way");
-            let res = store.set(key, val).unwrap();
-            assert_eq!(res, None);
-            let key = String::from("this is");
-            let res = store.rm(key.clone()).unwrap();
-            assert_eq!(res, Some(String::from("the way")));
+    // Point the index to an action which has been appended to the log, and publish the change.
+    // Callers hold the writer lock.
+    fn apply(
+        &self,
+        index: &mut BTreeMap<Vec<u8>, ActionPointer>,
+        action: Action,
+        action_pointer: ActionPointer,
+        now: u64,
+    ) -> Result<Written> {
+        match action {
+            Action::Set { key, val, .. } => {
+                self.evict(&key)?;
+                self.publish(|| Change::Set {
+                    key: key.clone(),
+                    val,
+                })?;
+                let old_action_pointer = index.insert(key, action_pointer);
+                self.track_expiry(old_action_pointer.as_ref(), Some(&action_pointer))?;
+                let old_val = match old_action_pointer {
+                    Some(old_action_pointer) => {
+                        self.uncompacted
+                            .fetch_add(old_action_pointer.len, Ordering::SeqCst);
+                        self.read_old_val(&old_action_pointer, now)?
+                    }
+                    None => None,
+                };
+                Ok(Written {
+                    old_val,
+                    version: Some(action_pointer.version),
+                })
+            }
+            Action::Remove { key } => {
+                self.evict(&key)?;
+                self.publish(|| Change::Rm { key: key.clone() })?;
+                let old_action_pointer = index.remove(&key);
+                self.track_expiry(old_action_pointer.as_ref(), None)?;
+                let old_val = match old_action_pointer {
+                    Some(old_action_pointer) => {
+                        // Both the removed set action and this remove action are stale now.
+                        let stale = old_action_pointer.len + action_pointer.len;
+                        self.uncompacted.fetch_add(stale, Ordering::SeqCst);
+                        self.read_old_val(&old_action_pointer, now)?
+                    }
+                    None => None,
+                };
+                Ok(Written {
+                    old_val,
+                    version: None,
+                })
+            }
+        }
+    }
+
+    // Count a key whose pointer in the index is replaced, or which is added to or removed from
+    // it, in the keys expiring at the time it expires at. Callers hold the index lock for writing.
+    fn track_expiry(
+        &self,
+        old_action_pointer: Option<&ActionPointer>,
+        new_action_pointer: Option<&ActionPointer>,
+    ) -> Result<()> {
+        let mut expiring = self.expiring.lock().map_err(|_| KVStoreError::Lock)?;
+        count_expiry(&mut expiring, old_action_pointer, new_action_pointer);
+        Ok(())
+    }
+
+    // Close the active segment and start a new one, if it has grown beyond the segment size.
+    fn maybe_roll(&self, writer: &mut SegmentWriter) -> Result<()> {
+        if writer.writer.pointer < self.config.segment_size {
+            return Ok(());
+        }
+        // Make sure the closed segment is durable before moving on from it.
+        if self.config.durability != Durability::Never {
+            writer.sync()?;
+        }
+        let new_writer = SegmentWriter::create(&self.path, writer.gen + 1, self.config.durability)?;
+        {
+            let log_file = File::open(log_path(&self.path, new_writer.gen))?;
+            let mut readers = self.readers.write().map_err(|_| KVStoreError::Lock)?;
+            readers.insert(new_writer.gen, log_file);
+        }
+        let closed = std::mem::replace(writer, new_writer);
+
+        // Nothing has been written since the segment was closed, so the index is as of its end.
+        let hint = {
+            let index = self.index.read().map_err(|_| KVStoreError::Lock)?;
+            closed_hint(&closed, &index)
+        };
+        hint::write_hint(&self.path, closed.gen, &hint)?;
+        Ok(())
+    }
+
+    // Compact the log if the amount of stale bytes has crossed the threshold.
+    fn maybe_compact(&self) -> Result<()> {
+        if self.uncompacted.load(Ordering::SeqCst) <= self.config.compaction_threshold {
+            return Ok(());
+        }
+        // Rather than holding up the write until a snapshot is done, leave the compaction
+        // to one of the writes after it.
+        match self.compaction.try_lock() {
+            Ok(_compaction) => self.compact_locked().map(|_| ()),
+            Err(TryLockError::WouldBlock) => Ok(()),
+            Err(TryLockError::Poisoned(_)) => Err(KVStoreError::Lock),
+        }
+    }
+
+    // Store the key and it's value, expiring it at the given time, if any.
+    fn set_with_expiry(
+        &self,
+        key: Vec<u8>,
+        val: Vec<u8>,
+        expires_at: Option<u64>,
+    ) -> Result<Option<Vec<u8>>> {
+        let action = Action::Set {
+            key,
+            val,
+            expires_at,
+            version: record::FIRST_VERSION,
+        };
+        let written = self.write(action, Condition::None)?;
+        self.maybe_compact()?;
+        Ok(written.old_val)
+    }
+
+    // Store the key and it's value, if the condition holds. Returns the new version of the key.
+    fn set_if(&self, key: Vec<u8>, val: Vec<u8>, condition: Condition) -> Result<u64> {
+        let action = Action::Set {
+            key,
+            val,
+            expires_at: None,
+            version: record::FIRST_VERSION,
+        };
+        let written = self.write(action, condition)?;
+        self.maybe_compact()?;
+        Ok(written.version.unwrap_or(record::FIRST_VERSION))
+    }
+
+    // Build a page out of the first live keys, up to the limit, in the range.
+    fn page<T, F>(&self, range: impl RangeBounds<Vec<u8>>, limit: usize, item: F) -> Result<Page<T>>
+    where
+        F: Fn(&Vec<u8>, &ActionPointer) -> Result<Option<T>>,
+    {
+        if engines::is_empty_range(&range) {
+            return Ok(Page {
+                items: vec![],
+                next: None,
+            });
+        }
+        let index = self.index.read().map_err(|_| KVStoreError::Lock)?;
+        let now = now_millis();
+        let limit = limit.max(1);
+        let mut items = vec![];
+        let mut last_key = None;
+        let mut next = None;
+        for (key, action_pointer) in index.range(range) {
+            if action_pointer.is_expired(now) {
+                continue;
+            }
+            if items.len() == limit {
+                next = last_key;
+                break;
+            }
+            if let Some(item) = item(key, action_pointer)? {
+                items.push(item);
+                last_key = Some(key.clone());
+            }
+        }
+        Ok(Page { items, next })
+    }
+
+    // Read the value of the key that the pointer points to, from the cache if it's there.
+    // Otherwise, it's read from the log and cached. Callers hold the index lock.
+    fn read_cached_val(
+        &self,
+        key: &[u8],
+        action_pointer: &ActionPointer,
+    ) -> Result<Option<Vec<u8>>> {
+        let cache = match &self.cache {
+            Some(cache) => cache,
+            None => return self.read_val(action_pointer),
+        };
+        if let Some(val) = cache.get(key)? {
+            return Ok(Some(val));
+        }
+        let val = self.read_val(action_pointer)?;
+        if let Some(val) = &val {
+            cache.insert(key.to_vec(), val.clone())?;
+        }
+        Ok(val)
+    }
+
+    // Evict the value of the key from the cache, since it's being overwritten or removed.
+    // Callers hold the index lock for writing, so that readers can't cache the old value again.
+    fn evict(&self, key: &[u8]) -> Result<()> {
+        if let Some(cache) = &self.cache {
+            cache.remove(key)?;
+        }
+        Ok(())
+    }
+
+    // Read the value that was overwritten or removed. Expired values were already gone.
+    fn read_old_val(&self, action_pointer: &ActionPointer, now: u64) -> Result<Option<Vec<u8>>> {
+        if action_pointer.is_expired(now) {
+            return Ok(None);
+        }
+        self.read_val(action_pointer)
+    }
+
+    // Read the action that the pointer points to and return its value, if it's a set action.
+    //
+    // Callers hold the index lock, which keeps the segment from being deleted by a compaction
+    // in the meantime. Since it's only ever held for reading here, reads proceed in parallel.
+    fn read_val(&self, action_pointer: &ActionPointer) -> Result<Option<Vec<u8>>> {
+        let readers = self.readers.read().map_err(|_| KVStoreError::Lock)?;
+        let reader = readers
+            .get(&action_pointer.gen)
+            .ok_or(KVStoreError::Corrupt)?;
+        let mut buf = vec![0; action_pointer.len as usize];
+        read_exact_at(reader, &mut buf, action_pointer.pos)?;
+        let payload = record::decode_record(&buf)?;
+        if let Action::Set { val, .. } = Action::decode(record::SEGMENT_VERSION, payload)? {
+            return Ok(Some(val));
+        }
+        Ok(None)
+    }
+}
+
+/// Iterator over the key-value pairs with their keys in a range, ordered by key.
+/// Created by `KVStore::scan`.
+///
+/// The pairs are read in batches and the store is not locked in between, so writes
+/// made while iterating may or may not be seen.
+pub struct Scan<'a> {
+    store: &'a KVStore,
+    range: KeyRange,
+    pairs: VecDeque<(Vec<u8>, Vec<u8>)>,
+    done: bool,
+}
+
+impl Iterator for Scan<'_> {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.pairs.is_empty() && !self.done {
+            match self.store.scan_page(self.range.clone(), SCAN_BATCH_SIZE) {
+                Ok(page) => {
+                    match page.next {
+                        Some(cursor) => self.range.0 = Bound::Excluded(cursor),
+                        None => self.done = true,
+                    }
+                    self.pairs.extend(page.items);
+                }
+                Err(err) => {
+                    self.done = true;
+                    return Some(Err(err));
+                }
+            }
+        }
+        self.pairs.pop_front().map(Ok)
+    }
+}
+
+/// Writes staged to be committed atomically. Created by `KVStore::begin`.
+///
+/// ```rust
+/// use kv_store::store::KVStore;
+/// fn main() {
+///     let store = KVStore::open("/tmp/store-txn").unwrap();
+///     store.set("from", "10").unwrap();
+///     let mut txn = store.begin();
+///     txn.set("to", "10");
+///     txn.rm("from");
+///     txn.commit().unwrap();
+/// }
+/// ```
+pub struct Transaction<'a> {
+    store: &'a KVStore,
+    ops: Vec<WriteOp>,
+}
+
+impl Transaction<'_> {
+    /// Stages storing the key and it's value.
+    pub fn set(&mut self, key: impl Into<Vec<u8>>, val: impl Into<Vec<u8>>) {
+        self.ops.push(WriteOp::Set {
+            key: key.into(),
+            val: val.into(),
+        });
+    }
+
+    /// Stages removing the key. The transaction fails if the key doesn't exist when
+    /// it's committed.
+    pub fn rm(&mut self, key: impl Into<Vec<u8>>) {
+        self.ops.push(WriteOp::Rm { key: key.into() });
+    }
+
+    /// Commits the staged writes. Returns the old value of the key for each write, in order.
+    pub fn commit(self) -> Result<Vec<Option<Vec<u8>>>> {
+        self.store.commit_txn(self.ops)
+    }
+}
+
+impl KvsEngine for KVStore {
+    fn get(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        KVStore::get(self, key)
+    }
+
+    fn set(&self, key: Vec<u8>, val: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        KVStore::set(self, key, val)
+    }
+
+    fn set_with_ttl(&self, key: Vec<u8>, val: Vec<u8>, ttl: Duration) -> Result<Option<Vec<u8>>> {
+        KVStore::set_with_ttl(self, key, val, ttl)
+    }
+
+    fn ttl(&self, key: Vec<u8>) -> Result<Option<Duration>> {
+        KVStore::ttl(self, key)
+    }
+
+    fn get_with_version(&self, key: Vec<u8>) -> Result<(Vec<u8>, u64)> {
+        KVStore::get_with_version(self, key)
+    }
+
+    fn set_if_absent(&self, key: Vec<u8>, val: Vec<u8>) -> Result<u64> {
+        KVStore::set_if_absent(self, key, val)
+    }
+
+    fn set_if_version(&self, key: Vec<u8>, val: Vec<u8>, version: u64) -> Result<u64> {
+        KVStore::set_if_version(self, key, val, version)
+    }
+
+    fn rm_if_version(&self, key: Vec<u8>, version: u64) -> Result<Option<Vec<u8>>> {
+        KVStore::rm_if_version(self, key, version)
+    }
+
+    fn rm(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        KVStore::rm(self, key)
+    }
+
+    fn commit_txn(&self, ops: Vec<WriteOp>) -> Result<Vec<Option<Vec<u8>>>> {
+        KVStore::commit_txn(self, ops)
+    }
+
+    fn get_many(&self, keys: Vec<Vec<u8>>) -> Result<Vec<Option<Vec<u8>>>> {
+        KVStore::get_many(self, keys)
+    }
+
+    fn write_many(&self, ops: Vec<WriteOp>) -> Result<Vec<Result<Option<Vec<u8>>>>> {
+        KVStore::write_many(self, ops)
+    }
+
+    fn incr(&self, key: Vec<u8>, delta: i64) -> Result<i64> {
+        KVStore::incr(self, key, delta)
+    }
+
+    fn scan(&self, range: KeyRange) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        KVStore::scan(self, range).collect()
+    }
+
+    fn scan_page(&self, range: KeyRange, limit: usize) -> Result<Page<(Vec<u8>, Vec<u8>)>> {
+        KVStore::scan_page(self, range, limit)
+    }
+
+    fn keys_page(&self, range: KeyRange, limit: usize) -> Result<Page<Vec<u8>>> {
+        KVStore::keys_page(self, range, limit)
+    }
+
+    fn stats(&self) -> Result<EngineStats> {
+        KVStore::stats(self)
+    }
+
+    fn check_writable(&self) -> Result<()> {
+        KVStore::check_writable(self)
+    }
+
+    fn compact(&self) -> Result<u64> {
+        KVStore::compact(self)
+    }
+
+    fn reap_expired(&self) -> Result<usize> {
+        KVStore::reap_expired(self)
+    }
+
+    fn watch(&self, sink: ChangeSink) -> Result<()> {
+        KVStore::watch(self, sink)
+    }
+
+    fn snapshot(&self, dest: PathBuf) -> Result<SnapshotInfo> {
+        KVStore::snapshot(self, dest)
+    }
+}
+
+/// Current time in milliseconds since the UNIX epoch, which is how expiry times are stored.
+pub(crate) fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+// Check the conditions of the writes against the index and the writes staged so far, and
+// stage them, assigning versions to the keys being set. Returns the actions to be appended.
+// If the condition of any of the writes doesn't hold, none of them are staged.
+fn stage(
+    index: &BTreeMap<Vec<u8>, ActionPointer>,
+    staged: &mut HashMap<Vec<u8>, Option<u64>>,
+    writes: Vec<(Action, Condition)>,
+    now: u64,
+) -> Result<Vec<Action>> {
+    // The version of each key, as per the writes seen so far, None if the key doesn't exist.
+    let mut local: HashMap<Vec<u8>, Option<u64>> = HashMap::new();
+    let mut actions = Vec::with_capacity(writes.len());
+    for (mut action, condition) in writes {
+        let key = action.key().to_vec();
+        let (current_version, expired) = match local.get(&key).or_else(|| staged.get(&key)) {
+            Some(&version) => (version, false),
+            None => match index.get(&key) {
+                Some(action_pointer) if action_pointer.is_expired(now) => (None, true),
+                Some(action_pointer) => (Some(action_pointer.version), false),
+                None => (None, false),
+            },
+        };
+        let exists = current_version.is_some();
+        match condition {
+            Condition::None if !exists && matches!(action, Action::Remove { .. }) => {
+                return Err(KVStoreError::KeyNotFound(display_key(&key)));
+            }
+            Condition::Expired if !expired => {
+                return Err(KVStoreError::KeyNotFound(display_key(&key)))
+            }
+            Condition::Absent if exists => return Err(KVStoreError::KeyExists(display_key(&key))),
+            Condition::Version(version) if current_version != Some(version) => {
+                return Err(KVStoreError::VersionMismatch(display_key(&key), version));
+            }
+            _ => {}
+        }
+        match &mut action {
+            Action::Set { version, .. } => {
+                *version = current_version.map_or(record::FIRST_VERSION, |version| version + 1);
+                local.insert(key, Some(*version));
+            }
+            Action::Remove { .. } => {
+                local.insert(key, None);
+            }
+        }
+        actions.push(action);
+    }
+    staged.extend(local);
+    Ok(actions)
+}
+
+// Stage the pending writes and append their actions to the segment, returning the actions of
+// each along with pointers to them, or the error if it couldn't be staged, and the number of
+// bytes taken up by batch records other than the actions in them.
+fn append(
+    writer: &mut SegmentWriter,
+    index: &BTreeMap<Vec<u8>, ActionPointer>,
+    batch: Vec<PendingWrite>,
+    now: u64,
+) -> Result<(Vec<Result<Appended>>, u64)> {
+    let mut appended = Vec::with_capacity(batch.len());
+    let mut overhead = 0;
+    // The version of each key, as per the writes in the batch seen so far.
+    let mut staged = HashMap::new();
+    for write in batch {
+        let actions = match stage(index, &mut staged, write.writes, now) {
+            Ok(actions) => actions,
+            Err(err) => {
+                appended.push(Err(err));
+                continue;
+            }
+        };
+        let action_pointers = match actions.len() {
+            0 => vec![],
+            1 => vec![writer.write(&actions[0].encode())?],
+            _ => {
+                let (action_pointers, batch_overhead) = writer.write_batch(&actions)?;
+                overhead += batch_overhead;
+                action_pointers
+            }
+        };
+        let mut written = Vec::with_capacity(actions.len());
+        for (action, mut action_pointer) in actions.into_iter().zip(action_pointers) {
+            if let Action::Set {
+                expires_at,
+                version,
+                ..
+            } = &action
+            {
+                action_pointer.expires_at = *expires_at;
+                action_pointer.version = *version;
+            }
+            written.push((action, action_pointer));
+        }
+        appended.push(Ok(written));
+    }
+    Ok((appended, overhead))
+}
+
+// Fill the buffer with the bytes in the file starting at the given position. The cursor of the
+// file isn't used, so the same file can be read from by many threads at once.
+#[cfg(unix)]
+fn read_exact_at(file: &File, buf: &mut [u8], pos: u64) -> io::Result<()> {
+    use std::os::unix::fs::FileExt;
+    file.read_exact_at(buf, pos)
+}
+
+#[cfg(windows)]
+fn read_exact_at(file: &File, mut buf: &mut [u8], mut pos: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match file.seek_read(buf, pos) {
+            Ok(0) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "failed to fill whole buffer",
+                ))
+            }
+            Ok(n) => {
+                buf = &mut buf[n..];
+                pos += n as u64;
+            }
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+// The action appending a write to the log.
+fn write_action(op: WriteOp) -> Action {
+    match op {
+        WriteOp::Set { key, val } => Action::Set {
+            key,
+            val,
+            expires_at: None,
+            version: record::FIRST_VERSION,
+        },
+        WriteOp::Rm { key } => Action::Remove { key },
+    }
+}
+
+// An error while committing a batch fails every write in it, so each writer gets its own copy.
+fn batch_error(err: &KVStoreError) -> KVStoreError {
+    match err {
+        KVStoreError::Lock => KVStoreError::Lock,
+        KVStoreError::Io(err) => KVStoreError::Io(io::Error::new(err.kind(), err.to_string())),
+        err => KVStoreError::Io(io::Error::new(io::ErrorKind::Other, err.to_string())),
+    }
+}
+
+// Path of the segment with the given generation.
+fn log_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{}.log", gen))
+}
+
+// Generations of all the segments in the directory, in ascending order.
+fn sorted_gens(dir: &Path) -> Result<Vec<u64>> {
+    let mut gens: Vec<u64> = fs::read_dir(dir)?
+        .flat_map(|entry| -> Result<_> { Ok(entry?.path()) })
+        .filter(|path| path.is_file() && path.extension() == Some(OsStr::new("log")))
+        .flat_map(|path| {
+            path.file_stem()
+                .and_then(OsStr::to_str)
+                .map(str::parse::<u64>)
+        })
+        .flatten()
+        .collect();
+    gens.sort_unstable();
+    Ok(gens)
+}
+
+// Copy the file under a temporary name, only renaming it once the copy is complete.
+fn copy_file(from: &Path, to: &Path) -> Result<()> {
+    let mut tmp_path = to.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    fs::copy(from, &tmp_path)?;
+    File::open(&tmp_path)?.sync_all()?;
+    fs::rename(tmp_path, to)?;
+    Ok(())
+}
+
+// Copy a log written as a single file into the directory as its first segment, which is then
+// migrated like any other legacy segment. Once the store has segments, the log has been
+// imported already, or the store was started afresh, so it's left alone.
+fn import_legacy_log(dir: &Path, legacy_log: &Path) -> Result<()> {
+    if !legacy_log.is_file() {
+        return Err(KVStoreError::LegacyLog(legacy_log.display().to_string()));
+    }
+    if !sorted_gens(dir)?.is_empty() {
+        warn!(
+            "Not importing {}, the store in {} already has segments",
+            legacy_log.display(),
+            dir.display()
+        );
+        return Ok(());
+    }
+    info!("Importing the log in {}", legacy_log.display());
+    copy_file(legacy_log, &log_path(dir, 1))
+}
+
+// Lock the directory, so that it's only ever opened by one store at a time. The lock is
+// released once the returned file is closed, including when the process goes down.
+fn lock_dir(dir: &Path) -> Result<File> {
+    let lock = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(false)
+        .open(dir.join(LOCK_FILE))?;
+    match lock.try_lock_exclusive() {
+        Ok(()) => Ok(lock),
+        Err(err) if err.kind() == fs2::lock_contended_error().kind() => {
+            Err(KVStoreError::DirLocked(dir.display().to_string()))
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+// Check that a file can be written to the directory, e.g. that the disk it's on isn't full
+// or read-only, by writing one and removing it. Concurrent checks may remove each other's file.
+fn check_dir_writable(dir: &Path) -> Result<()> {
+    let path = dir.join(WRITABLE_CHECK_FILE);
+    let written = File::create(&path).and_then(|mut file| file.write_all(&[0]));
+    match fs::remove_file(&path) {
+        Ok(()) => {}
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+        Err(err) => return Err(err.into()),
+    }
+    Ok(written?)
+}
+
+// Remove any compacted segments or hint files that were not renamed before the store went down.
+fn remove_temp_files(dir: &Path) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let extension = path.extension().and_then(OsStr::to_str);
+        if path.is_file() && (extension == Some("compact") || extension == Some("tmp")) {
+            fs::remove_file(path)?;
+        }
+    }
+    Ok(())
+}
+
+// Open the segment with the given generation and read its header. Returns the reader, at the
+// first record, along with the version of the format the segment is encoded in. Segments
+// that aren't in any known format are left as is, rather than treated as corrupt.
+fn open_segment(dir: &Path, gen: u64) -> Result<(BufReaderWithPointer<File>, u16)> {
+    let path = log_path(dir, gen);
+    let mut reader = BufReaderWithPointer::new(File::open(&path)?)?;
+    match record::read_segment_header(&mut reader)? {
+        Some(version) => Ok((reader, version)),
+        None => Err(KVStoreError::UnknownSegment(path.display().to_string())),
+    }
+}
+
+// Read the hint file for a segment. Returns None if there's none or it can't be used,
+// in which case the segment needs to be parsed instead.
+fn load_hint(dir: &Path, gen: u64) -> Option<Hint> {
+    match hint::read_hint(dir, gen) {
+        Ok(hint) => hint,
+        Err(err) => {
+            warn!("Ignoring hint file for segment {}: {}", gen, err);
+            None
+        }
+    }
+}
+
+// Parse a segment of the log, encoded in the given version of the format, and return
+// its hint. Expects the reader to be at the first record of the segment.
+//
+// If a record is found to be corrupt or incomplete, e.g. because the store crashed
+// in the middle of a write, the segment is truncated to the last intact record.
+// Segments without framed records are only ever read to be migrated, so they're never
+// truncated, see `record::read_unframed`.
+fn scan_segment(
+    dir: &Path,
+    gen: u64,
+    version: u16,
+    reader: &mut BufReaderWithPointer<File>,
+) -> Result<Hint> {
+    let mut entries = BTreeMap::new();
+    let mut stale = 0;
+    if version == record::UNFRAMED_VERSION {
+        let start = reader.pointer;
+        record::read_unframed(reader, |action, pos, len| {
+            add_hint_entry(&mut entries, &mut stale, action, start + pos, len);
+        })?;
+        return Ok(Hint {
+            entries: entries.into_iter().collect(),
+            stale,
+        });
+    }
+    let mut pointer = reader.pointer;
+    loop {
+        let payload = match record::read_record(reader) {
+            Ok(Some(payload)) => payload,
+            Ok(None) => break,
+            Err(KVStoreError::Corrupt) => {
+                truncate_segment(dir, gen, pointer)?;
+                break;
+            }
+            Err(err) => return Err(err),
+        };
+        let new_pointer = reader.pointer;
+        if record::is_batch(version, &payload) {
+            // The actions in a batch are pointed to directly, the rest of the batch is stale.
+            let start = pointer + record::HEADER_LEN as u64;
+            stale += new_pointer - pointer;
+            for (range, action) in record::decode_batch(&payload)? {
+                let len = (range.end - range.start) as u64;
+                stale -= len;
+                add_hint_entry(
+                    &mut entries,
+                    &mut stale,
+                    action,
+                    start + range.start as u64,
+                    len,
+                );
+            }
+        } else {
+            let action = Action::decode(version, &payload)?;
+            add_hint_entry(
+                &mut entries,
+                &mut stale,
+                action,
+                pointer,
+                new_pointer - pointer,
+            );
+        }
+        pointer = new_pointer;
+    }
+    Ok(Hint {
+        entries: entries.into_iter().collect(),
+        stale,
+    })
+}
+
+// Record the state of the key as of the action at the given offset in the segment being
+// parsed, adding the bytes that it makes stale to the count.
+fn add_hint_entry(
+    entries: &mut BTreeMap<Vec<u8>, HintEntry>,
+    stale: &mut u64,
+    action: Action,
+    pos: u64,
+    len: u64,
+) {
+    let (key, entry) = match action {
+        Action::Set {
+            key,
+            expires_at,
+            version,
+            ..
+        } => {
+            let entry = HintEntry::Set {
+                pos,
+                len,
+                expires_at,
+                version,
+            };
+            (key, entry)
+        }
+        Action::Remove { key } => {
+            *stale += len;
+            (key, HintEntry::Removed)
+        }
+    };
+    if let Some(HintEntry::Set { len, .. }) = entries.insert(key, entry) {
+        *stale += len;
+    }
+}
+
+// Build the hint for a closed segment out of the index, as of the end of the segment. Each of
+// the keys written to it is either still set by one of its actions, or was removed. Every byte
+// in it that doesn't belong to one of those actions is stale.
+fn closed_hint(closed: &SegmentWriter, index: &BTreeMap<Vec<u8>, ActionPointer>) -> Hint {
+    let mut live = 0;
+    let entries = closed
+        .keys
+        .iter()
+        .map(|key| {
+            let entry = match index.get(key) {
+                Some(action_pointer) if action_pointer.gen == closed.gen => {
+                    live += action_pointer.len;
+                    HintEntry::Set {
+                        pos: action_pointer.pos,
+                        len: action_pointer.len,
+                        expires_at: action_pointer.expires_at,
+                        version: action_pointer.version,
+                    }
+                }
+                _ => HintEntry::Removed,
+            };
+            (key.clone(), entry)
         })
+        .collect();
+    let stale = closed
+        .writer
+        .pointer
+        .saturating_sub(record::SEGMENT_HEADER_LEN + live);
+    Hint { entries, stale }
+}
+
+// Move a key from the keys expiring at the time its old pointer expires at, if any, to the
+// ones expiring at the time its new pointer does, if any.
+fn count_expiry(
+    expiring: &mut BTreeMap<u64, u64>,
+    old_action_pointer: Option<&ActionPointer>,
+    new_action_pointer: Option<&ActionPointer>,
+) {
+    if let Some(expires_at) =
+        old_action_pointer.and_then(|action_pointer| action_pointer.expires_at)
+    {
+        if let Some(count) = expiring.get_mut(&expires_at) {
+            *count -= 1;
+            if *count == 0 {
+                expiring.remove(&expires_at);
+            }
+        }
+    }
+    if let Some(expires_at) =
+        new_action_pointer.and_then(|action_pointer| action_pointer.expires_at)
+    {
+        *expiring.entry(expires_at).or_insert(0) += 1;
+    }
+}
+
+// Populate the index with the hint for a segment.
+// Returns the number of stale bytes in the log found while doing so.
+fn apply_hint(gen: u64, hint: Hint, index: &mut BTreeMap<Vec<u8>, ActionPointer>) -> u64 {
+    let mut uncompacted = hint.stale;
+    for (key, entry) in hint.entries {
+        let old_action_pointer = match entry {
+            HintEntry::Set {
+                pos,
+                len,
+                expires_at,
+                version,
+            } => {
+                let action_pointer = ActionPointer {
+                    gen,
+                    pos,
+                    len,
+                    expires_at,
+                    version,
+                };
+                index.insert(key, action_pointer)
+            }
+            HintEntry::Removed => index.remove(&key),
+        };
+        if let Some(old_action_pointer) = old_action_pointer {
+            uncompacted += old_action_pointer.len;
+        }
+    }
+    uncompacted
+}
+
+// Truncate the segment to the given length, dropping everything after it.
+fn truncate_segment(dir: &Path, gen: u64, len: u64) -> Result<()> {
+    let log_file = OpenOptions::new().write(true).open(log_path(dir, gen))?;
+    let dropped = log_file.metadata()?.len() - len;
+    warn!(
+        "Truncating {} bytes of corrupt or incomplete records from segment {}",
+        dropped, gen
+    );
+    log_file.set_len(len)?;
+    log_file.sync_all()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use rand::Rng;
+    use std::{fs, panic};
+
+    fn run_test<T>(test: T)
+    where
+        T: FnOnce(KVStore) + panic::UnwindSafe,
+    {
+        let dir = test_dir("kvs");
+        let store = KVStore::open(dir.clone()).unwrap();
+
+        let result = panic::catch_unwind(move || test(store));
+
+        fs::remove_dir_all(dir).unwrap();
+
+        assert!(result.is_ok())
+    }
+
+    // Since tests are run in parallel by default, we cannot have multiple unit tests working on the same directory.
+    fn test_dir(prefix: &str) -> String {
+        let mut rng = rand::thread_rng();
+        let n: u32 = rng.gen();
+        format!("/tmp/{}-{}", prefix, n)
+    }
+
+    #[test]
+    fn test_set() {
+        run_test(|store: KVStore| {
+            let key = String::from("this is");
+            let val = String::from("the way");
+            let res = store.set(key.clone(), val).unwrap();
+            assert_eq!(res, None);
+            let val = String::from("not the way");
+            let res = store.set(key, val).unwrap();
+            assert_eq!(res, Some(b"the way".to_vec()));
+        })
+    }
+
+    #[test]
+    fn test_get() {
+        run_test(|store: KVStore| {
+            let key = String::from("this is");
+            let val = String::from("the way");
+            let res = store.set(key.clone(), val).unwrap();
+            assert_eq!(res, None);
+            let res = store.get(key).unwrap();
+            assert_eq!(res, Some(b"the way".to_vec()));
+        })
+    }
+
+    #[test]
+    fn test_rm() {
+        run_test(|store: KVStore| {
+            let key = String::from("this is");
+            let val = String::from("the way");
+            let res = store.set(key, val).unwrap();
+            assert_eq!(res, None);
+            let key = String::from("this is");
+            let res = store.rm(key.clone()).unwrap();
+            assert_eq!(res, Some(b"the way".to_vec()));
+        })
+    }
+
+    #[test]
+    fn test_binary() {
+        let dir = test_dir("kvs-binary");
+        let key = vec![0, 159, 146, 150];
+        let val: Vec<u8> = (0..=255).collect();
+        {
+            let store = KVStore::open(dir.clone()).unwrap();
+            store.set(key.clone(), val.clone()).unwrap();
+            store.set(&[0xff][..], &b"\xff"[..]).unwrap();
+        }
+        let store = KVStore::open(dir.clone()).unwrap();
+        let reopened = store.get(key.clone()).unwrap();
+        store.compact().unwrap();
+        let compacted = store.get(key.clone()).unwrap();
+        let keys: Vec<Vec<u8>> = store.scan(..).map(|pair| pair.unwrap().0).collect();
+        fs::remove_dir_all(dir).unwrap();
+        assert_eq!(reopened, Some(val.clone()));
+        assert_eq!(compacted, Some(val));
+        assert_eq!(keys, vec![key, vec![0xff]]);
+    }
+
+    #[test]
+    fn test_scan() {
+        run_test(|store| {
+            for i in 0..300 {
+                store
+                    .set(format!("key {:03}", i), format!("val {}", i))
+                    .unwrap();
+            }
+            store.set(String::from("other"), String::new()).unwrap();
+            store.rm(String::from("key 150")).unwrap();
+            // Scans read more pairs than fit in a single batch.
+            let pairs: Vec<(Vec<u8>, Vec<u8>)> =
+                store.scan_prefix("key").collect::<Result<_>>().unwrap();
+            assert_eq!(pairs.len(), 299);
+            assert_eq!(pairs[0], (b"key 000".to_vec(), b"val 0".to_vec()));
+            assert!(pairs.windows(2).all(|pair| pair[0].0 < pair[1].0));
+            let range = b"key 100".to_vec()..b"key 200".to_vec();
+            assert_eq!(store.scan(range).count(), 99);
+
+            let page = store.keys_page(b"key 298".to_vec().., 2).unwrap();
+            assert_eq!(page.items, vec![b"key 298".to_vec(), b"key 299".to_vec()]);
+            assert_eq!(page.next, Some(b"key 299".to_vec()));
+            let page = store
+                .keys_page((Bound::Excluded(b"key 299".to_vec()), Bound::Unbounded), 2)
+                .unwrap();
+            assert_eq!(page.items, vec![b"other".to_vec()]);
+            assert_eq!(page.next, None);
+        });
+    }
+
+    #[test]
+    fn test_check_writable() {
+        let dir = test_dir("kvs-writable");
+        let store = KVStore::open(dir.clone()).unwrap();
+        store.set("a", "1").unwrap();
+        store.check_writable().unwrap();
+
+        // Once a write fails, the store isn't writable until the segment can be synced again.
+        let segment_path = log_path(Path::new(&dir), 1);
+        let writable = {
+            let mut writer = store.writer.lock().unwrap();
+            let writable = writer.writer.writer.get_ref().try_clone().unwrap();
+            writer.writer = BufWriterWithPointer::new(File::open(&segment_path).unwrap()).unwrap();
+            writer.writer.seek(SeekFrom::End(0)).unwrap();
+            writer.failed = Some(String::from("No space left on device"));
+            writer.writer.write_all(b"unflushed").unwrap();
+            writable
+        };
+        let failed = store.check_writable();
+        {
+            let mut writer = store.writer.lock().unwrap();
+            writer.writer = BufWriterWithPointer::new(writable).unwrap();
+        }
+        let fixed = store.check_writable();
+        let after_fix = store.check_writable();
+        let files = fs::read_dir(&dir).unwrap().count();
+        // The directory is checked too, so the store isn't writable once it's gone, even though
+        // the active segment still is.
+        fs::remove_dir_all(&dir).unwrap();
+        let removed = store.check_writable();
+        assert!(matches!(failed, Err(KVStoreError::WriteFailed(_))));
+        assert!(fixed.is_ok());
+        assert!(after_fix.is_ok());
+        assert_eq!(files, 2);
+        assert!(matches!(removed, Err(KVStoreError::Io(_))));
+    }
+
+    #[test]
+    fn test_compact() {
+        run_test(|store: KVStore| {
+            let key = String::from("this is");
+            for i in 0..10 {
+                store.set(key.clone(), format!("the way {}", i)).unwrap();
+            }
+            store
+                .set(String::from("gone"), String::from("soon"))
+                .unwrap();
+            store.rm(String::from("gone")).unwrap();
+            let reclaimed = store.compact().unwrap();
+            assert!(reclaimed > 0);
+            assert_eq!(store.stats().unwrap().compactions, 1);
+            let res = store.get(key.clone()).unwrap();
+            assert_eq!(res, Some(b"the way 9".to_vec()));
+            assert!(store.get(String::from("gone")).is_err());
+            // The store should keep working on top of the compacted log.
+            let res = store.set(key.clone(), String::from("the way")).unwrap();
+            assert_eq!(res, Some(b"the way 9".to_vec()));
+            let res = store.get(key).unwrap();
+            assert_eq!(res, Some(b"the way".to_vec()));
+        })
+    }
+
+    #[test]
+    fn test_auto_compact() {
+        let dir = test_dir("kvs-compact");
+        let config = KVStoreConfig {
+            compaction_threshold: 1024,
+            ..KVStoreConfig::default()
+        };
+        let key = String::from("this is");
+        {
+            let store = KVStore::open_with_config(dir.clone(), config.clone()).unwrap();
+            for i in 0..1000 {
+                store.set(key.clone(), format!("the way {}", i)).unwrap();
+            }
+        }
+        // Every set action is ~40 bytes, without compaction the log would be ~40KB.
+        let log_len: u64 = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().metadata().unwrap().len())
+            .sum();
+        let store = KVStore::open_with_config(dir.clone(), config).unwrap();
+        let res = store.get(key).unwrap();
+        fs::remove_dir_all(dir).unwrap();
+        assert!(log_len < 2048);
+        assert_eq!(res, Some(b"the way 999".to_vec()));
+    }
+
+    #[test]
+    fn test_segments() {
+        let dir = test_dir("kvs-segments");
+        let config = KVStoreConfig {
+            segment_size: 512,
+            ..KVStoreConfig::default()
+        };
+        {
+            let store = KVStore::open_with_config(dir.clone(), config.clone()).unwrap();
+            for i in 0..100 {
+                store
+                    .set(format!("key {}", i), format!("val {}", i))
+                    .unwrap();
+            }
+            store.rm(String::from("key 0")).unwrap();
+        }
+        let segments = fs::read_dir(&dir).unwrap().count();
+        let store = KVStore::open_with_config(dir.clone(), config).unwrap();
+        let res = store.get(String::from("key 0"));
+        let vals: Vec<Option<Vec<u8>>> = (1..100)
+            .map(|i| store.get(format!("key {}", i)).unwrap())
+            .collect();
+        fs::remove_dir_all(dir).unwrap();
+        assert!(segments > 1);
+        assert!(res.is_err());
+        for (i, val) in (1..100).zip(vals) {
+            assert_eq!(val, Some(format!("val {}", i).into_bytes()));
+        }
+    }
+
+    // Write a few actions to a fresh store and return the contents of its only segment.
+    fn write_segment(n: usize) -> Vec<u8> {
+        let dir = test_dir("kvs-segment");
+        {
+            let store = KVStore::open(dir.clone()).unwrap();
+            for i in 0..n {
+                store
+                    .set(format!("key {}", i), format!("val {}", i))
+                    .unwrap();
+            }
+        }
+        let segment = fs::read(log_path(Path::new(&dir), 1)).unwrap();
+        fs::remove_dir_all(dir).unwrap();
+        segment
+    }
+
+    #[test]
+    fn test_torn_writes() {
+        let n = 5;
+        let segment = write_segment(n);
+        // Offsets at which each of the records ends.
+        let mut boundaries = vec![];
+        let header_len = record::SEGMENT_HEADER_LEN as usize;
+        let mut pos = header_len;
+        while pos < segment.len() {
+            let mut len = [0; 4];
+            len.copy_from_slice(&segment[pos..pos + 4]);
+            pos += record::HEADER_LEN + u32::from_le_bytes(len) as usize;
+            boundaries.push(pos);
+        }
+        assert_eq!(boundaries.len(), n);
+
+        // Simulate a crash after every single byte written to the segment.
+        for crash_at in 0..=segment.len() {
+            let dir = test_dir("kvs-torn");
+            fs::create_dir_all(&dir).unwrap();
+            let path = log_path(Path::new(&dir), 1);
+            fs::write(&path, &segment[..crash_at]).unwrap();
+
+            let store = KVStore::open(dir.clone()).unwrap();
+            let intact = boundaries.iter().filter(|&&b| b <= crash_at).count();
+            // A partially written segment header is left as is.
+            let intact_len = if intact == 0 {
+                crash_at.min(header_len)
+            } else {
+                boundaries[intact - 1]
+            };
+            let segment_len = fs::metadata(&path).unwrap().len();
+            let vals: Vec<Option<Vec<u8>>> = (0..n)
+                .map(|i| store.get(format!("key {}", i)).ok().flatten())
+                .collect();
+            // The store must keep working after the recovery.
+            store.set(String::from("key"), String::from("val")).unwrap();
+            let res = store.get(String::from("key")).unwrap();
+            fs::remove_dir_all(dir).unwrap();
+
+            assert_eq!(segment_len, intact_len as u64);
+            for (i, val) in vals.into_iter().enumerate() {
+                if i < intact {
+                    assert_eq!(val, Some(format!("val {}", i).into_bytes()));
+                } else {
+                    assert_eq!(val, None);
+                }
+            }
+            assert_eq!(res, Some(b"val".to_vec()));
+        }
+    }
+
+    #[test]
+    fn test_corrupt_record() {
+        let mut segment = write_segment(3);
+        // Flip a bit in the last byte, which belongs to the payload of the last record.
+        let last = segment.len() - 1;
+        segment[last] ^= 1;
+
+        let dir = test_dir("kvs-corrupt");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(log_path(Path::new(&dir), 1), &segment).unwrap();
+        let store = KVStore::open(dir.clone()).unwrap();
+        let first = store.get(String::from("key 0")).unwrap();
+        let last = store.get(String::from("key 2"));
+        fs::remove_dir_all(dir).unwrap();
+
+        assert_eq!(first, Some(b"val 0".to_vec()));
+        assert!(last.is_err());
+    }
+
+    #[test]
+    fn test_durability_from_str() {
+        assert_eq!("never".parse::<Durability>().unwrap(), Durability::Never);
+        assert_eq!("always".parse::<Durability>().unwrap(), Durability::Always);
+        assert_eq!(
+            "interval:100".parse::<Durability>().unwrap(),
+            Durability::Interval(100)
+        );
+        assert_eq!(
+            "writes:10".parse::<Durability>().unwrap(),
+            Durability::Writes(10)
+        );
+        assert!("sometimes".parse::<Durability>().is_err());
+        assert!("always:1".parse::<Durability>().is_err());
+        assert!("interval:".parse::<Durability>().is_err());
+        assert!("writes:0".parse::<Durability>().is_err());
+    }
+
+    #[test]
+    fn test_durability() {
+        let policies = vec![
+            Durability::Always,
+            Durability::Interval(10),
+            Durability::Writes(3),
+        ];
+        for durability in policies {
+            let dir = test_dir("kvs-durability");
+            let config = KVStoreConfig {
+                durability,
+                segment_size: 256,
+                ..KVStoreConfig::default()
+            };
+            {
+                let store = KVStore::open_with_config(dir.clone(), config.clone()).unwrap();
+                for i in 0..20 {
+                    store
+                        .set(format!("key {}", i), format!("val {}", i))
+                        .unwrap();
+                }
+                store.rm(String::from("key 0")).unwrap();
+                // Give the syncer a chance to run.
+                thread::sleep(Duration::from_millis(20));
+            }
+            let store = KVStore::open_with_config(dir.clone(), config).unwrap();
+            let removed = store.get(String::from("key 0"));
+            let val = store.get(String::from("key 19")).unwrap();
+            fs::remove_dir_all(dir).unwrap();
+            assert!(removed.is_err());
+            assert_eq!(val, Some(b"val 19".to_vec()));
+        }
+    }
+
+    #[test]
+    fn test_concurrent_writes() {
+        let dir = test_dir("kvs-concurrent");
+        let config = KVStoreConfig {
+            durability: Durability::Always,
+            ..KVStoreConfig::default()
+        };
+        {
+            let store = Arc::new(KVStore::open_with_config(dir.clone(), config.clone()).unwrap());
+            let handles: Vec<_> = (0..8)
+                .map(|t| {
+                    let store = Arc::clone(&store);
+                    thread::spawn(move || {
+                        for i in 0..50 {
+                            let key = format!("key {} {}", t, i);
+                            store.set(key.clone(), format!("val {}", i)).unwrap();
+                            if i % 2 == 0 {
+                                store.rm(key.clone()).unwrap();
+                                assert!(store.rm(key).is_err());
+                            }
+                        }
+                    })
+                })
+                .collect();
+            for handle in handles {
+                handle.join().unwrap();
+            }
+        }
+        let store = KVStore::open_with_config(dir.clone(), config).unwrap();
+        let mut vals = vec![];
+        for t in 0..8 {
+            for i in 0..50 {
+                vals.push((i, store.get(format!("key {} {}", t, i)).ok()));
+            }
+        }
+        fs::remove_dir_all(dir).unwrap();
+        for (i, val) in vals {
+            if i % 2 == 0 {
+                assert_eq!(val, None);
+            } else {
+                assert_eq!(val, Some(Some(format!("val {}", i).into_bytes())));
+            }
+        }
+    }
+
+    #[test]
+    fn test_concurrent_reads() {
+        let dir = test_dir("kvs-concurrent-reads");
+        let config = KVStoreConfig {
+            segment_size: 1024,
+            ..KVStoreConfig::default()
+        };
+        let store = Arc::new(KVStore::open_with_config(dir.clone(), config).unwrap());
+        for i in 0..100 {
+            store
+                .set(format!("key {}", i), format!("val {}", i))
+                .unwrap();
+        }
+        // Readers keep finding every key while it's overwritten, segments are rolled
+        // and the log is compacted under them.
+        let readers: Vec<_> = (0..8)
+            .map(|_| {
+                let store = Arc::clone(&store);
+                thread::spawn(move || {
+                    for _ in 0..20 {
+                        for i in 0..100 {
+                            let val = store.get(format!("key {}", i)).unwrap().unwrap();
+                            assert!(val.starts_with(format!("val {}", i).as_bytes()));
+                        }
+                    }
+                })
+            })
+            .collect();
+        for round in 0..5 {
+            for i in 0..100 {
+                store
+                    .set(format!("key {}", i), format!("val {} {}", i, round))
+                    .unwrap();
+            }
+            store.compact().unwrap();
+        }
+        for reader in readers {
+            reader.join().unwrap();
+        }
+        drop(store);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_cache() {
+        let dir = test_dir("kvs-cache");
+        let config = KVStoreConfig {
+            cache_size: 64,
+            ..KVStoreConfig::default()
+        };
+        let store = KVStore::open_with_config(dir.clone(), config).unwrap();
+        store.set("key", "val").unwrap();
+        assert_eq!(store.get("key").unwrap(), Some(b"val".to_vec()));
+        assert_eq!(store.get("key").unwrap(), Some(b"val".to_vec()));
+        let stats = store.stats().unwrap();
+        assert_eq!((stats.cache_hits, stats.cache_misses), (1, 1));
+        assert_eq!(stats.cache_bytes, 6);
+
+        // Cached values never outlive the writes to their keys.
+        store.set("key", "new val").unwrap();
+        assert_eq!(store.get("key").unwrap(), Some(b"new val".to_vec()));
+        store.incr("counter", 1).unwrap();
+        assert_eq!(store.get("counter").unwrap(), Some(b"1".to_vec()));
+        store.incr("counter", 1).unwrap();
+        assert_eq!(store.get("counter").unwrap(), Some(b"2".to_vec()));
+        store.compact().unwrap();
+        assert_eq!(
+            store.get_with_version("key").unwrap().0,
+            b"new val".to_vec()
+        );
+        store.rm("key").unwrap();
+        assert!(matches!(
+            store.get("key"),
+            Err(KVStoreError::KeyNotFound(_))
+        ));
+        store
+            .set_with_ttl("short", "val", Duration::from_millis(20))
+            .unwrap();
+        assert_eq!(store.get("short").unwrap(), Some(b"val".to_vec()));
+        thread::sleep(Duration::from_millis(30));
+        assert!(matches!(
+            store.get("short"),
+            Err(KVStoreError::KeyNotFound(_))
+        ));
+        store.compact().unwrap();
+
+        // The least recently used values are evicted to stay within the size of the cache.
+        for i in 0..10 {
+            store.set(format!("key {}", i), vec![b'x'; 10]).unwrap();
+            store.get(format!("key {}", i)).unwrap();
+        }
+        assert!(store.stats().unwrap().cache_bytes <= 64);
+        drop(store);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_counters() {
+        let dir = test_dir("kvs-counters");
+        let hour = Duration::from_secs(60 * 60);
+        {
+            let store = Arc::new(KVStore::open(dir.clone()).unwrap());
+            // Increments racing with each other, and with other writes, are never lost.
+            let handles: Vec<_> = (0..8)
+                .map(|t| {
+                    let store = Arc::clone(&store);
+                    thread::spawn(move || {
+                        for i in 0..50 {
+                            store.incr("hits", 2).unwrap();
+                            store.decr("hits", 1).unwrap();
+                            store.set(format!("key {} {}", t, i), "val").unwrap();
+                        }
+                    })
+                })
+                .collect();
+            for handle in handles {
+                handle.join().unwrap();
+            }
+            assert_eq!(store.get("hits").unwrap(), Some(b"400".to_vec()));
+            assert_eq!(store.get_with_version("hits").unwrap().1, 800);
+
+            store.set_with_ttl("limited", "5", hour).unwrap();
+            assert_eq!(store.decr("limited", 10).unwrap(), -5);
+            assert!(store.ttl("limited").unwrap().is_some());
+            assert!(matches!(
+                store.decr("limited", i64::MIN),
+                Err(KVStoreError::Overflow(_))
+            ));
+        }
+        let store = KVStore::open(dir.clone()).unwrap();
+        assert_eq!(store.incr("hits", 0).unwrap(), 400);
+        assert_eq!(store.get("limited").unwrap(), Some(b"-5".to_vec()));
+        assert!(store.ttl("limited").unwrap().is_some());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let dir = test_dir("kvs-snapshot");
+        let snapshot_dir = test_dir("kvs-snapshot-dest");
+        let restore_dir = test_dir("kvs-snapshot-restore");
+        let config = KVStoreConfig {
+            segment_size: 1024,
+            ..KVStoreConfig::default()
+        };
+        let store = Arc::new(KVStore::open_with_config(dir.clone(), config.clone()).unwrap());
+        store.set("kept", "val").unwrap();
+        store.set("removed", "val").unwrap();
+        store.rm("removed").unwrap();
+        store
+            .set_with_ttl("expired", "val", Duration::from_millis(1))
+            .unwrap();
+        thread::sleep(Duration::from_millis(5));
+
+        // Keys are written in order while the snapshot is taken, so a consistent
+        // snapshot holds all the keys up to some point and none after it.
+        let writer = {
+            let store = Arc::clone(&store);
+            thread::spawn(move || {
+                for i in 0..500 {
+                    store.set(format!("key {:03}", i), "val").unwrap();
+                }
+            })
+        };
+        let info = store.snapshot(snapshot_dir.clone()).unwrap();
+        writer.join().unwrap();
+        assert!(matches!(
+            store.snapshot(snapshot_dir.clone()),
+            Err(KVStoreError::DirNotEmpty(_))
+        ));
+
+        let restored = KVStore::restore(&snapshot_dir, restore_dir.clone(), config).unwrap();
+        let keys: Vec<Vec<u8>> = restored
+            .scan_prefix("key ")
+            .map(|pair| pair.unwrap().0)
+            .collect();
+        for (i, key) in keys.iter().enumerate() {
+            assert_eq!(key, format!("key {:03}", i).as_bytes());
+        }
+        assert_eq!(info.keys, keys.len() as u64 + 1);
+        assert_eq!(restored.get("kept").unwrap(), Some(b"val".to_vec()));
+        assert!(restored.get("removed").is_err());
+        assert!(restored.get("expired").is_err());
+        assert!(matches!(
+            KVStore::restore(&snapshot_dir, restore_dir.clone(), KVStoreConfig::default()),
+            Err(KVStoreError::DirNotEmpty(_))
+        ));
+
+        // Both stores keep working on their own.
+        restored.set("kept", "restored").unwrap();
+        store.compact().unwrap();
+        assert_eq!(store.get("kept").unwrap(), Some(b"val".to_vec()));
+        assert_eq!(store.get("key 499").unwrap(), Some(b"val".to_vec()));
+        drop(restored);
+        let restored = KVStore::open(restore_dir.clone()).unwrap();
+        assert_eq!(restored.get("kept").unwrap(), Some(b"restored".to_vec()));
+
+        let empty_dir = test_dir("kvs-snapshot-empty");
+        fs::create_dir_all(&empty_dir).unwrap();
+        assert!(matches!(
+            KVStore::restore(
+                &empty_dir,
+                test_dir("kvs-snapshot-none"),
+                KVStoreConfig::default()
+            )
+            .map(|_| ()),
+            Err(KVStoreError::InvalidSnapshot(_))
+        ));
+        for dir in &[dir, snapshot_dir, restore_dir, empty_dir] {
+            fs::remove_dir_all(dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_batch() {
+        let dir = test_dir("kvs-batch");
+        {
+            let store = KVStore::open(dir.clone()).unwrap();
+            let ops = (0..100)
+                .map(|i| match i % 10 {
+                    9 => WriteOp::Rm {
+                        key: format!("missing {}", i).into_bytes(),
+                    },
+                    _ => WriteOp::Set {
+                        key: format!("key {}", i % 20).into_bytes(),
+                        val: i.to_string().into_bytes(),
+                    },
+                })
+                .collect();
+            let results = store.write_many(ops).unwrap();
+            assert_eq!(results.iter().filter(|result| result.is_err()).count(), 10);
+        }
+        // Every write that succeeded was committed, and the failed ones left no trace.
+        let store = KVStore::open(dir.clone()).unwrap();
+        let keys = vec![b"key 0".to_vec(), b"key 18".to_vec(), b"missing 9".to_vec()];
+        assert_eq!(
+            store.get_many(keys).unwrap(),
+            vec![Some(b"80".to_vec()), Some(b"98".to_vec()), None]
+        );
+        assert_eq!(store.stats().unwrap().live_keys, 18);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_lock_dir() {
+        let dir = test_dir("kvs-lock");
+        let store = KVStore::open(dir.clone()).unwrap();
+        let locked = KVStore::open(dir.clone());
+        drop(store);
+        let reopened = KVStore::open(dir.clone()).map(|_| ());
+        fs::remove_dir_all(dir).unwrap();
+        assert!(matches!(locked, Err(KVStoreError::DirLocked(_))));
+        assert!(reopened.is_ok());
+    }
+
+    #[test]
+    fn test_failed_write() {
+        let dir = test_dir("kvs-failed-write");
+        let segment_path = log_path(Path::new(&dir), 1);
+        let (segment_len, res, truncated_len) = {
+            let store = KVStore::open(dir.clone()).unwrap();
+            store.set(String::from("a"), String::from("1")).unwrap();
+            let segment_len = fs::metadata(&segment_path).unwrap().len();
+            // Swap the file the segment is written to for a read-only handle, so that the
+            // large write fails once the small one has been buffered.
+            {
+                let mut writer = store.writer.lock().unwrap();
+                let read_only = File::open(&segment_path).unwrap();
+                writer.writer = BufWriterWithPointer::new(read_only).unwrap();
+                writer.writer.seek(SeekFrom::End(0)).unwrap();
+            }
+            let ops = vec![
+                WriteOp::Set {
+                    key: b"b".to_vec(),
+                    val: b"2".to_vec(),
+                },
+                WriteOp::Set {
+                    key: b"c".to_vec(),
+                    val: vec![3; 64 * 1024],
+                },
+            ];
+            let res = store.write_many(ops).unwrap();
+            let truncated_len = fs::metadata(&segment_path).unwrap().len();
+            assert!(store.writer.lock().unwrap().failed.is_some());
+            // The segment is opened again, so writes go through after a failed one.
+            store.set(String::from("d"), String::from("4")).unwrap();
+            assert!(store.writer.lock().unwrap().failed.is_none());
+            (segment_len, res, truncated_len)
+        };
+
+        let store = KVStore::open(dir.clone()).unwrap();
+        let keys = vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec(), b"d".to_vec()];
+        let vals = store.get_many(keys).unwrap();
+        fs::remove_dir_all(dir).unwrap();
+        assert!(res.iter().all(|res| res.is_err()));
+        assert_eq!(truncated_len, segment_len);
+        assert_eq!(
+            vals,
+            vec![Some(b"1".to_vec()), None, None, Some(b"4".to_vec())]
+        );
+    }
+
+    #[test]
+    fn test_migrate_legacy_segments() {
+        let dir = test_dir("kvs-legacy");
+        fs::create_dir_all(&dir).unwrap();
+        // Logs used to be streams of JSON encoded actions, with no header and no framing.
+        // The last action was cut short by a crash.
+        let unframed = concat!(
+            r#"{"Set":{"key":"this is","val":"the way"}}"#,
+            r#"{"Set":{"key":"gone","val":"soon"}}"#,
+            r#"{"Set":{"key":"torn","val":"#,
+        );
+        fs::write(log_path(Path::new(&dir), 1), unframed).unwrap();
+        // Then the actions were framed as records, still without a header.
+        let mut framed = vec![];
+        record::write_record(&mut framed, br#"{"Remove":{"key":"gone"}}"#).unwrap();
+        fs::write(log_path(Path::new(&dir), 2), &framed).unwrap();
+
+        let store = KVStore::open(dir.clone()).unwrap();
+        let val = store.get(String::from("this is")).unwrap();
+        let gone = store.get(String::from("gone"));
+        let torn = store.get(String::from("torn"));
+        let gens = sorted_gens(Path::new(&dir)).unwrap();
+        let headers: Vec<Vec<u8>> = gens
+            .iter()
+            .map(|&gen| fs::read(log_path(Path::new(&dir), gen)).unwrap()[..6].to_vec())
+            .collect();
+        drop(store);
+        let reopened = KVStore::open(dir.clone())
+            .unwrap()
+            .get(String::from("this is"))
+            .unwrap();
+        fs::remove_dir_all(dir).unwrap();
+
+        assert_eq!(val, Some(b"the way".to_vec()));
+        assert!(gone.is_err());
+        assert!(torn.is_err());
+        assert!(!gens.contains(&1) && !gens.contains(&2));
+        for header in headers {
+            assert_eq!(&header[..], &record::SEGMENT_MAGIC[..]);
+        }
+        assert_eq!(reopened, Some(b"the way".to_vec()));
+    }
+
+    #[test]
+    fn test_import_legacy_log() {
+        let dir = test_dir("kvs-import");
+        let legacy_log = PathBuf::from(format!("{}.log", dir));
+        // The log used to be a single file, in the same format as the oldest segments.
+        let log = concat!(
+            r#"{"Set":{"key":"this is","val":"the way"}}"#,
+            r#"{"Set":{"key":"gone","val":"soon"}}"#,
+            r#"{"Remove":{"key":"gone"}}"#,
+        );
+        fs::write(&legacy_log, log).unwrap();
+        let config = KVStoreConfig {
+            legacy_log: Some(legacy_log.clone()),
+            ..KVStoreConfig::default()
+        };
+
+        let store = KVStore::open_with_config(dir.clone(), config.clone()).unwrap();
+        let val = store.get("this is").unwrap();
+        let gone = store.get("gone");
+        store.set("this is", "another way").unwrap();
+        drop(store);
+        // Once imported, the log isn't imported again.
+        let reopened = KVStore::open_with_config(dir.clone(), config)
+            .unwrap()
+            .get("this is")
+            .unwrap();
+        let left = fs::read(&legacy_log).unwrap();
+        let missing = KVStoreConfig {
+            legacy_log: Some(PathBuf::from(format!("{}-missing.log", dir))),
+            ..KVStoreConfig::default()
+        };
+        let opened = KVStore::open_with_config(format!("{}-missing", dir), missing);
+        fs::remove_dir_all(format!("{}-missing", dir)).unwrap();
+        fs::remove_dir_all(dir).unwrap();
+        fs::remove_file(legacy_log).unwrap();
+
+        assert_eq!(val, Some(b"the way".to_vec()));
+        assert!(gone.is_err());
+        assert_eq!(reopened, Some(b"another way".to_vec()));
+        assert_eq!(left, log.as_bytes());
+        assert!(matches!(opened, Err(KVStoreError::LegacyLog(_))));
+    }
+
+    #[test]
+    fn test_unknown_segment() {
+        let mut newer = record::SEGMENT_MAGIC.to_vec();
+        newer.extend_from_slice(&(record::SEGMENT_VERSION + 1).to_le_bytes());
+        newer.extend_from_slice(b"whatever comes next");
+        let segments: Vec<&[u8]> = vec![b"not a segment at all", &newer];
+        for segment in segments {
+            let dir = test_dir("kvs-unknown");
+            fs::create_dir_all(&dir).unwrap();
+            let path = log_path(Path::new(&dir), 1);
+            fs::write(&path, segment).unwrap();
+            let opened = KVStore::open(dir.clone());
+            let left = fs::read(&path).unwrap();
+            fs::remove_dir_all(dir).unwrap();
+
+            // The store refuses to open rather than throwing away what it can't read.
+            assert!(matches!(opened, Err(KVStoreError::UnknownSegment(_))));
+            assert_eq!(left, segment);
+        }
+    }
+
+    #[test]
+    fn test_ttl() {
+        let dir = test_dir("kvs-ttl");
+        let hour = Duration::from_secs(60 * 60);
+        {
+            let store = KVStore::open(dir.clone()).unwrap();
+            for key in &["short", "reset", "compacted"] {
+                store
+                    .set_with_ttl(
+                        key.to_string(),
+                        String::from("val"),
+                        Duration::from_millis(50),
+                    )
+                    .unwrap();
+            }
+            store
+                .set_with_ttl(String::from("long"), String::from("val"), hour)
+                .unwrap();
+            store
+                .set(String::from("forever"), String::from("val"))
+                .unwrap();
+            assert_eq!(
+                store.get(String::from("short")).unwrap(),
+                Some(b"val".to_vec())
+            );
+            thread::sleep(Duration::from_millis(100));
+
+            assert!(matches!(
+                store.get(String::from("short")),
+                Err(KVStoreError::KeyNotFound(_))
+            ));
+            assert!(matches!(
+                store.ttl(String::from("short")),
+                Err(KVStoreError::KeyNotFound(_))
+            ));
+            assert!(matches!(
+                store.rm(String::from("short")),
+                Err(KVStoreError::KeyNotFound(_))
+            ));
+            // Overwriting an expired key doesn't return the expired value.
+            let old_val = store
+                .set(String::from("reset"), String::from("new val"))
+                .unwrap();
+            assert_eq!(old_val, None);
+            // Expired keys aren't counted, whereas the one that was set again without a TTL is.
+            assert_eq!(store.stats().unwrap().live_keys, 3);
+            assert_eq!(store.reap_expired().unwrap(), 2);
+            assert_eq!(store.reap_expired().unwrap(), 0);
+        }
+
+        // Expiry times are persisted in the log, and in the hint files.
+        let store = KVStore::open(dir.clone()).unwrap();
+        let remaining = store.ttl(String::from("long")).unwrap().unwrap();
+        assert!(remaining <= hour && remaining > hour - Duration::from_secs(60));
+        assert_eq!(store.ttl(String::from("forever")).unwrap(), None);
+        assert_eq!(store.ttl(String::from("reset")).unwrap(), None);
+        assert!(store.get(String::from("short")).is_err());
+        assert_eq!(store.stats().unwrap().live_keys, 3);
+
+        // Compaction drops expired keys without having to reap them first.
+        store
+            .set_with_ttl(
+                String::from("compacted"),
+                String::from("val"),
+                Duration::from_millis(10),
+            )
+            .unwrap();
+        thread::sleep(Duration::from_millis(50));
+        store.compact().unwrap();
+        assert_eq!(store.stats().unwrap().live_keys, 3);
+        drop(store);
+        let store = KVStore::open(dir.clone()).unwrap();
+        let keys: Vec<Vec<u8>> = store.scan(..).map(|pair| pair.unwrap().0).collect();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(
+            keys,
+            vec![b"forever".to_vec(), b"long".to_vec(), b"reset".to_vec()]
+        );
+    }
+
+    #[test]
+    fn test_versions() {
+        let dir = test_dir("kvs-versions");
+        let config = KVStoreConfig {
+            segment_size: 256,
+            ..KVStoreConfig::default()
+        };
+        {
+            let store = Arc::new(KVStore::open_with_config(dir.clone(), config.clone()).unwrap());
+            store
+                .set(String::from("counter"), String::from("0"))
+                .unwrap();
+            // Increment the counter from several threads, retrying on conflicts.
+            let handles: Vec<_> = (0..4)
+                .map(|_| {
+                    let store = Arc::clone(&store);
+                    thread::spawn(move || {
+                        for _ in 0..25 {
+                            loop {
+                                let key = String::from("counter");
+                                let (val, version) = store.get_with_version(key.clone()).unwrap();
+                                let val = (String::from_utf8(val).unwrap().parse::<u64>().unwrap()
+                                    + 1)
+                                .to_string();
+                                match store.set_if_version(key, val, version) {
+                                    Ok(_) => break,
+                                    Err(KVStoreError::VersionMismatch(..)) => continue,
+                                    Err(err) => panic!("{}", err),
+                                }
+                            }
+                        }
+                    })
+                })
+                .collect();
+            for handle in handles {
+                handle.join().unwrap();
+            }
+            let counter = store.get_with_version(String::from("counter")).unwrap();
+            assert_eq!(counter, (b"100".to_vec(), 101));
+            assert!(matches!(
+                store.set_if_absent(String::from("counter"), String::new()),
+                Err(KVStoreError::KeyExists(_))
+            ));
+            assert!(matches!(
+                store.rm_if_version(String::from("counter"), 100),
+                Err(KVStoreError::VersionMismatch(..))
+            ));
+        }
+
+        // Versions are persisted in the log, in the hint files and through compaction.
+        let store = KVStore::open_with_config(dir.clone(), config.clone()).unwrap();
+        let reopened = store.get_with_version(String::from("counter")).unwrap();
+        store.compact().unwrap();
+        drop(store);
+        let store = KVStore::open_with_config(dir.clone(), config).unwrap();
+        let compacted = store.get_with_version(String::from("counter")).unwrap();
+        let removed = store.rm_if_version(String::from("counter"), 101).unwrap();
+        let version = store
+            .set_if_absent(String::from("counter"), String::from("0"))
+            .unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(reopened, (b"100".to_vec(), 101));
+        assert_eq!(compacted, (b"100".to_vec(), 101));
+        assert_eq!(removed, Some(b"100".to_vec()));
+        // Versions start over once the key is removed.
+        assert_eq!(version, 1);
+    }
+
+    #[test]
+    fn test_transactions() {
+        let dir = test_dir("kvs-txn");
+        let segment_len = {
+            let store = KVStore::open(dir.clone()).unwrap();
+            store.set(String::from("from"), String::from("10")).unwrap();
+            let mut txn = store.begin();
+            txn.set(String::from("to"), String::from("10"));
+            txn.rm(String::from("from"));
+            let old_vals = txn.commit().unwrap();
+            assert_eq!(old_vals, vec![None, Some(b"10".to_vec())]);
+            let mut txn = store.begin();
+            txn.set(String::from("to"), String::from("0"));
+            txn.rm(String::from("from"));
+            assert!(matches!(txn.commit(), Err(KVStoreError::KeyNotFound(_))));
+            assert_eq!(store.get(String::from("to")).unwrap(), Some(b"10".to_vec()));
+            fs::metadata(log_path(Path::new(&dir), 1)).unwrap().len()
+        };
+        let segment = fs::read(log_path(Path::new(&dir), 1)).unwrap();
+        let set_len = {
+            let mut set = vec![];
+            let action = Action::Set {
+                key: b"from".to_vec(),
+                val: b"10".to_vec(),
+                expires_at: None,
+                version: record::FIRST_VERSION,
+            };
+            record::write_record(&mut set, &action.encode()).unwrap();
+            set.len() as u64
+        };
+        let batch_start = record::SEGMENT_HEADER_LEN + set_len;
+
+        // Crash at every byte offset within the batch, the store sees either all or none of it.
+        for len in batch_start..=segment_len {
+            let crash_dir = test_dir("kvs-txn-crash");
+            fs::create_dir_all(&crash_dir).unwrap();
+            fs::write(log_path(Path::new(&crash_dir), 1), &segment[..len as usize]).unwrap();
+            let store = KVStore::open(crash_dir.clone()).unwrap();
+            let from = store.get(String::from("from")).ok().flatten();
+            let to = store.get(String::from("to")).ok().flatten();
+            drop(store);
+            fs::remove_dir_all(&crash_dir).unwrap();
+            if len == segment_len {
+                assert_eq!((from, to), (None, Some(b"10".to_vec())));
+            } else {
+                assert_eq!((from, to), (Some(b"10".to_vec()), None));
+            }
+        }
+
+        // Keys point to the actions within the batch, through hint files and compaction too.
+        let store = KVStore::open(dir.clone()).unwrap();
+        let reopened = store.get(String::from("to")).unwrap();
+        store.compact().unwrap();
+        let compacted = store.get(String::from("to")).unwrap();
+        let stale_bytes = store.stats().unwrap().stale_bytes;
+        drop(store);
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(reopened, Some(b"10".to_vec()));
+        assert_eq!(compacted, Some(b"10".to_vec()));
+        assert_eq!(stale_bytes, 0);
+    }
+
+    #[test]
+    fn test_hint_files() {
+        let dir = test_dir("kvs-hint");
+        let config = KVStoreConfig {
+            segment_size: 256,
+            ..KVStoreConfig::default()
+        };
+        {
+            let store = KVStore::open_with_config(dir.clone(), config.clone()).unwrap();
+            for i in 0..20 {
+                store
+                    .set(format!("key {}", i), format!("val {}", i))
+                    .unwrap();
+            }
+            store.rm(String::from("key 0")).unwrap();
+        }
+        let path = Path::new(&dir);
+        let gens = sorted_gens(path).unwrap();
+        let hinted = gens
+            .iter()
+            .filter(|&&gen| hint::hint_path(path, gen).exists())
+            .count();
+        // Mangle the value of a key in its segment, if the index is rebuilt from
+        // the hint files the segment is not read, so it won't be truncated.
+        let mut segment = fs::read(log_path(path, 1)).unwrap();
+        let last_byte = segment.len() - 1;
+        segment[last_byte] ^= 1;
+        fs::write(log_path(path, 1), &segment).unwrap();
+
+        let store = KVStore::open_with_config(dir.clone(), config).unwrap();
+        let segment_len = fs::metadata(log_path(path, 1)).unwrap().len();
+        let removed = store.get(String::from("key 0"));
+        let vals: Vec<Option<Vec<u8>>> = (1..20)
+            .map(|i| store.get(format!("key {}", i)).ok().flatten())
+            .collect();
+        fs::remove_dir_all(&dir).unwrap();
+
+        // Only the segment that was active when the store was closed has no hint file.
+        assert!(gens.len() > 2);
+        assert_eq!(hinted, gens.len() - 1);
+        assert_eq!(segment_len, segment.len() as u64);
+        assert!(removed.is_err());
+        // The value corrupted above belongs to the last key in the first segment.
+        assert_eq!(vals.iter().filter(|val| val.is_none()).count(), 1);
+    }
+
+    #[test]
+    fn test_roll_hint() {
+        let dir = test_dir("kvs-roll-hint");
+        let config = KVStoreConfig {
+            segment_size: 512,
+            ..KVStoreConfig::default()
+        };
+        for round in 0..2 {
+            // The segment that's appended to after reopening the store has keys written to it
+            // before then too.
+            let store = KVStore::open_with_config(dir.clone(), config.clone()).unwrap();
+            for i in 0..40 {
+                let key = format!("key {}", i % 7);
+                match i % 5 {
+                    0 => store.rm(key).map(|_| ()).unwrap_or_default(),
+                    1 => {
+                        let mut txn = store.begin();
+                        txn.set(key, format!("txn {}", i));
+                        txn.set(format!("other {}", round), String::from("val"));
+                        txn.commit().unwrap();
+                    }
+                    2 => store
+                        .incr(format!("counter {}", i % 3), 1)
+                        .map(|_| ())
+                        .unwrap(),
+                    _ => store
+                        .set(key, format!("val {} {}", round, i))
+                        .map(|_| ())
+                        .unwrap(),
+                }
+            }
+        }
+        // The hints written as segments are closed are the same as if they were read from them.
+        let path = Path::new(&dir);
+        let gens = sorted_gens(path).unwrap();
+        let hints: Vec<(Option<Hint>, Hint)> = gens[..gens.len() - 1]
+            .iter()
+            .map(|&gen| {
+                let (mut reader, version) = open_segment(path, gen).unwrap();
+                let scanned = scan_segment(path, gen, version, &mut reader).unwrap();
+                (hint::read_hint(path, gen).unwrap(), scanned)
+            })
+            .collect();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(hints.len() > 2);
+        for (written, scanned) in hints {
+            assert_eq!(written, Some(scanned));
+        }
+    }
+
+    #[test]
+    fn test_corrupt_hint_file() {
+        let dir = test_dir("kvs-corrupt-hint");
+        // The first segment is full after a single record, so it's closed and gets a hint file.
+        let config = KVStoreConfig {
+            segment_size: 16,
+            ..KVStoreConfig::default()
+        };
+        {
+            let store = KVStore::open_with_config(dir.clone(), config.clone()).unwrap();
+            store
+                .set(String::from("this is"), String::from("the way"))
+                .unwrap();
+        }
+        let path = Path::new(&dir);
+        let hinted = hint::hint_path(path, 1).exists();
+        fs::write(hint::hint_path(path, 1), b"not a hint").unwrap();
+
+        let store = KVStore::open_with_config(dir.clone(), config).unwrap();
+        let val = store.get(String::from("this is")).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(hinted);
+        assert_eq!(val, Some(b"the way".to_vec()));
+    }
+
+    #[test]
+    fn test_reopen_last_segment() {
+        let dir = test_dir("kvs-reopen");
+        let path = Path::new(&dir);
+        for i in 0..3 {
+            let store = KVStore::open(dir.clone()).unwrap();
+            store
+                .set(format!("key {}", i), format!("val {}", i))
+                .unwrap();
+        }
+        let gens = sorted_gens(path).unwrap();
+        let hinted = hint::hint_path(path, 1).exists();
+
+        // Once the last segment is full, the next open starts a new one.
+        let config = KVStoreConfig {
+            segment_size: 16,
+            ..KVStoreConfig::default()
+        };
+        let store = KVStore::open_with_config(dir.clone(), config).unwrap();
+        let full_gens = sorted_gens(path).unwrap();
+        let vals: Vec<Option<Vec<u8>>> = (0..3)
+            .map(|i| store.get(format!("key {}", i)).unwrap())
+            .collect();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(gens, vec![1]);
+        assert!(!hinted);
+        assert_eq!(full_gens, vec![1, 2]);
+        for (i, val) in vals.into_iter().enumerate() {
+            assert_eq!(val, Some(format!("val {}", i).into_bytes()));
+        }
     }
 }
@@ -0,0 +1,316 @@
+use crate::{store::now_millis, KVStoreError, Result};
+use log::error;
+use std::{
+    collections::VecDeque,
+    fmt,
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::broadcast;
+
+/// Default number of bytes of keys and values kept around in the history of changes.
+pub const DEFAULT_HISTORY_SIZE: u64 = 1024 * 1024;
+
+// Number of changes a watcher can fall behind by before it's cut off.
+const CHANNEL_SIZE: usize = 1024;
+
+/// A change made to a key.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Change {
+    Set { key: Vec<u8>, val: Vec<u8> },
+    Rm { key: Vec<u8> },
+}
+
+impl Change {
+    pub fn key(&self) -> &[u8] {
+        match self {
+            Change::Set { key, .. } | Change::Rm { key } => key,
+        }
+    }
+
+    // Number of bytes taken up by the key and the value.
+    fn size(&self) -> u64 {
+        match self {
+            Change::Set { key, val } => (key.len() + val.len()) as u64,
+            Change::Rm { key } => key.len() as u64,
+        }
+    }
+}
+
+/// Id of a change: the epoch of the process it was made in, i.e. the time it started at, in
+/// milliseconds since the Unix epoch, along with its sequence number within the process.
+/// Written as `{epoch}-{seq}`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EventId {
+    pub epoch: u64,
+    pub seq: u64,
+}
+
+impl fmt::Display for EventId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.epoch, self.seq)
+    }
+}
+
+impl FromStr for EventId {
+    type Err = KVStoreError;
+
+    fn from_str(id: &str) -> Result<Self> {
+        let invalid = || KVStoreError::InvalidEventId(id.to_string());
+        let (epoch, seq) = id.split_once('-').ok_or_else(invalid)?;
+        Ok(EventId {
+            epoch: epoch.parse().map_err(|_| invalid())?,
+            seq: seq.parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+/// A change along with the bucket it was made in, or None for the default bucket, and its id.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Event {
+    pub id: EventId,
+    pub bucket: Option<String>,
+    pub change: Change,
+}
+
+/// Keys whose changes are watched.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Keys {
+    All,
+    Key(Vec<u8>),
+    Prefix(Vec<u8>),
+}
+
+impl Event {
+    /// Whether the event is for one of the keys in the bucket.
+    pub fn matches(&self, bucket: Option<&str>, keys: &Keys) -> bool {
+        if self.bucket.as_deref() != bucket {
+            return false;
+        }
+        let key = self.change.key();
+        match keys {
+            Keys::All => true,
+            Keys::Key(k) => key == &k[..],
+            Keys::Prefix(prefix) => key.starts_with(prefix),
+        }
+    }
+}
+
+/// Receiver of the changes as they're made.
+pub type Receiver = broadcast::Receiver<Arc<Event>>;
+
+/// In-process broadcast of the changes made to the keys, in every bucket.
+///
+/// Every change gets the next sequence number, starting from 1. The most recent changes are
+/// kept around, up to a number of bytes of keys and values, so that a watcher that dropped
+/// off can pick up where it left off. Sequence numbers aren't persisted, they start over
+/// along with the process, so they're qualified by the epoch of the process in the ids of
+/// the changes, which keeps the ids from before a restart from being mistaken for new ones.
+pub struct Changes {
+    epoch: u64,
+    history: Mutex<History>,
+    sender: broadcast::Sender<Arc<Event>>,
+}
+
+struct History {
+    events: VecDeque<Arc<Event>>,
+    bytes: u64,
+    max_bytes: u64,
+    last_seq: u64,
+}
+
+impl Changes {
+    /// Keeps up to `history_size` bytes of keys and values of the most recent changes.
+    pub fn new(history_size: u64) -> Self {
+        let (sender, _) = broadcast::channel(CHANNEL_SIZE);
+        Changes {
+            epoch: now_millis(),
+            history: Mutex::new(History {
+                events: VecDeque::new(),
+                bytes: 0,
+                max_bytes: history_size,
+                last_seq: 0,
+            }),
+            sender,
+        }
+    }
+
+    /// Epoch of the ids of the changes.
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    /// Broadcasts the change to the watchers and returns its id.
+    pub fn publish(&self, bucket: Option<&str>, change: Change) -> Result<EventId> {
+        let mut history = self.history.lock().map_err(|_| KVStoreError::Lock)?;
+        history.last_seq += 1;
+        let id = EventId {
+            epoch: self.epoch,
+            seq: history.last_seq,
+        };
+        let event = Arc::new(Event {
+            id,
+            bucket: bucket.map(String::from),
+            change,
+        });
+        history.bytes += event.change.size();
+        history.events.push_back(Arc::clone(&event));
+        while history.bytes > history.max_bytes {
+            match history.events.pop_front() {
+                Some(evicted) => history.bytes -= evicted.change.size(),
+                None => break,
+            }
+        }
+        // The event is sent while the history is locked, so that the watchers get the events
+        // in the order of their sequence numbers. It's fine if there's no one watching.
+        let _ = self.sender.send(event);
+        Ok(id)
+    }
+
+    /// Starts watching the changes. If an id is given, the changes after it are returned along
+    /// with the receiver of the changes to come, with none missing or repeated in between.
+    /// Returns an EventsUnavailable error if some of them aren't kept anymore, or if the id
+    /// is from another process, e.g. from before a restart.
+    pub fn subscribe(&self, after: Option<EventId>) -> Result<(Vec<Arc<Event>>, Receiver)> {
+        let history = self.history.lock().map_err(|_| KVStoreError::Lock)?;
+        let receiver = self.sender.subscribe();
+        let missed = match after {
+            Some(after) => {
+                let first = match history.events.front() {
+                    Some(event) => event.id.seq,
+                    None => history.last_seq + 1,
+                };
+                // Changes after a sequence number that hasn't been reached can't be told apart
+                // from missing ones either.
+                if after.epoch != self.epoch
+                    || after.seq > history.last_seq
+                    || after.seq + 1 < first
+                {
+                    return Err(KVStoreError::EventsUnavailable(after.to_string()));
+                }
+                history
+                    .events
+                    .iter()
+                    .filter(|event| event.id.seq > after.seq)
+                    .cloned()
+                    .collect()
+            }
+            None => vec![],
+        };
+        Ok((missed, receiver))
+    }
+}
+
+/// Where an engine sends the changes made to its keys, see `KvsEngine::watch`: the changes,
+/// along with the bucket the engine backs, None for the default bucket.
+#[derive(Clone)]
+pub struct ChangeSink {
+    changes: Arc<Changes>,
+    bucket: Option<String>,
+}
+
+impl ChangeSink {
+    pub fn new(changes: Arc<Changes>, bucket: Option<String>) -> Self {
+        ChangeSink { changes, bucket }
+    }
+
+    /// Publishes the change made in the bucket. The change has already been made by then, so
+    /// failing to publish it is only logged.
+    pub fn publish(&self, change: Change) {
+        if let Err(err) = self.changes.publish(self.bucket.as_deref(), change) {
+            error!("Could not publish a change: {}", err);
+        }
+    }
+}
+
+impl fmt::Debug for ChangeSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ChangeSink")
+            .field("bucket", &self.bucket)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn set(key: &str, val: &str) -> Change {
+        Change::Set {
+            key: key.as_bytes().to_vec(),
+            val: val.as_bytes().to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_publish_subscribe() {
+        let changes = Changes::new(DEFAULT_HISTORY_SIZE);
+        let id = |seq| EventId {
+            epoch: changes.epoch(),
+            seq,
+        };
+        assert_eq!(changes.publish(None, set("a", "1")).unwrap(), id(1));
+        let (missed, mut receiver) = changes.subscribe(None).unwrap();
+        assert!(missed.is_empty());
+
+        assert_eq!(
+            changes.publish(Some("users"), set("ab", "2")).unwrap(),
+            id(2)
+        );
+        let rm = Change::Rm { key: b"a".to_vec() };
+        assert_eq!(changes.publish(None, rm.clone()).unwrap(), id(3));
+        let event = receiver.try_recv().unwrap();
+        assert_eq!(event.id, id(2));
+        assert!(event.matches(Some("users"), &Keys::Prefix(b"a".to_vec())));
+        assert!(!event.matches(None, &Keys::All));
+        assert!(!event.matches(Some("users"), &Keys::Key(b"a".to_vec())));
+        let event = receiver.try_recv().unwrap();
+        assert_eq!((event.id, &event.change), (id(3), &rm));
+        assert!(event.matches(None, &Keys::Key(b"a".to_vec())));
+    }
+
+    #[test]
+    fn test_resume() {
+        // Only room for the last two changes.
+        let changes = Changes::new(4);
+        let id = |seq| EventId {
+            epoch: changes.epoch(),
+            seq,
+        };
+        for i in 0..4 {
+            changes.publish(None, set("k", &i.to_string())).unwrap();
+        }
+
+        let (missed, _) = changes.subscribe(Some(id(2))).unwrap();
+        let seqs: Vec<u64> = missed.iter().map(|event| event.id.seq).collect();
+        assert_eq!(seqs, vec![3, 4]);
+        let (missed, _) = changes.subscribe(Some(id(4))).unwrap();
+        assert!(missed.is_empty());
+
+        // The change after 1 isn't kept anymore, there's no change after 4 yet, and the
+        // changes of another process are gone along with it.
+        let other = EventId {
+            epoch: changes.epoch() + 1,
+            seq: 2,
+        };
+        for &after in &[id(1), id(5), other] {
+            assert!(matches!(
+                changes.subscribe(Some(after)),
+                Err(KVStoreError::EventsUnavailable(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_event_id() {
+        let id = EventId { epoch: 17, seq: 3 };
+        assert_eq!(id.to_string(), "17-3");
+        assert_eq!("17-3".parse::<EventId>().unwrap(), id);
+        for id in &["3", "17-", "-3", "a-3", "17-3-1"] {
+            assert!(matches!(
+                id.parse::<EventId>(),
+                Err(KVStoreError::InvalidEventId(_))
+            ));
+        }
+    }
+}